@@ -0,0 +1,347 @@
+//! The access log: one line per request we answer (see --access-log), in either the Apache
+//! combined format (followed by a few fields of our own) or as JSON. The file is reopened on
+//! SIGUSR1, so that logrotate can move it aside and have us start a new one.
+//!
+//! Lines are written by a thread of their own, so that a slow disk doesn't hold up the tasks
+//! handling requests.
+
+use crate::dates::{civil_time, MONTHS};
+use crate::rate_limiting::Decision;
+use std::fs::OpenOptions;
+use std::io::{BufWriter, Write};
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant, SystemTime};
+
+/// How each request is written out
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Format {
+    /// The Apache/nginx combined log format, followed by key=value fields for what it lacks
+    Combined,
+    /// One JSON object per line
+    Json,
+}
+
+impl std::str::FromStr for Format {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Format, String> {
+        match s {
+            "combined" => Ok(Format::Combined),
+            "json" => Ok(Format::Json),
+            _ => Err(format!(
+                "unknown access log format \"{}\" (expected combined or json)",
+                s
+            )),
+        }
+    }
+}
+
+/// The first line of a request, as it is logged
+struct RequestLine {
+    method: String,
+    path: String,
+    protocol: &'static str,
+}
+
+/// What the writer thread is asked to do
+enum Message {
+    Line(String),
+    Reopen,
+}
+
+/// Where the access log goes, and in which format
+pub struct AccessLog {
+    /// The file we write to, or "-" for standard output
+    path: String,
+    format: Format,
+    /// Sends lines to the writer thread. Only None while the log is being dropped.
+    sender: Option<Sender<Message>>,
+    writer: Option<JoinHandle<()>>,
+}
+
+impl AccessLog {
+    pub fn open(path: &str, format: Format) -> Result<AccessLog, String> {
+        // The file is opened here rather than by the writer, so that a bad path is reported
+        // right away
+        let destination = open_destination(path)?;
+        let (sender, receiver) = mpsc::channel();
+        let writer_path = path.to_string();
+        let writer = thread::Builder::new()
+            .name("access-log".to_string())
+            .spawn(move || run_writer(&writer_path, destination, receiver))
+            .map_err(|err| format!("Could not start access log writer: {}", err))?;
+        Ok(AccessLog {
+            path: path.to_string(),
+            format,
+            sender: Some(sender),
+            writer: Some(writer),
+        })
+    }
+
+    /// Returns true if `other` writes the same log to the same place.
+    pub fn same_as(&self, other: &AccessLog) -> bool {
+        self.path == other.path && self.format == other.format
+    }
+
+    /// Opens the file again, e.g. after logrotate has renamed it. If it can't be opened, we carry
+    /// on writing to the old one.
+    pub fn reopen(&self) {
+        self.send(Message::Reopen);
+    }
+
+    fn write(&self, entry: &Entry, status: http::StatusCode) {
+        let mut line = match self.format {
+            Format::Combined => entry.combined(status),
+            Format::Json => entry.json(status),
+        };
+        line.push('\n');
+        self.send(Message::Line(line));
+    }
+
+    fn send(&self, message: Message) {
+        let sent = self
+            .sender
+            .as_ref()
+            .is_some_and(|sender| sender.send(message).is_ok());
+        if !sent {
+            log::warn!("The writer for access log {} has stopped", self.path);
+        }
+    }
+}
+
+impl Drop for AccessLog {
+    fn drop(&mut self) {
+        // Hanging up tells the writer to write out whatever is still queued and stop; wait for it,
+        // so that the last lines aren't lost when we exit
+        self.sender.take();
+        if let Some(writer) = self.writer.take() {
+            let _ = writer.join();
+        }
+    }
+}
+
+fn open_destination(path: &str) -> Result<Box<dyn Write + Send>, String> {
+    if path == "-" {
+        return Ok(Box::new(std::io::stdout()));
+    }
+    OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .map(|file| Box::new(BufWriter::new(file)) as Box<dyn Write + Send>)
+        .map_err(|err| format!("Could not open access log {}: {}", path, err))
+}
+
+/// Writes lines to the access log until the AccessLog is dropped. Whatever has queued up is
+/// written before flushing, so that a busy log is written in big chunks rather than line by line.
+fn run_writer(path: &str, mut destination: Box<dyn Write + Send>, messages: Receiver<Message>) {
+    while let Ok(message) = messages.recv() {
+        let mut next = Some(message);
+        while let Some(message) = next {
+            match message {
+                Message::Line(line) => {
+                    if let Err(err) = destination.write_all(line.as_bytes()) {
+                        log::warn!("Failed to write to access log {}: {}", path, err);
+                    }
+                }
+                Message::Reopen => match open_destination(path) {
+                    Ok(reopened) => {
+                        let _ = destination.flush();
+                        destination = reopened;
+                        log::info!("Reopened access log {}", path);
+                    }
+                    Err(err) => log::error!("{}", err),
+                },
+            }
+            next = messages.try_recv().ok();
+        }
+        if let Err(err) = destination.flush() {
+            log::warn!("Failed to write to access log {}: {}", path, err);
+        }
+    }
+}
+
+/// What we know about one request, filled in as it is handled. The entry is written to the access
+/// log when it is dropped (so that however handling the request ends, it is logged), as long as
+/// the client was sent a response.
+pub struct Entry {
+    log: Option<Arc<AccessLog>>,
+    time: SystemTime,
+    started: Instant,
+    client_ip: String,
+    /// Method, path and protocol, once we have read the request
+    request: Option<RequestLine>,
+    referer: Option<String>,
+    user_agent: Option<String>,
+    /// The last upstream the request was sent to, and how long it took to respond
+    upstream: Option<(String, Duration)>,
+    /// Whether the rate limiter turned the request away, if it looked at it at all
+    rate_limited: Option<bool>,
+    status: Option<http::StatusCode>,
+    /// Bytes of response body sent to the client
+    bytes: u64,
+}
+
+impl Entry {
+    /// Starts an entry for a request from `client_ip`. Nothing is written if `log` is None.
+    pub fn new(log: Option<Arc<AccessLog>>, client_ip: &str) -> Entry {
+        Entry {
+            log,
+            time: SystemTime::now(),
+            started: Instant::now(),
+            client_ip: client_ip.to_string(),
+            request: None,
+            referer: None,
+            user_agent: None,
+            upstream: None,
+            rate_limited: None,
+            status: None,
+            bytes: 0,
+        }
+    }
+
+    pub fn set_request<B>(&mut self, request: &http::Request<B>) {
+        let header = |name| {
+            request
+                .headers()
+                .get(name)
+                .map(|value| String::from_utf8_lossy(value.as_bytes()).into_owned())
+        };
+        self.referer = header(http::header::REFERER);
+        self.user_agent = header(http::header::USER_AGENT);
+        let protocol = match request.version() {
+            http::Version::HTTP_09 => "HTTP/0.9",
+            http::Version::HTTP_10 => "HTTP/1.0",
+            http::Version::HTTP_2 => "HTTP/2.0",
+            _ => "HTTP/1.1",
+        };
+        self.request = Some(RequestLine {
+            method: request.method().to_string(),
+            path: request.uri().to_string(),
+            protocol,
+        });
+    }
+
+    pub fn set_rate_limit(&mut self, decision: Option<&Decision>) {
+        self.rate_limited = decision.map(|decision| decision.limited);
+    }
+
+    /// Records that the request was sent to `address`, which took `latency` to respond (or to
+    /// fail).
+    pub fn set_upstream(&mut self, address: &str, latency: Duration) {
+        self.upstream = Some((address.to_string(), latency));
+    }
+
+    pub fn set_status(&mut self, status: http::StatusCode) {
+        self.status = Some(status);
+    }
+
+    pub fn add_bytes(&mut self, bytes: u64) {
+        self.bytes += bytes;
+    }
+
+    fn rate_limit(&self) -> Option<&'static str> {
+        self.rate_limited
+            .map(|limited| if limited { "limited" } else { "allowed" })
+    }
+
+    fn combined(&self, status: http::StatusCode) -> String {
+        let (year, month, day, hour, minute, second) = civil_time(self.time);
+        let quoted = |value: &Option<String>| match value {
+            Some(value) => format!("\"{}\"", escape(value)),
+            None => "\"-\"".to_string(),
+        };
+        let request = self
+            .request
+            .as_ref()
+            .map(|line| format!("{} {} {}", line.method, line.path, line.protocol));
+        let bytes = match self.bytes {
+            0 => "-".to_string(),
+            bytes => bytes.to_string(),
+        };
+        let (upstream, upstream_time) = match &self.upstream {
+            Some((address, latency)) => (address.as_str(), seconds(*latency)),
+            None => ("-", "-".to_string()),
+        };
+        format!(
+            "{} - - [{:02}/{}/{}:{:02}:{:02}:{:02} +0000] {} {} {} {} {} upstream={} \
+            upstream_time={} request_time={} rate_limit={}",
+            self.client_ip,
+            day,
+            MONTHS[month as usize - 1],
+            year,
+            hour,
+            minute,
+            second,
+            quoted(&request),
+            status.as_u16(),
+            bytes,
+            quoted(&self.referer),
+            quoted(&self.user_agent),
+            upstream,
+            upstream_time,
+            seconds(self.started.elapsed()),
+            self.rate_limit().unwrap_or("-"),
+        )
+    }
+
+    fn json(&self, status: http::StatusCode) -> String {
+        let (year, month, day, hour, minute, second) = civil_time(self.time);
+        let line = self.request.as_ref();
+        let (upstream, upstream_time) = match &self.upstream {
+            Some((address, latency)) => (Some(address), Some(latency.as_secs_f64())),
+            None => (None, None),
+        };
+        serde_json::json!({
+            "time": format!(
+                "{}-{:02}-{:02}T{:02}:{:02}:{:02}Z",
+                year, month, day, hour, minute, second
+            ),
+            "client": self.client_ip,
+            "method": line.map(|line| &line.method),
+            "path": line.map(|line| &line.path),
+            "protocol": line.map(|line| line.protocol),
+            "status": status.as_u16(),
+            "bytes": self.bytes,
+            "referer": self.referer,
+            "user_agent": self.user_agent,
+            "upstream": upstream,
+            "upstream_time": upstream_time,
+            "request_time": self.started.elapsed().as_secs_f64(),
+            "rate_limit": self.rate_limit(),
+        })
+        .to_string()
+    }
+}
+
+impl Drop for Entry {
+    fn drop(&mut self) {
+        if let (Some(log), Some(status)) = (&self.log, self.status) {
+            log.write(self, status);
+        }
+    }
+}
+
+/// Writes a duration in seconds, to the millisecond (like nginx's $request_time)
+fn seconds(duration: Duration) -> String {
+    format!("{:.3}", duration.as_secs_f64())
+}
+
+/// Escapes a value for a quoted field of the combined format, the way Apache does: quotes and
+/// backslashes are backslash-escaped, and control characters are written as \xhh.
+fn escape(value: &str) -> String {
+    let mut escaped = String::new();
+    for c in value.chars() {
+        match c {
+            '"' | '\\' => {
+                escaped.push('\\');
+                escaped.push(c);
+            }
+            c if c.is_control() => escaped.push_str(&format!("\\x{:02x}", c as u32)),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
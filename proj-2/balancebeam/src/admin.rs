@@ -0,0 +1,404 @@
+use crate::body::{self, Framing};
+use crate::health_check;
+use crate::metrics::Metrics;
+use crate::{request, response, routing, CurrentGeneration, Upstream, UpstreamState};
+use http::{Method, StatusCode};
+use serde::Serialize;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::Mutex;
+use tokio::time;
+
+/// Largest request body the admin API accepts. Bodies only ever hold an upstream spec.
+const MAX_BODY_SIZE: usize = 64 * 1024;
+
+/// Everything the admin API looks at or changes
+pub struct AdminContext {
+    /// The configuration new connections are handled with. Changes made through the admin API
+    /// apply to it, and are lost when the configuration is reloaded (except for the state of
+    /// upstreams that are still configured).
+    pub current: CurrentGeneration,
+    pub metrics: Arc<Metrics>,
+}
+
+impl AdminContext {
+    /// Returns the list of upstreams new connections pick from.
+    async fn upstreams(&self) -> Arc<Mutex<Vec<Upstream>>> {
+        let state = self.current.read().state.clone();
+        let upstreams = state.lock().await.upstream_addresses.clone();
+        upstreams
+    }
+}
+
+/// What the admin API reports about an upstream
+#[derive(Serialize)]
+struct UpstreamInfo {
+    address: String,
+    pool: String,
+    state: &'static str,
+    weight: usize,
+    active_connections: usize,
+    circuit_breaker: &'static str,
+    checks_passed: usize,
+    checks_failed: usize,
+}
+
+impl UpstreamInfo {
+    fn new(upstream: &Upstream) -> UpstreamInfo {
+        UpstreamInfo {
+            address: upstream.address.clone(),
+            pool: upstream.pool.clone(),
+            state: upstream.state.name(),
+            weight: upstream.weight,
+            active_connections: upstream.active_connections.load(Ordering::SeqCst),
+            circuit_breaker: upstream.breaker.state_name(),
+            checks_passed: upstream.checks_passed,
+            checks_failed: upstream.checks_failed,
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct CheckResult {
+    healthy: bool,
+    upstream: UpstreamInfo,
+}
+
+#[derive(Serialize)]
+struct ErrorMessage {
+    error: String,
+}
+
+/// Serves the admin API on `listener` until the process exits. The API is:
+///
+/// * `GET /upstreams`: lists the upstreams and their state
+/// * `POST /upstreams`: adds the upstream given in the body, written like an --upstream argument
+/// * `GET /upstreams/ADDRESS`: shows a single upstream
+/// * `DELETE /upstreams/ADDRESS`: removes an upstream (requests in flight to it are finished)
+/// * `POST /upstreams/ADDRESS/drain`: stops sending new requests to an upstream
+/// * `POST /upstreams/ADDRESS/disable`: stops sending requests and health checks to an upstream
+/// * `POST /upstreams/ADDRESS/enable`: hands a drained or disabled upstream back to the health
+///   checks, so that it is active again unless they found it dead
+/// * `POST /upstreams/ADDRESS/check`: health checks an upstream right away
+/// * `GET /rate-limits`: shows how each rate limit has been deciding
+/// * `GET /metrics`: reports metrics in the Prometheus text format
+///
+/// ADDRESS is percent-encoded if it contains a slash, as https:// upstreams do (e.g.
+/// `/upstreams/https%3A%2F%2Fexample.com%3A443/drain`).
+pub fn run(mut listener: TcpListener, context: Arc<AdminContext>) {
+    tokio::spawn(async move {
+        loop {
+            match listener.accept().await {
+                Ok((stream, _sock_addr)) => {
+                    let context = context.clone();
+                    tokio::spawn(async move { handle_connection(stream, &context).await });
+                }
+                Err(err) => log::warn!("Couldn't accept admin connection: {}", err),
+            }
+        }
+    });
+}
+
+/// Handles a single request; admin clients are few enough that keep-alive isn't worth supporting.
+/// Like any other client, an admin client only gets --client-header-timeout to send its request.
+async fn handle_connection(mut conn: TcpStream, context: &AdminContext) {
+    let state = context.current.read().state.clone();
+    let timeout = state.lock().await.client_header_timeout;
+    let request = match time::timeout(timeout, read_request(&mut conn)).await {
+        Ok(request) => request,
+        Err(_elapsed) => Err(StatusCode::REQUEST_TIMEOUT),
+    };
+    let response = match request {
+        Ok(request) => {
+            let response = handle_request(&request, context).await;
+            log::info!(
+                "Admin API: {} -> {}",
+                request::format_request_line(&request),
+                response.status()
+            );
+            response
+        }
+        Err(status) => error_response(status, "couldn't read request"),
+    };
+    if let Err(err) = response::write_to_stream(&response, &mut conn).await {
+        log::warn!("Failed to send admin response: {}", err);
+    }
+}
+
+async fn read_request(conn: &mut TcpStream) -> Result<http::Request<Vec<u8>>, StatusCode> {
+    let (mut request, framing) = request::read_head(conn)
+        .await
+        .map_err(|_| StatusCode::BAD_REQUEST)?;
+    let body = match framing {
+        Framing::Empty => Vec::new(),
+        Framing::ContentLength(len) if len <= MAX_BODY_SIZE => {
+            body::read_to_vec(conn, request.body(), len)
+                .await
+                .map_err(|_| StatusCode::BAD_REQUEST)?
+        }
+        _ => return Err(StatusCode::PAYLOAD_TOO_LARGE),
+    };
+    *request.body_mut() = body;
+    Ok(request)
+}
+
+async fn handle_request(
+    request: &http::Request<Vec<u8>>,
+    context: &AdminContext,
+) -> http::Response<Vec<u8>> {
+    let segments = match request
+        .uri()
+        .path()
+        .split('/')
+        .filter(|segment| !segment.is_empty())
+        .map(percent_decode)
+        .collect::<Option<Vec<String>>>()
+    {
+        Some(segments) => segments,
+        None => return error_response(StatusCode::BAD_REQUEST, "invalid percent-encoding"),
+    };
+    let segments = segments.iter().map(String::as_str).collect::<Vec<&str>>();
+    let method = request.method();
+    match segments.as_slice() {
+        ["upstreams"] if method == Method::GET => {
+            let upstreams = context.upstreams().await;
+            let upstreams = upstreams.lock().await;
+            json_response(
+                StatusCode::OK,
+                &upstreams.iter().map(UpstreamInfo::new).collect::<Vec<_>>(),
+            )
+        }
+        ["upstreams"] if method == Method::POST => add_upstream(request, context).await,
+        ["upstreams", address] if method == Method::GET => {
+            with_upstream(context, address, |upstream| {
+                json_response(StatusCode::OK, &UpstreamInfo::new(upstream))
+            })
+            .await
+        }
+        ["upstreams", address] if method == Method::DELETE => {
+            let upstreams = context.upstreams().await;
+            let removed = {
+                let mut upstreams = upstreams.lock().await;
+                upstreams
+                    .iter()
+                    .position(|upstream| upstream.address == *address)
+                    .map(|idx| upstreams.remove(idx))
+            };
+            match removed {
+                Some(upstream) => {
+                    let state = context.current.read().state.clone();
+                    let connection_pool = state.lock().await.connection_pool.clone();
+                    connection_pool.remove(address);
+                    log::info!("Removed upstream {} through the admin API", address);
+                    json_response(StatusCode::OK, &UpstreamInfo::new(&upstream))
+                }
+                None => not_found(address),
+            }
+        }
+        ["upstreams", address, "check"] if method == Method::POST => {
+            check_upstream(context, address).await
+        }
+        ["upstreams", address, action] if method == Method::POST => {
+            // Enabling an upstream only undoes draining or disabling it; whether it is active or
+            // dead is still up to the health checks
+            let state = match *action {
+                "drain" => Some(UpstreamState::Draining),
+                "disable" => Some(UpstreamState::Disabled),
+                "enable" => None,
+                _ => return error_response(StatusCode::NOT_FOUND, "no such action"),
+            };
+            with_upstream(context, address, |upstream| {
+                let state = state.unwrap_or_else(|| upstream.health_state());
+                log::info!(
+                    "Upstream {} is now {} (set through the admin API)",
+                    upstream.address,
+                    state.name()
+                );
+                if upstream.state != state {
+                    context.metrics.record_transition(
+                        &upstream.address,
+                        upstream.state.name(),
+                        state.name(),
+                    );
+                }
+                upstream.state = state;
+                upstream.checks_passed = 0;
+                upstream.checks_failed = 0;
+                json_response(StatusCode::OK, &UpstreamInfo::new(upstream))
+            })
+            .await
+        }
+        ["rate-limits"] if method == Method::GET => {
+            let rate_limits = context.current.read().rate_limits.clone();
+            let stats = rate_limits.lock().await.stats();
+            json_response(StatusCode::OK, &stats)
+        }
+        ["metrics"] if method == Method::GET => {
+            let upstream_states = context
+                .upstreams()
+                .await
+                .lock()
+                .await
+                .iter()
+                .map(|upstream| (upstream.address.clone(), upstream.state.name()))
+                .collect::<Vec<_>>();
+            let body = context.metrics.render(&upstream_states).into_bytes();
+            http::Response::builder()
+                .status(StatusCode::OK)
+                .header("Content-Type", "text/plain; version=0.0.4")
+                .header("Content-Length", body.len().to_string())
+                .header("Connection", "close")
+                .version(http::Version::HTTP_11)
+                .body(body)
+                .unwrap()
+        }
+        ["upstreams"] | ["upstreams", ..] | ["rate-limits"] | ["metrics"] => {
+            error_response(StatusCode::METHOD_NOT_ALLOWED, "method not allowed")
+        }
+        _ => error_response(StatusCode::NOT_FOUND, "not found"),
+    }
+}
+
+/// Adds the upstream in the request body to the pool named by the "pool" query parameter (or the
+/// default pool).
+async fn add_upstream(
+    request: &http::Request<Vec<u8>>,
+    context: &AdminContext,
+) -> http::Response<Vec<u8>> {
+    let spec = String::from_utf8_lossy(request.body());
+    let pool = request
+        .uri()
+        .query()
+        .unwrap_or("")
+        .split('&')
+        .find_map(|param| param.strip_prefix("pool="))
+        .unwrap_or(routing::DEFAULT_POOL);
+    let state = context.current.read().state.clone();
+    let (upstreams, parsed) = {
+        let state = state.lock().await;
+        let default_check = match state.pools.get(pool) {
+            Some(pool) => &pool.default_check,
+            None => return error_response(StatusCode::NOT_FOUND, "no such pool"),
+        };
+        (
+            state.upstream_addresses.clone(),
+            Upstream::parse(
+                spec.trim(),
+                pool,
+                default_check,
+                &state.breaker_config,
+                state.upstream_require_tls,
+            ),
+        )
+    };
+    let upstream = match parsed {
+        Ok(upstream) => upstream,
+        Err(err) => return error_response(StatusCode::BAD_REQUEST, &err),
+    };
+    let mut upstreams = upstreams.lock().await;
+    if upstreams
+        .iter()
+        .any(|existing| existing.address == upstream.address)
+    {
+        return error_response(StatusCode::CONFLICT, "upstream already exists");
+    }
+    log::info!("Added upstream {} through the admin API", upstream.address);
+    let response = json_response(StatusCode::CREATED, &UpstreamInfo::new(&upstream));
+    upstreams.push(upstream);
+    response
+}
+
+/// Health checks an upstream right away, and applies the result as if it came from the regular
+/// active health checks.
+async fn check_upstream(context: &AdminContext, address: &str) -> http::Response<Vec<u8>> {
+    let state = context.current.read().state.clone();
+    let config = state.lock().await.health_check.clone();
+    // Don't hold the upstream list while the check runs
+    let kind = match context
+        .upstreams()
+        .await
+        .lock()
+        .await
+        .iter()
+        .find(|upstream| upstream.address == address)
+    {
+        Some(upstream) => upstream.health_check.clone(),
+        None => return not_found(address),
+    };
+    let healthy = health_check::check(address, &kind, &config).await;
+    with_upstream(context, address, |upstream| {
+        upstream.record_check(healthy, &config, &context.metrics);
+        json_response(
+            StatusCode::OK,
+            &CheckResult {
+                healthy,
+                upstream: UpstreamInfo::new(upstream),
+            },
+        )
+    })
+    .await
+}
+
+/// Runs `f` on the upstream with the given address, or responds with 404 if there isn't one.
+async fn with_upstream(
+    context: &AdminContext,
+    address: &str,
+    f: impl FnOnce(&mut Upstream) -> http::Response<Vec<u8>>,
+) -> http::Response<Vec<u8>> {
+    let upstreams = context.upstreams().await;
+    let mut upstreams = upstreams.lock().await;
+    match upstreams
+        .iter_mut()
+        .find(|upstream| upstream.address == address)
+    {
+        Some(upstream) => f(upstream),
+        None => not_found(address),
+    }
+}
+
+/// Decodes %XX escapes in a path segment. Returns None if an escape is invalid, or the result
+/// isn't UTF-8.
+fn percent_decode(segment: &str) -> Option<String> {
+    let mut decoded = Vec::new();
+    let mut bytes = segment.bytes();
+    while let Some(byte) = bytes.next() {
+        if byte == b'%' {
+            let high = (bytes.next()? as char).to_digit(16)?;
+            let low = (bytes.next()? as char).to_digit(16)?;
+            decoded.push((high * 16 + low) as u8);
+        } else {
+            decoded.push(byte);
+        }
+    }
+    String::from_utf8(decoded).ok()
+}
+
+fn not_found(address: &str) -> http::Response<Vec<u8>> {
+    error_response(
+        StatusCode::NOT_FOUND,
+        &format!("no upstream with address {}", address),
+    )
+}
+
+fn error_response(status: StatusCode, message: &str) -> http::Response<Vec<u8>> {
+    json_response(
+        status,
+        &ErrorMessage {
+            error: message.to_string(),
+        },
+    )
+}
+
+fn json_response(status: StatusCode, value: &impl Serialize) -> http::Response<Vec<u8>> {
+    let mut body = serde_json::to_vec_pretty(value).unwrap();
+    body.push(b'\n');
+    http::Response::builder()
+        .status(status)
+        .header("Content-Type", "application/json")
+        .header("Content-Length", body.len().to_string())
+        .header("Connection", "close")
+        .version(http::Version::HTTP_11)
+        .body(body)
+        .unwrap()
+}
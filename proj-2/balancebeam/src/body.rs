@@ -0,0 +1,203 @@
+use crate::chunked;
+use std::future::Future;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::time;
+
+/// Size of the buffer used to shuttle body bytes from one stream to the other. This bounds how much
+/// of a body we hold in memory at a time, no matter how large the body is.
+const COPY_BUFFER_SIZE: usize = 16384;
+
+/// How the end of a message body is determined (RFC 7230, section 3.3.3)
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Framing {
+    /// There is no body
+    Empty,
+    /// The body is exactly this many bytes long
+    ContentLength(usize),
+    /// The body is sent with chunked encoding
+    Chunked,
+    /// The body continues until the sender closes the connection (only possible for responses)
+    UntilClose,
+}
+
+#[derive(Debug)]
+pub enum Error {
+    /// The sender hung up before the end of the body
+    UnexpectedEof,
+    /// The sender's chunked encoding is invalid, or it sent more bytes than its Content-Length
+    Malformed,
+    /// Encountered an I/O error when reading from the sender
+    Read(std::io::Error),
+    /// The sender stalled for longer than its ReadTimeout allows
+    TimedOut,
+    /// Encountered an I/O error when writing to the receiver
+    Write(std::io::Error),
+}
+
+impl std::fmt::Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Error::UnexpectedEof => write!(f, "sender hung up in the middle of the body"),
+            Error::Malformed => write!(f, "malformed body"),
+            Error::Read(err) => write!(f, "{}", err),
+            Error::TimedOut => write!(f, "sender stalled"),
+            Error::Write(err) => write!(f, "{}", err),
+        }
+    }
+}
+
+/// Copies a message body from `src` to `dst` as it arrives, without ever holding more than a
+/// small buffer's worth of it in memory. `prefix` holds any bytes of the body that were already
+/// read from `src` along with the headers. Returns the number of body bytes copied.
+pub async fn copy<R: AsyncRead + Unpin, W: AsyncWrite + Unpin>(
+    src: &mut R,
+    prefix: &[u8],
+    dst: &mut W,
+    framing: Framing,
+) -> Result<u64, Error> {
+    match framing {
+        Framing::Empty => {
+            if !prefix.is_empty() {
+                log::debug!(
+                    "Discarding {} bytes received after a message with no body",
+                    prefix.len()
+                );
+            }
+            Ok(0)
+        }
+        Framing::ContentLength(content_length) => {
+            if prefix.len() > content_length {
+                log::debug!("Sender sent more bytes than we expected based on the content length");
+                return Err(Error::Malformed);
+            }
+            dst.write_all(prefix).await.map_err(Error::Write)?;
+            copy_bytes(src, dst, Some(content_length - prefix.len())).await?;
+            Ok(content_length as u64)
+        }
+        Framing::Chunked => chunked::copy_body(src, prefix, dst, COPY_BUFFER_SIZE)
+            .await
+            .map_err(|err| match err {
+                chunked::Error::MalformedChunk | chunked::Error::BodyTooLarge => Error::Malformed,
+                chunked::Error::UnexpectedEof => Error::UnexpectedEof,
+                chunked::Error::Connection(io_err) => read_error(io_err),
+                chunked::Error::Write(io_err) => Error::Write(io_err),
+            }),
+        Framing::UntilClose => {
+            dst.write_all(prefix).await.map_err(Error::Write)?;
+            Ok(prefix.len() as u64 + copy_bytes(src, dst, None).await?)
+        }
+    }
+}
+
+/// Reads a body of exactly `content_length` bytes into memory, starting with the `prefix` bytes that
+/// were already read from `src` along with the headers. This is for the rare cases where we need
+/// to hold on to a body (e.g. to send it again), so callers should keep `content_length` small.
+pub async fn read_to_vec<R: AsyncRead + Unpin>(
+    src: &mut R,
+    prefix: &[u8],
+    content_length: usize,
+) -> Result<Vec<u8>, Error> {
+    if prefix.len() > content_length {
+        log::debug!("Sender sent more bytes than we expected based on the content length");
+        return Err(Error::Malformed);
+    }
+    let mut body = prefix.to_vec();
+    body.resize(content_length, 0);
+    src.read_exact(&mut body[prefix.len()..])
+        .await
+        .map_err(|err| match err.kind() {
+            std::io::ErrorKind::UnexpectedEof => Error::UnexpectedEof,
+            _ => read_error(err),
+        })?;
+    Ok(body)
+}
+
+/// Copies exactly `len` bytes from `src` to `dst`, or everything until `src` is closed if `len` is
+/// None. Returns the number of bytes copied.
+async fn copy_bytes<R: AsyncRead + Unpin, W: AsyncWrite + Unpin>(
+    src: &mut R,
+    dst: &mut W,
+    len: Option<usize>,
+) -> Result<u64, Error> {
+    let mut buffer = vec![0_u8; COPY_BUFFER_SIZE];
+    let mut copied: u64 = 0;
+    loop {
+        let to_read = match len {
+            Some(len) if copied as usize >= len => break,
+            Some(len) => std::cmp::min(buffer.len(), len - copied as usize),
+            None => buffer.len(),
+        };
+        let bytes_read = src.read(&mut buffer[..to_read]).await.map_err(read_error)?;
+        if bytes_read == 0 {
+            if len.is_some() {
+                return Err(Error::UnexpectedEof);
+            }
+            break;
+        }
+        dst.write_all(&buffer[..bytes_read])
+            .await
+            .map_err(Error::Write)?;
+        copied += bytes_read as u64;
+    }
+    Ok(copied)
+}
+
+fn read_error(err: std::io::Error) -> Error {
+    match err.kind() {
+        std::io::ErrorKind::TimedOut => Error::TimedOut,
+        _ => Error::Read(err),
+    }
+}
+
+/// Wraps a reader so that any one read that has to wait longer than `timeout` for data fails with
+/// a TimedOut error. This stops a peer that stalls partway through a body from tying us up forever,
+/// while still letting a large body take as long as it needs.
+pub struct ReadTimeout<'a, R> {
+    inner: &'a mut R,
+    timeout: Duration,
+    delay: Option<time::Delay>,
+}
+
+impl<'a, R> ReadTimeout<'a, R> {
+    pub fn new(inner: &'a mut R, timeout: Duration) -> ReadTimeout<'a, R> {
+        ReadTimeout {
+            inner,
+            timeout,
+            delay: None,
+        }
+    }
+}
+
+impl<R: AsyncRead + Unpin> AsyncRead for ReadTimeout<'_, R> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<std::io::Result<usize>> {
+        let this = &mut *self;
+        match Pin::new(&mut *this.inner).poll_read(cx, buf) {
+            Poll::Pending => {
+                let timeout = this.timeout;
+                let delay = this.delay.get_or_insert_with(|| time::delay_for(timeout));
+                match Pin::new(delay).poll(cx) {
+                    Poll::Ready(()) => {
+                        this.delay = None;
+                        Poll::Ready(Err(std::io::Error::new(
+                            std::io::ErrorKind::TimedOut,
+                            "timed out waiting for data",
+                        )))
+                    }
+                    Poll::Pending => Poll::Pending,
+                }
+            }
+            ready => {
+                // The clock starts over for the next read
+                this.delay = None;
+                ready
+            }
+        }
+    }
+}
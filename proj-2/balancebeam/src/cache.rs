@@ -0,0 +1,477 @@
+//! An in-memory cache of responses to GET and HEAD requests (see --cache-size), so that clients
+//! asking for the same thing over and over don't all have to be sent to an upstream.
+//!
+//! Responses are stored if their Cache-Control (max-age, s-maxage) or Expires header says how long
+//! they stay fresh, and aren't stored if Cache-Control says no-store or private. Entries are keyed
+//! on the pool, method, host and path, and on the request headers the response's Vary header
+//! names. A stale entry with an ETag isn't thrown away: the upstream is asked with If-None-Match
+//! whether it is still good, and a 304 gives it a new lease of life. The least recently used
+//! entries are evicted to keep the cache within its size.
+
+use crate::dates::parse_http_date;
+use http::header::{HeaderMap, HeaderName, HeaderValue};
+use http::{Method, StatusCode};
+use lru::LruCache;
+use parking_lot::Mutex;
+use std::time::{Duration, Instant, SystemTime};
+
+/// Statuses whose responses we store (those that RFC 9110 considers cacheable by default, less
+/// the ones that say nothing about the resource, like 405 and 501)
+const STORABLE_STATUSES: [u16; 8] = [200, 203, 204, 300, 301, 308, 404, 410];
+
+/// Headers that describe a single connection or message, which a stored response mustn't keep
+const CONNECTION_HEADERS: [&str; 3] = ["connection", "keep-alive", "transfer-encoding"];
+
+#[derive(Clone, Debug, Hash, PartialEq, Eq)]
+struct Key {
+    pool: String,
+    method: Method,
+    host: String,
+    path: String,
+}
+
+impl Key {
+    fn new(pool: &str, request: &http::Request<Vec<u8>>) -> Key {
+        let host = request
+            .headers()
+            .get(http::header::HOST)
+            .and_then(|host| host.to_str().ok())
+            .unwrap_or("")
+            .to_ascii_lowercase();
+        Key {
+            pool: pool.to_string(),
+            method: request.method().clone(),
+            host,
+            path: request.uri().to_string(),
+        }
+    }
+}
+
+/// One stored response. The same method, host and path can have several, told apart by the
+/// request headers their Vary header names.
+#[derive(Clone)]
+pub struct Stored {
+    /// The request headers named by Vary, and the values they had in the request this responded to
+    vary: Vec<(HeaderName, Vec<HeaderValue>)>,
+    status: StatusCode,
+    headers: HeaderMap,
+    body: Vec<u8>,
+    /// When we stored (or last revalidated) the response
+    stored_at: Instant,
+    /// How old the response already was at that point
+    initial_age: Duration,
+    /// How long the response stays fresh, counting from when the upstream generated it
+    lifetime: Duration,
+}
+
+impl Stored {
+    fn new(
+        request: &http::Request<Vec<u8>>,
+        status: StatusCode,
+        headers: HeaderMap,
+        body: Vec<u8>,
+    ) -> Stored {
+        let vary = vary_names(&headers)
+            .unwrap_or_default()
+            .into_iter()
+            .map(|name| {
+                let values = request.headers().get_all(&name).iter().cloned().collect();
+                (name, values)
+            })
+            .collect();
+        Stored {
+            vary,
+            status,
+            initial_age: initial_age(&headers),
+            lifetime: freshness_lifetime(&headers).unwrap_or_default(),
+            headers,
+            body,
+            stored_at: Instant::now(),
+        }
+    }
+
+    fn matches(&self, request: &http::Request<Vec<u8>>) -> bool {
+        self.vary
+            .iter()
+            .all(|(name, values)| request.headers().get_all(name).iter().eq(values.iter()))
+    }
+
+    fn age(&self) -> Duration {
+        self.initial_age + self.stored_at.elapsed()
+    }
+
+    fn etag(&self) -> Option<&HeaderValue> {
+        self.headers.get(http::header::ETAG)
+    }
+
+    /// Roughly how much memory the entry takes up
+    fn size(&self) -> usize {
+        self.body.len()
+            + self
+                .headers
+                .iter()
+                .map(|(name, value)| name.as_str().len() + value.len())
+                .sum::<usize>()
+    }
+
+    /// Makes a copy of the response to send to a client.
+    fn to_response(&self) -> http::Response<Vec<u8>> {
+        let mut response = http::Response::new(self.body.clone());
+        *response.status_mut() = self.status;
+        *response.headers_mut() = self.headers.clone();
+        response
+            .headers_mut()
+            .insert(http::header::AGE, HeaderValue::from(self.age().as_secs()));
+        response
+    }
+
+    /// Tells a client whose If-None-Match already names this response that it still has it.
+    fn to_not_modified(&self) -> http::Response<Vec<u8>> {
+        let mut response = self.to_response();
+        *response.status_mut() = StatusCode::NOT_MODIFIED;
+        response.body_mut().clear();
+        response
+    }
+
+    /// Asks the upstream to answer with a 304 if the stored response is still current.
+    pub fn add_condition(&self, request: &mut http::Request<Vec<u8>>) {
+        if let Some(etag) = self.etag() {
+            request
+                .headers_mut()
+                .insert(http::header::IF_NONE_MATCH, etag.clone());
+        }
+    }
+}
+
+/// What the cache has for a request
+pub enum Lookup {
+    /// A fresh response, ready to send
+    Hit(http::Response<Vec<u8>>),
+    /// A stale response that can be revalidated (see Stored::add_condition)
+    Stale(Stored),
+    Miss,
+}
+
+struct Entries {
+    responses: LruCache<Key, Vec<Stored>>,
+    /// Total size of the stored responses
+    size: usize,
+}
+
+pub struct ResponseCache {
+    /// How many bytes of responses we hold on to at most
+    max_size: usize,
+    entries: Mutex<Entries>,
+}
+
+impl ResponseCache {
+    pub fn new(max_size: usize) -> ResponseCache {
+        ResponseCache {
+            max_size,
+            entries: Mutex::new(Entries {
+                responses: LruCache::unbounded(),
+                size: 0,
+            }),
+        }
+    }
+
+    /// Looks for a stored response to `request`, which was routed to `pool`. Only requests for
+    /// which is_cacheable returns true should be looked up.
+    pub fn lookup(&self, pool: &str, request: &http::Request<Vec<u8>>) -> Lookup {
+        let mut entries = self.entries.lock();
+        let key = Key::new(pool, request);
+        let variants = match entries.responses.get_mut(&key) {
+            Some(variants) => variants,
+            None => return Lookup::Miss,
+        };
+        let idx = match variants.iter().position(|stored| stored.matches(request)) {
+            Some(idx) => idx,
+            None => return Lookup::Miss,
+        };
+        let stored = &variants[idx];
+
+        // Clients may ask for something fresher than we'd otherwise settle for
+        let directives = cache_control(request.headers());
+        let max_age = if directives.iter().any(|(name, _)| name == "no-cache") {
+            Some(Duration::from_secs(0))
+        } else {
+            duration_directive(&directives, "max-age")
+        };
+        let age = stored.age();
+        if age < stored.lifetime && max_age.is_none_or(|max_age| age <= max_age) {
+            if if_none_match(request.headers(), stored.etag()) {
+                return Lookup::Hit(stored.to_not_modified());
+            }
+            return Lookup::Hit(stored.to_response());
+        }
+        if stored.etag().is_some() {
+            // Leave the client's own conditions alone; the response it gets will replace this one
+            if is_conditional(request.headers()) {
+                return Lookup::Miss;
+            }
+            return Lookup::Stale(stored.clone());
+        }
+        // Without an ETag, there's no way of finding out whether it is still good
+        let size = variants.remove(idx).size();
+        if variants.is_empty() {
+            // An empty entry takes up no space, so nothing would ever evict it
+            entries.responses.pop(&key);
+        }
+        entries.size -= size;
+        Lookup::Miss
+    }
+
+    /// Returns true if `response` to `request` (whose body is `body_len` bytes) may be stored.
+    /// Callers that need to read the body in full to store it can check this first.
+    pub fn should_store(
+        &self,
+        request: &http::Request<Vec<u8>>,
+        response: &http::Response<Vec<u8>>,
+        body_len: usize,
+    ) -> bool {
+        let headers = response.headers();
+        let directives = cache_control(headers);
+        let lifetime = freshness_lifetime(headers);
+        is_cacheable(request)
+            && body_len <= self.max_size
+            && STORABLE_STATUSES.contains(&response.status().as_u16())
+            && !directives
+                .iter()
+                .any(|(name, _)| name == "no-store" || name == "private")
+            && !headers.contains_key(http::header::SET_COOKIE)
+            && vary_names(headers).is_some()
+            && (lifetime.is_some_and(|lifetime| lifetime > Duration::from_secs(0))
+                || headers.contains_key(http::header::ETAG))
+    }
+
+    /// Stores `response` (body included) to `request`, which was routed to `pool`, if
+    /// should_store allows it.
+    pub fn store(
+        &self,
+        pool: &str,
+        request: &http::Request<Vec<u8>>,
+        response: &http::Response<Vec<u8>>,
+    ) {
+        if !self.should_store(request, response, response.body().len())
+            || response
+                .extensions()
+                .get::<crate::chunked::Trailers>()
+                .is_some()
+        {
+            return;
+        }
+        let mut headers = response.headers().clone();
+        if request.method() != Method::HEAD && headers.contains_key(http::header::TRANSFER_ENCODING)
+        {
+            // We send the stored body in one piece
+            headers.insert(
+                http::header::CONTENT_LENGTH,
+                HeaderValue::from(response.body().len()),
+            );
+        }
+        for name in CONNECTION_HEADERS.iter() {
+            headers.remove(*name);
+        }
+        let stored = Stored::new(request, response.status(), headers, response.body().clone());
+        self.insert(Key::new(pool, request), stored);
+    }
+
+    /// Brings a stale response back to life after the upstream answered our If-None-Match with
+    /// `not_modified`, and returns it to be sent to the client.
+    pub fn revalidated(
+        &self,
+        pool: &str,
+        request: &http::Request<Vec<u8>>,
+        stored: Stored,
+        not_modified: &http::Response<Vec<u8>>,
+    ) -> http::Response<Vec<u8>> {
+        // The 304 carries the response's current metadata (e.g. a new Date and Cache-Control)
+        let mut headers = stored.headers;
+        headers.remove(http::header::AGE);
+        if !not_modified.headers().contains_key(http::header::DATE) {
+            // Otherwise the old Date would make the response look as old as ever
+            headers.remove(http::header::DATE);
+        }
+        for name in not_modified.headers().keys() {
+            if CONNECTION_HEADERS.contains(&name.as_str()) || name == http::header::CONTENT_LENGTH {
+                continue;
+            }
+            headers.remove(name);
+            for value in not_modified.headers().get_all(name) {
+                headers.append(name, value.clone());
+            }
+        }
+        let stored = Stored::new(request, stored.status, headers, stored.body);
+        let response = stored.to_response();
+        self.insert(Key::new(pool, request), stored);
+        response
+    }
+
+    /// Forgets the responses stored for the path of `request` if it was an unsafe one (e.g. a
+    /// POST or DELETE) that succeeded, since it has probably changed what they'd be.
+    pub fn invalidate(
+        &self,
+        pool: &str,
+        request: &http::Request<Vec<u8>>,
+        response: &http::Response<Vec<u8>>,
+    ) {
+        if request.method().is_safe() || response.status().as_u16() >= 400 {
+            return;
+        }
+        let mut entries = self.entries.lock();
+        for method in [Method::GET, Method::HEAD].iter() {
+            let mut key = Key::new(pool, request);
+            key.method = method.clone();
+            if let Some(variants) = entries.responses.pop(&key) {
+                entries.size -= variants.iter().map(Stored::size).sum::<usize>();
+            }
+        }
+    }
+
+    fn insert(&self, key: Key, stored: Stored) {
+        let size = stored.size();
+        if size > self.max_size {
+            return;
+        }
+        let mut entries = self.entries.lock();
+        let mut variants = entries.responses.pop(&key).unwrap_or_default();
+        let mut freed = 0;
+        variants.retain(|variant| {
+            // The new response replaces any stored for the same request headers
+            let replaced = variant.vary == stored.vary;
+            if replaced {
+                freed += variant.size();
+            }
+            !replaced
+        });
+        variants.push(stored);
+        entries.size = entries.size - freed + size;
+        entries.responses.put(key, variants);
+        while entries.size > self.max_size {
+            match entries.responses.pop_lru() {
+                Some((_, variants)) => {
+                    entries.size -= variants.iter().map(Stored::size).sum::<usize>();
+                }
+                None => break,
+            }
+        }
+    }
+}
+
+/// Returns true if a response to `request` may come from (and go into) the cache: it must be a
+/// GET or HEAD without credentials that doesn't switch protocols, and it mustn't say no-store.
+pub fn is_cacheable(request: &http::Request<Vec<u8>>) -> bool {
+    (request.method() == Method::GET || request.method() == Method::HEAD)
+        && !request.headers().contains_key(http::header::AUTHORIZATION)
+        && !request.headers().contains_key(http::header::UPGRADE)
+        && !cache_control(request.headers())
+            .iter()
+            .any(|(name, _)| name == "no-store")
+}
+
+/// Returns true if the request makes itself conditional on what the client already has.
+fn is_conditional(headers: &HeaderMap) -> bool {
+    headers.contains_key(http::header::IF_NONE_MATCH)
+        || headers.contains_key(http::header::IF_MODIFIED_SINCE)
+}
+
+/// Returns true if an If-None-Match header lists `etag` (using the weak comparison that
+/// If-None-Match calls for) or is "*".
+fn if_none_match(headers: &HeaderMap, etag: Option<&HeaderValue>) -> bool {
+    let etag = match etag.and_then(|etag| etag.to_str().ok()) {
+        Some(etag) => etag.trim_start_matches("W/"),
+        None => return false,
+    };
+    headers
+        .get_all(http::header::IF_NONE_MATCH)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .map(|tag| tag.trim())
+        .any(|tag| tag == "*" || tag.trim_start_matches("W/") == etag)
+}
+
+/// Splits Cache-Control headers into their directives (lowercased), with their values if any.
+fn cache_control(headers: &HeaderMap) -> Vec<(String, Option<String>)> {
+    headers
+        .get_all(http::header::CACHE_CONTROL)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .map(|directive| match directive.find('=') {
+            Some(idx) => (
+                directive[..idx].trim().to_ascii_lowercase(),
+                Some(directive[idx + 1..].trim().trim_matches('"').to_string()),
+            ),
+            None => (directive.trim().to_ascii_lowercase(), None),
+        })
+        .collect()
+}
+
+/// Reads a directive that's a number of seconds (like max-age=60).
+fn duration_directive(directives: &[(String, Option<String>)], name: &str) -> Option<Duration> {
+    directives
+        .iter()
+        .find(|(directive, _)| directive == name)
+        .and_then(|(_, value)| value.as_ref()?.parse::<u64>().ok())
+        .map(Duration::from_secs)
+}
+
+/// Returns the names listed in the Vary header, or None if the response varies on more than the
+/// request headers (Vary: *).
+fn vary_names(headers: &HeaderMap) -> Option<Vec<HeaderName>> {
+    let mut names = Vec::new();
+    for name in headers
+        .get_all(http::header::VARY)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .map(|name| name.trim())
+        .filter(|name| !name.is_empty())
+    {
+        names.push(HeaderName::from_bytes(name.as_bytes()).ok()?);
+    }
+    Some(names)
+}
+
+/// Works out how long a response stays fresh, counting from when it was generated, from its
+/// Cache-Control or Expires header. Returns None if it doesn't say.
+fn freshness_lifetime(headers: &HeaderMap) -> Option<Duration> {
+    let directives = cache_control(headers);
+    if directives.iter().any(|(name, _)| name == "no-cache") {
+        // It may be stored, but has to be revalidated every time
+        return Some(Duration::from_secs(0));
+    }
+    // We're a shared cache, so s-maxage takes precedence
+    if let Some(lifetime) = duration_directive(&directives, "s-maxage")
+        .or_else(|| duration_directive(&directives, "max-age"))
+    {
+        return Some(lifetime);
+    }
+    let expires = headers.get(http::header::EXPIRES)?;
+    // An invalid Expires (e.g. "0") means the response has already expired
+    let expires = match expires.to_str().ok().and_then(parse_http_date) {
+        Some(expires) => expires,
+        None => return Some(Duration::from_secs(0)),
+    };
+    let date = header_date(headers).unwrap_or_else(SystemTime::now);
+    Some(expires.duration_since(date).unwrap_or_default())
+}
+
+/// Works out how old a response was when we received it, from its Age and Date headers.
+fn initial_age(headers: &HeaderMap) -> Duration {
+    let age = headers
+        .get(http::header::AGE)
+        .and_then(|age| age.to_str().ok())
+        .and_then(|age| age.trim().parse::<u64>().ok())
+        .map(Duration::from_secs)
+        .unwrap_or_default();
+    let apparent_age = header_date(headers)
+        .and_then(|date| SystemTime::now().duration_since(date).ok())
+        .unwrap_or_default();
+    std::cmp::max(age, apparent_age)
+}
+
+fn header_date(headers: &HeaderMap) -> Option<SystemTime> {
+    parse_http_date(headers.get(http::header::DATE)?.to_str().ok()?)
+}
@@ -0,0 +1,300 @@
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+/// Longest chunk-size or trailer line we are willing to buffer
+const MAX_LINE_SIZE: usize = 8000;
+
+/// How much we read at a time while looking for the end of a chunk-size or trailer line
+const LINE_READ_SIZE: usize = 1024;
+
+#[derive(Debug)]
+pub enum Error {
+    /// The chunk framing (chunk sizes, CRLFs or trailers) is invalid
+    MalformedChunk,
+    /// The decoded body is bigger than the caller allows
+    BodyTooLarge,
+    /// The peer hung up before sending the terminating zero-length chunk
+    UnexpectedEof,
+    /// Encountered an I/O error when reading from the stream
+    Connection(std::io::Error),
+    /// Encountered an I/O error when writing the body to its destination
+    Write(std::io::Error),
+}
+
+/// Trailer fields sent after the last chunk of a chunked body. This is stored in the extensions of
+/// any request/response whose body was read with chunked encoding, so that write_to_stream knows to
+/// re-encode the body (and the trailers) the same way when forwarding it.
+#[derive(Clone, Debug, Default)]
+pub struct Trailers(pub http::HeaderMap);
+
+/// Returns true if the message body is framed with chunked encoding, i.e. chunked is the last
+/// (outermost) transfer coding listed in Transfer-Encoding.
+pub fn is_chunked(headers: &http::HeaderMap) -> bool {
+    last_transfer_coding(headers).as_deref() == Some("chunked")
+}
+
+/// Returns the last transfer coding listed across all Transfer-Encoding headers, lowercased, or
+/// None if the header isn't present.
+fn last_transfer_coding(headers: &http::HeaderMap) -> Option<String> {
+    headers
+        .get_all("transfer-encoding")
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .map(|coding| coding.trim().to_lowercase())
+        .rfind(|coding| !coding.is_empty())
+}
+
+/// Reads the chunk framing through a buffer, so that chunk-size and trailer lines don't take a
+/// read per byte. Bytes that were already read off the stream (e.g. along with the headers) are
+/// handed out first. Like read_headers, we may read past the end of the message; whatever is left
+/// over once the body ends is discarded.
+struct ChunkReader<'a, S> {
+    stream: &'a mut S,
+    /// Bytes read off the stream that haven't been handed out yet, from `pos` on
+    buffered: Vec<u8>,
+    pos: usize,
+}
+
+impl<'a, S: AsyncRead + Unpin> ChunkReader<'a, S> {
+    fn new(stream: &'a mut S, buffered: Vec<u8>) -> ChunkReader<'a, S> {
+        ChunkReader {
+            stream,
+            buffered,
+            pos: 0,
+        }
+    }
+
+    /// Reads more of the stream onto the end of the buffer.
+    async fn fill(&mut self) -> Result<(), Error> {
+        self.buffered.drain(..self.pos);
+        self.pos = 0;
+        let len = self.buffered.len();
+        self.buffered.resize(len + LINE_READ_SIZE, 0);
+        let bytes_read = self.stream.read(&mut self.buffered[len..]).await;
+        self.buffered
+            .truncate(len + *bytes_read.as_ref().unwrap_or(&0));
+        match bytes_read.map_err(Error::Connection)? {
+            0 => Err(Error::UnexpectedEof),
+            _ => Ok(()),
+        }
+    }
+
+    /// Reads at least one and at most `buffer.len()` bytes into `buffer`, returning how many were
+    /// read. Once the buffer is used up, chunk data is read straight into `buffer`.
+    async fn read_some(&mut self, buffer: &mut [u8]) -> Result<usize, Error> {
+        if self.pos < self.buffered.len() {
+            let len = std::cmp::min(buffer.len(), self.buffered.len() - self.pos);
+            buffer[..len].copy_from_slice(&self.buffered[self.pos..self.pos + len]);
+            self.pos += len;
+            return Ok(len);
+        }
+        let bytes_read = self.stream.read(buffer).await.map_err(Error::Connection)?;
+        if bytes_read == 0 {
+            return Err(Error::UnexpectedEof);
+        }
+        Ok(bytes_read)
+    }
+
+    /// Reads a CRLF-terminated line, returning it without the CRLF.
+    async fn read_line(&mut self) -> Result<Vec<u8>, Error> {
+        loop {
+            let unread = &self.buffered[self.pos..];
+            if let Some(idx) = unread.iter().position(|byte| *byte == b'\n') {
+                if idx == 0 || unread[idx - 1] != b'\r' {
+                    return Err(Error::MalformedChunk);
+                }
+                let line = unread[..idx - 1].to_vec();
+                self.pos += idx + 1;
+                return Ok(line);
+            }
+            if unread.len() > MAX_LINE_SIZE {
+                return Err(Error::MalformedChunk);
+            }
+            self.fill().await?;
+        }
+    }
+
+    /// Reads a chunk-size line (chunk-size [; chunk-ext] CRLF), returning the chunk size. Chunk
+    /// extensions are ignored.
+    async fn read_chunk_size(&mut self) -> Result<usize, Error> {
+        let line = self.read_line().await?;
+        let line = std::str::from_utf8(&line).or(Err(Error::MalformedChunk))?;
+        let size_str = line
+            .split(';')
+            .next()
+            .unwrap()
+            .trim_end_matches(&[' ', '\t'][..]);
+        // from_str_radix would also take a leading "+", which an upstream might read differently
+        // (and so disagree with us about where the body ends). A size too big for a usize is
+        // rejected too.
+        if size_str.is_empty() || !size_str.bytes().all(|byte| byte.is_ascii_hexdigit()) {
+            return Err(Error::MalformedChunk);
+        }
+        usize::from_str_radix(size_str, 16).or(Err(Error::MalformedChunk))
+    }
+
+    /// Reads the CRLF that follows each chunk's data.
+    async fn read_chunk_end(&mut self) -> Result<(), Error> {
+        if !self.read_line().await?.is_empty() {
+            return Err(Error::MalformedChunk);
+        }
+        Ok(())
+    }
+
+    /// Reads one trailer field, or returns None if we've reached the empty line that ends the
+    /// chunked body.
+    async fn read_trailer(
+        &mut self,
+    ) -> Result<Option<(http::header::HeaderName, http::HeaderValue)>, Error> {
+        let line = self.read_line().await?;
+        if line.is_empty() {
+            return Ok(None);
+        }
+        let colon = line
+            .iter()
+            .position(|byte| *byte == b':')
+            .ok_or(Error::MalformedChunk)?;
+        let name =
+            http::header::HeaderName::from_bytes(&line[..colon]).or(Err(Error::MalformedChunk))?;
+        let value = http::HeaderValue::from_bytes(trim_ascii_whitespace(&line[colon + 1..]))
+            .or(Err(Error::MalformedChunk))?;
+        Ok(Some((name, value)))
+    }
+
+    fn discard_leftover(&self) {
+        if self.pos < self.buffered.len() {
+            log::debug!(
+                "Discarding {} bytes received after the end of a chunked body",
+                self.buffered.len() - self.pos
+            );
+        }
+    }
+}
+
+/// Reads a chunked body from the stream and decodes it into `body`. On entry, `body` holds any
+/// bytes that were already read from the stream after the headers; those are consumed first.
+/// Returns the trailer fields that followed the last chunk.
+pub async fn read_body<S: AsyncRead + Unpin>(
+    stream: &mut S,
+    body: &mut Vec<u8>,
+    max_body_size: usize,
+) -> Result<Trailers, Error> {
+    let mut reader = ChunkReader::new(stream, std::mem::take(body));
+
+    let mut buffer = [0_u8; 512];
+    loop {
+        let size = reader.read_chunk_size().await?;
+        if size == 0 {
+            break;
+        }
+        if size > max_body_size - body.len() {
+            return Err(Error::BodyTooLarge);
+        }
+        let mut remaining = size;
+        while remaining > 0 {
+            let to_read = std::cmp::min(remaining, buffer.len());
+            let bytes_read = reader.read_some(&mut buffer[..to_read]).await?;
+            body.extend_from_slice(&buffer[..bytes_read]);
+            remaining -= bytes_read;
+        }
+        reader.read_chunk_end().await?;
+    }
+
+    // The last chunk is followed by zero or more trailer fields and then an empty line
+    let mut trailers = http::HeaderMap::new();
+    while let Some((name, value)) = reader.read_trailer().await? {
+        trailers.append(name, value);
+    }
+    reader.discard_leftover();
+    Ok(Trailers(trailers))
+}
+
+/// Copies a chunked body from `src` to `dst` one chunk at a time, as the data arrives, using a
+/// buffer of `buffer_size` bytes. `prefix` holds any bytes of the body that were already read from
+/// `src` along with the headers. Chunk extensions are dropped; chunk boundaries and trailers are
+/// passed through. Returns the number of (decoded) body bytes copied.
+pub async fn copy_body<R: AsyncRead + Unpin, W: AsyncWrite + Unpin>(
+    src: &mut R,
+    prefix: &[u8],
+    dst: &mut W,
+    buffer_size: usize,
+) -> Result<u64, Error> {
+    let mut reader = ChunkReader::new(src, prefix.to_vec());
+
+    let mut buffer = vec![0_u8; buffer_size];
+    let mut total: u64 = 0;
+    loop {
+        let size = reader.read_chunk_size().await?;
+        dst.write_all(format!("{:x}\r\n", size).as_bytes())
+            .await
+            .map_err(Error::Write)?;
+        if size == 0 {
+            break;
+        }
+        let mut remaining = size;
+        while remaining > 0 {
+            let to_read = std::cmp::min(remaining, buffer.len());
+            let bytes_read = reader.read_some(&mut buffer[..to_read]).await?;
+            dst.write_all(&buffer[..bytes_read])
+                .await
+                .map_err(Error::Write)?;
+            remaining -= bytes_read;
+        }
+        reader.read_chunk_end().await?;
+        dst.write_all(b"\r\n").await.map_err(Error::Write)?;
+        total += size as u64;
+    }
+
+    while let Some((name, value)) = reader.read_trailer().await? {
+        write_field(dst, &name, &value)
+            .await
+            .map_err(Error::Write)?;
+    }
+    dst.write_all(b"\r\n").await.map_err(Error::Write)?;
+    reader.discard_leftover();
+    Ok(total)
+}
+
+fn trim_ascii_whitespace(bytes: &[u8]) -> &[u8] {
+    let start = bytes
+        .iter()
+        .position(|byte| !byte.is_ascii_whitespace())
+        .unwrap_or(bytes.len());
+    let end = bytes
+        .iter()
+        .rposition(|byte| !byte.is_ascii_whitespace())
+        .map_or(start, |idx| idx + 1);
+    &bytes[start..end]
+}
+
+async fn write_field<S: AsyncWrite + Unpin>(
+    stream: &mut S,
+    name: &http::header::HeaderName,
+    value: &http::HeaderValue,
+) -> Result<(), std::io::Error> {
+    stream.write_all(format!("{}: ", name).as_bytes()).await?;
+    stream.write_all(value.as_bytes()).await?;
+    stream.write_all(b"\r\n").await
+}
+
+/// Writes `body` to the stream using chunked encoding (as a single chunk), followed by the
+/// terminating zero-length chunk and the given trailers.
+pub async fn write_body<S: AsyncWrite + Unpin>(
+    stream: &mut S,
+    body: &[u8],
+    trailers: &Trailers,
+) -> Result<(), std::io::Error> {
+    if !body.is_empty() {
+        stream
+            .write_all(format!("{:x}\r\n", body.len()).as_bytes())
+            .await?;
+        stream.write_all(body).await?;
+        stream.write_all(b"\r\n").await?;
+    }
+    stream.write_all(b"0\r\n").await?;
+    for (name, value) in trailers.0.iter() {
+        write_field(stream, name, value).await?;
+    }
+    stream.write_all(b"\r\n").await?;
+    Ok(())
+}
@@ -0,0 +1,174 @@
+use std::collections::VecDeque;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+/// Thresholds shared by the circuit breakers of all upstreams
+pub struct BreakerConfig {
+    /// Number of failures in a row (failed connections, timeouts or 5xx responses) that opens
+    /// the breaker
+    pub failure_threshold: usize,
+    /// Percentage of failed requests among the last `window_size` that opens the breaker (0
+    /// disables this check)
+    pub error_rate_percent: usize,
+    /// Number of recent requests the error rate is calculated over. The error rate isn't checked
+    /// until this many requests have been seen.
+    pub window_size: usize,
+    /// How long an open breaker keeps requests away from its upstream before letting trial
+    /// requests through
+    pub cool_down: Duration,
+    /// Number of trial requests that have to succeed in a row before a half-open breaker closes
+    pub trial_requests: usize,
+}
+
+#[derive(Debug)]
+enum State {
+    /// Requests flow normally
+    Closed,
+    /// The upstream failed too often, and gets no requests until the cool-down has passed
+    Open { since: Instant },
+    /// The cool-down has passed, and a limited number of trial requests are let through to see
+    /// whether the upstream has recovered
+    HalfOpen {
+        since: Instant,
+        started: usize,
+        succeeded: usize,
+    },
+}
+
+/// Tracks how requests to one upstream have been going, and stops sending it requests while it
+/// seems to be failing (see https://martinfowler.com/bliki/CircuitBreaker.html).
+pub struct CircuitBreaker {
+    /// Address of the upstream, for logging
+    address: String,
+    config: Arc<BreakerConfig>,
+    state: State,
+    consecutive_failures: usize,
+    /// Outcomes of the most recent requests (true for failures), oldest first
+    recent: VecDeque<bool>,
+}
+
+impl CircuitBreaker {
+    pub fn new(address: &str, config: Arc<BreakerConfig>) -> CircuitBreaker {
+        CircuitBreaker {
+            address: address.to_string(),
+            config,
+            state: State::Closed,
+            consecutive_failures: 0,
+            recent: VecDeque::new(),
+        }
+    }
+
+    /// Returns true if the upstream may be sent a request right now. This moves an open breaker
+    /// whose cool-down has passed to half-open.
+    pub fn is_available(&mut self) -> bool {
+        match self.state {
+            State::Closed => true,
+            State::Open { since } => {
+                if since.elapsed() < self.config.cool_down {
+                    return false;
+                }
+                log::info!(
+                    "Circuit breaker for {} half-open, letting trial requests through",
+                    self.address
+                );
+                self.state = State::HalfOpen {
+                    since: Instant::now(),
+                    started: 0,
+                    succeeded: 0,
+                };
+                true
+            }
+            State::HalfOpen { since, started, .. } => {
+                // If trial requests never report back (e.g. because the client went away), give
+                // out a fresh set once another cool-down has passed rather than waiting forever
+                if since.elapsed() >= self.config.cool_down {
+                    self.state = State::HalfOpen {
+                        since: Instant::now(),
+                        started: 0,
+                        succeeded: 0,
+                    };
+                    return true;
+                }
+                started < self.config.trial_requests
+            }
+        }
+    }
+
+    /// Returns the name of the breaker's state, for the admin API.
+    pub fn state_name(&self) -> &'static str {
+        match self.state {
+            State::Closed => "closed",
+            State::Open { .. } => "open",
+            State::HalfOpen { .. } => "half-open",
+        }
+    }
+
+    /// Records that a request is being sent to the upstream, which counts against the trial
+    /// requests of a half-open breaker.
+    pub fn start_request(&mut self) {
+        if let State::HalfOpen { started, .. } = &mut self.state {
+            *started += 1;
+        }
+    }
+
+    /// Records that the upstream handled a request.
+    pub fn record_success(&mut self) {
+        self.consecutive_failures = 0;
+        self.push_outcome(false);
+        if let State::HalfOpen { succeeded, .. } = &mut self.state {
+            *succeeded += 1;
+            if *succeeded >= self.config.trial_requests {
+                log::info!(
+                    "Circuit breaker for {} closed after successful trial requests",
+                    self.address
+                );
+                self.state = State::Closed;
+                self.recent.clear();
+            }
+        }
+    }
+
+    /// Records that a request to the upstream failed, opening the breaker if that was one failure
+    /// too many.
+    pub fn record_failure(&mut self) {
+        self.consecutive_failures += 1;
+        self.push_outcome(true);
+        let should_open = match self.state {
+            State::Closed => {
+                self.consecutive_failures >= self.config.failure_threshold
+                    || self.error_rate_exceeded()
+            }
+            // A single failed trial request is enough to send the upstream back to cool down
+            State::HalfOpen { .. } => true,
+            State::Open { .. } => false,
+        };
+        if should_open {
+            log::warn!(
+                "Circuit breaker for {} open after {} consecutive failures",
+                self.address,
+                self.consecutive_failures
+            );
+            self.state = State::Open {
+                since: Instant::now(),
+            };
+        }
+    }
+
+    fn push_outcome(&mut self, failed: bool) {
+        self.recent.push_back(failed);
+        while self.recent.len() > self.config.window_size {
+            self.recent.pop_front();
+        }
+    }
+
+    fn error_rate_exceeded(&self) -> bool {
+        if self.config.error_rate_percent == 0
+            || self.config.window_size == 0
+            || self.recent.len() < self.config.window_size
+        {
+            return false;
+        }
+        let failures = self.recent.iter().filter(|failed| **failed).count();
+        failures * 100 >= self.config.error_rate_percent * self.recent.len()
+    }
+}
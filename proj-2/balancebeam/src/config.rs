@@ -0,0 +1,168 @@
+//! Loading settings from a configuration file (see --config). The file is TOML, and its keys are
+//! the names of the long command-line options, with underscores in place of dashes. Tables prefix
+//! the keys inside them with their own name, so these two are the same:
+//!
+//! ```toml
+//! active_health_check_interval = 5
+//!
+//! [active_health_check]
+//! interval = 5
+//! ```
+//!
+//! Options that can be repeated take arrays, and flags take booleans. Upstreams can be written
+//! either as strings in --upstream syntax or as tables:
+//!
+//! ```toml
+//! bind = "0.0.0.0:80"
+//! strategy = "weighted-round-robin"
+//!
+//! [[upstream]]
+//! address = "10.0.0.1:8080"
+//! weight = 3
+//! check = "HEAD:/healthz"
+//!
+//! [rate_limit]
+//! algorithm = "token-bucket"
+//! key = "header:x-api-key"
+//! class = ["premium-=1000"]
+//! ```
+//!
+//! Anything given on the command line takes precedence over the file.
+
+use crate::CmdOptions;
+use clap::{ArgSettings, Clap, IntoApp};
+use toml::Value;
+
+/// Parses the command line `args` (program name included), filling in anything it doesn't set from
+/// the configuration file it names with --config. This is used both at startup and whenever the
+/// configuration is reloaded.
+pub fn load_options(args: &[String]) -> Result<CmdOptions, String> {
+    let app = CmdOptions::into_app();
+    let matches = app
+        .clone()
+        .try_get_matches_from(args)
+        .map_err(|err| err.to_string())?;
+    let path = match matches.value_of("config") {
+        Some(path) => path,
+        None => return CmdOptions::try_parse_from(args).map_err(|err| err.to_string()),
+    };
+    let contents = std::fs::read_to_string(path)
+        .map_err(|err| format!("Could not read config file {}: {}", path, err))?;
+    let settings = contents
+        .parse::<Value>()
+        .map_err(|err| format!("Invalid config file {}: {}", path, err))?;
+    let settings = match settings {
+        Value::Table(table) => table,
+        _ => unreachable!("a TOML document is always a table"),
+    };
+    let mut flattened = Vec::new();
+    flatten("", &settings, &mut flattened)
+        .map_err(|err| format!("{} in config file {}", err, path))?;
+
+    // The settings go first, so that --upstream (which takes several values) can stay last on the
+    // command line
+    let mut combined = vec![args[0].clone()];
+    for (name, values) in flattened {
+        let long = name.replace('_', "-");
+        let arg = app
+            .get_arguments()
+            .find(|arg| arg.get_long() == Some(long.as_str()))
+            .filter(|arg| arg.get_long() != Some("config"))
+            .ok_or_else(|| format!("Unknown setting {} in config file {}", name, path))?;
+        if matches.occurrences_of(arg.get_name()) > 0 {
+            continue;
+        }
+        match values {
+            Setting::Flag(true) if !arg.is_set(ArgSettings::TakesValue) => {
+                combined.push(format!("--{}", long))
+            }
+            Setting::Flag(false) if !arg.is_set(ArgSettings::TakesValue) => {}
+            Setting::Values(values) if arg.is_set(ArgSettings::TakesValue) => {
+                for value in values {
+                    combined.push(format!("--{}={}", long, value));
+                }
+            }
+            _ => {
+                return Err(format!(
+                    "Setting {} in config file {} has the wrong type",
+                    name, path
+                ))
+            }
+        }
+    }
+    combined.extend(args[1..].iter().cloned());
+    CmdOptions::try_parse_from(&combined)
+        .map_err(|err| format!("Invalid config file {}: {}", path, err))
+}
+
+/// The value of a single setting, ready to be turned into command-line arguments
+enum Setting {
+    Flag(bool),
+    Values(Vec<String>),
+}
+
+/// Collects the settings in `table`, naming each one after the tables it is nested in.
+fn flatten(
+    prefix: &str,
+    table: &toml::value::Table,
+    out: &mut Vec<(String, Setting)>,
+) -> Result<(), String> {
+    for (key, value) in table {
+        let name = match prefix {
+            "" => key.replace('-', "_"),
+            _ => format!("{}_{}", prefix, key.replace('-', "_")),
+        };
+        match value {
+            Value::Table(table) => flatten(&name, table, out)?,
+            Value::Boolean(flag) => out.push((name, Setting::Flag(*flag))),
+            Value::Array(values) => {
+                let values = values
+                    .iter()
+                    .map(|value| match value {
+                        Value::Table(upstream) if name == "upstream" => upstream_spec(upstream),
+                        _ => scalar(&name, value),
+                    })
+                    .collect::<Result<Vec<_>, _>>()?;
+                out.push((name, Setting::Values(values)));
+            }
+            _ => {
+                let value = scalar(&name, value)?;
+                out.push((name, Setting::Values(vec![value])));
+            }
+        }
+    }
+    Ok(())
+}
+
+fn scalar(name: &str, value: &Value) -> Result<String, String> {
+    match value {
+        Value::String(value) => Ok(value.clone()),
+        Value::Integer(value) => Ok(value.to_string()),
+        Value::Float(value) => Ok(value.to_string()),
+        _ => Err(format!("Setting {} has the wrong type", name)),
+    }
+}
+
+/// Turns an upstream table into --upstream syntax, i.e. ADDRESS[=WEIGHT][@CHECK].
+fn upstream_spec(upstream: &toml::value::Table) -> Result<String, String> {
+    let mut spec = match upstream.get("address") {
+        Some(Value::String(address)) => address.clone(),
+        _ => return Err("Every upstream needs an address".to_string()),
+    };
+    for key in upstream.keys() {
+        if !["address", "weight", "check"].contains(&key.as_str()) {
+            return Err(format!("Unknown upstream setting {}", key));
+        }
+    }
+    match upstream.get("weight") {
+        Some(Value::Integer(weight)) => spec.push_str(&format!("={}", weight)),
+        Some(_) => return Err(format!("Upstream {} has an invalid weight", spec)),
+        None => {}
+    }
+    match upstream.get("check") {
+        Some(Value::String(check)) => spec.push_str(&format!("@{}", check)),
+        Some(_) => return Err(format!("Upstream {} has an invalid check", spec)),
+        None => {}
+    }
+    Ok(spec)
+}
@@ -0,0 +1,129 @@
+//! Converting between SystemTime and UTC calendar dates, for timestamps in the access log and the
+//! Date and Expires headers the response cache looks at. Both directions use Howard Hinnant's
+//! algorithms (civil_from_days and days_from_civil), which count in 400-year eras starting on
+//! March 1st, so that leap days fall at the end of a year.
+
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+pub const MONTHS: [&str; 12] = [
+    "Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec",
+];
+
+/// Splits a time into its UTC year, month, day, hour, minute and second.
+pub fn civil_time(time: SystemTime) -> (i64, u32, u32, u32, u32, u32) {
+    let secs = time
+        .duration_since(UNIX_EPOCH)
+        .map(|since| since.as_secs())
+        .unwrap_or(0);
+    let (days, secs_of_day) = ((secs / 86400) as i64, (secs % 86400) as u32);
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let day_of_era = z.rem_euclid(146_097);
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let shifted_month = (5 * day_of_year + 2) / 153;
+    let day = (day_of_year - (153 * shifted_month + 2) / 5 + 1) as u32;
+    let month = if shifted_month < 10 {
+        shifted_month + 3
+    } else {
+        shifted_month - 9
+    } as u32;
+    let year = year_of_era + era * 400 + if month <= 2 { 1 } else { 0 };
+    (
+        year,
+        month,
+        day,
+        secs_of_day / 3600,
+        secs_of_day % 3600 / 60,
+        secs_of_day % 60,
+    )
+}
+
+/// Parses a date in the format HTTP uses (e.g. "Sun, 06 Nov 1994 08:49:37 GMT"). The obsolete
+/// formats that RFC 9110 still asks recipients to accept aren't supported. These dates come from
+/// upstreams, so anything out of range (including years before 1970 or after 9999) is rejected
+/// rather than trusted to fit.
+pub fn parse_http_date(date: &str) -> Option<SystemTime> {
+    let parts = date.split_whitespace().collect::<Vec<&str>>();
+    if parts.len() != 6 || parts[5] != "GMT" {
+        return None;
+    }
+    let day = parts[1].parse::<i64>().ok()?;
+    let month = MONTHS.iter().position(|month| *month == parts[2])? as i64 + 1;
+    let year = parts[3].parse::<i64>().ok()?;
+    let time = parts[4]
+        .split(':')
+        .map(|part| part.parse::<u32>().ok())
+        .collect::<Option<Vec<u32>>>()?;
+    // A second of 60 is a leap second
+    if time.len() != 3
+        || !(1..=31).contains(&day)
+        || !(1970..=9999).contains(&year)
+        || time[0] >= 24
+        || time[1] >= 60
+        || time[2] > 60
+    {
+        return None;
+    }
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let year_of_era = year.rem_euclid(400);
+    let shifted_month = if month > 2 { month - 3 } else { month + 9 };
+    let day_of_year = (153 * shifted_month + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    let days = era
+        .checked_mul(146_097)?
+        .checked_add(day_of_era)?
+        .checked_sub(719_468)?;
+    let secs = days
+        .checked_mul(86400)?
+        .checked_add(i64::from(time[0] * 3600 + time[1] * 60 + time[2]))?;
+    if secs < 0 {
+        return None;
+    }
+    Some(UNIX_EPOCH + Duration::from_secs(secs as u64))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_known_date() {
+        assert_eq!(
+            parse_http_date("Sun, 06 Nov 1994 08:49:37 GMT"),
+            Some(UNIX_EPOCH + Duration::from_secs(784_111_777))
+        );
+        assert_eq!(
+            parse_http_date("Fri, 31 Dec 9999 23:59:59 GMT"),
+            Some(UNIX_EPOCH + Duration::from_secs(253_402_300_799))
+        );
+        let time = parse_http_date("Thu, 29 Feb 2024 12:30:60 GMT").unwrap();
+        assert_eq!(civil_time(time), (2024, 2, 29, 12, 31, 0));
+    }
+
+    #[test]
+    fn rejects_out_of_range_years() {
+        for year in &[
+            "1969",
+            "10000",
+            "-400",
+            "9223372036854775807",
+            "99999999999999999999",
+        ] {
+            let date = format!("Sun, 06 Nov {} 08:49:37 GMT", year);
+            assert_eq!(parse_http_date(&date), None, "{}", date);
+        }
+    }
+
+    #[test]
+    fn rejects_out_of_range_times() {
+        for time in &[
+            "99:99:99", "24:00:00", "23:60:00", "23:59:61", "-1:00:00", "08:49",
+        ] {
+            let date = format!("Sun, 06 Nov 1994 {} GMT", time);
+            assert_eq!(parse_http_date(&date), None, "{}", date);
+        }
+    }
+}
@@ -0,0 +1,264 @@
+//! Headers that tell upstreams who they're really talking to (X-Forwarded-For, X-Forwarded-Proto
+//! and RFC 7239 Forwarded), and the rules that add, set or remove headers on requests and
+//! responses passing through (see --request-header and --response-header).
+
+use crate::request;
+use http::header::{HeaderMap, HeaderName, HeaderValue};
+use std::net::IpAddr;
+
+/// Headers that header rules may not touch
+const FRAMING_HEADERS: &[&str] = &["connection", "content-length", "transfer-encoding"];
+
+/// A range of client addresses written in CIDR notation (e.g. 10.0.0.0/8 or fd00::/8), or a single
+/// address
+#[derive(Clone, Copy, Debug)]
+pub struct IpRange {
+    network: IpAddr,
+    prefix_len: u32,
+}
+
+impl std::str::FromStr for IpRange {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<IpRange, String> {
+        let invalid = || format!("invalid address range \"{}\" (expected e.g. 10.0.0.0/8)", s);
+        let (address, prefix_len) = match s.find('/') {
+            Some(idx) => (&s[..idx], Some(&s[idx + 1..])),
+            None => (s, None),
+        };
+        let network = address.parse::<IpAddr>().map_err(|_| invalid())?;
+        let max_len = if network.is_ipv4() { 32 } else { 128 };
+        let prefix_len = match prefix_len {
+            Some(len) => len
+                .parse::<u32>()
+                .ok()
+                .filter(|len| *len <= max_len)
+                .ok_or_else(invalid)?,
+            None => max_len,
+        };
+        Ok(IpRange {
+            network,
+            prefix_len,
+        })
+    }
+}
+
+impl IpRange {
+    pub fn contains(&self, address: IpAddr) -> bool {
+        // Compare the leading prefix_len bits of both addresses
+        let (network, address, len) = match (self.network, address) {
+            (IpAddr::V4(network), IpAddr::V4(address)) => {
+                (u32::from(network) as u128, u32::from(address) as u128, 32)
+            }
+            (IpAddr::V6(network), IpAddr::V6(address)) => {
+                (u128::from(network), u128::from(address), 128)
+            }
+            _ => return false,
+        };
+        let shift = len - self.prefix_len;
+        shift == 128 || network >> shift == address >> shift
+    }
+}
+
+/// What header rules can refer to in their values
+pub struct Variables {
+    /// $client_ip: the address of the client connected to us
+    client_ip: String,
+    /// $host: the Host the client asked for
+    host: String,
+    /// $scheme: http or https
+    scheme: &'static str,
+    /// $version: the HTTP version the client spoke, as written in a Via header
+    version: &'static str,
+}
+
+impl Variables {
+    pub fn new(
+        request: &http::Request<Vec<u8>>,
+        client_ip: &str,
+        tls: bool,
+        version: http::Version,
+    ) -> Variables {
+        Variables {
+            client_ip: client_ip.to_string(),
+            host: request
+                .headers()
+                .get(http::header::HOST)
+                .and_then(|host| host.to_str().ok())
+                .unwrap_or("")
+                .to_string(),
+            scheme: if tls { "https" } else { "http" },
+            version: match version {
+                http::Version::HTTP_10 => "1.0",
+                http::Version::HTTP_2 => "2",
+                _ => "1.1",
+            },
+        }
+    }
+
+    /// Replaces the variables in `template` with their values. This is done in one pass, so that
+    /// values taken from the request (like $host) are never expanded themselves.
+    fn expand(&self, template: &str) -> String {
+        let variables = [
+            ("$client_ip", self.client_ip.as_str()),
+            ("$host", self.host.as_str()),
+            ("$scheme", self.scheme),
+            ("$version", self.version),
+        ];
+        let mut expanded = String::new();
+        let mut rest = template;
+        while let Some(idx) = rest.find('$') {
+            expanded.push_str(&rest[..idx]);
+            rest = &rest[idx..];
+            match variables.iter().find(|(name, _)| rest.starts_with(name)) {
+                Some((name, value)) => {
+                    expanded.push_str(value);
+                    rest = &rest[name.len()..];
+                }
+                None => {
+                    expanded.push('$');
+                    rest = &rest[1..];
+                }
+            }
+        }
+        expanded.push_str(rest);
+        expanded
+    }
+}
+
+#[derive(Clone, Debug)]
+enum Action {
+    /// Adds a value, keeping any the message already had
+    Add(String),
+    /// Replaces any values the message had
+    Set(String),
+    Remove,
+}
+
+/// Adds, sets or removes a header, written add:NAME=VALUE, set:NAME=VALUE or remove:NAME. VALUE
+/// may refer to $client_ip, $host, $scheme and $version.
+#[derive(Clone, Debug)]
+pub struct HeaderRule {
+    name: HeaderName,
+    action: Action,
+}
+
+impl std::str::FromStr for HeaderRule {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<HeaderRule, String> {
+        let invalid = || {
+            format!(
+                "invalid header rule \"{}\" (expected add:NAME=VALUE, set:NAME=VALUE or \
+                remove:NAME)",
+                s
+            )
+        };
+        let idx = s.find(':').ok_or_else(invalid)?;
+        let (kind, rest) = (&s[..idx], &s[idx + 1..]);
+        let (name, action) = match (kind, rest.find('=')) {
+            ("add", Some(idx)) => (&rest[..idx], Action::Add(rest[idx + 1..].to_string())),
+            ("set", Some(idx)) => (&rest[..idx], Action::Set(rest[idx + 1..].to_string())),
+            ("remove", None) => (rest, Action::Remove),
+            _ => return Err(invalid()),
+        };
+        if let Action::Add(value) | Action::Set(value) = &action {
+            HeaderValue::from_str(value).map_err(|_| invalid())?;
+        }
+        let name = HeaderName::from_bytes(name.as_bytes()).map_err(|_| invalid())?;
+        // We rely on these to know where one message ends and the next begins
+        if FRAMING_HEADERS.contains(&name.as_str()) {
+            return Err(format!("header rule \"{}\" can't change {}", s, name));
+        }
+        Ok(HeaderRule { name, action })
+    }
+}
+
+impl HeaderRule {
+    fn apply(&self, headers: &mut HeaderMap, variables: &Variables) {
+        let value = match &self.action {
+            Action::Add(template) | Action::Set(template) => {
+                match HeaderValue::from_str(&variables.expand(template)) {
+                    Ok(value) => value,
+                    Err(_) => {
+                        log::warn!("Header rule for {} produced an invalid value", self.name);
+                        return;
+                    }
+                }
+            }
+            Action::Remove => {
+                headers.remove(&self.name);
+                return;
+            }
+        };
+        if let Action::Add(_) = self.action {
+            headers.append(&self.name, value);
+        } else {
+            headers.insert(&self.name, value);
+        }
+    }
+}
+
+/// Applies `rules` to `headers`, in order.
+pub fn apply_rules(rules: &[HeaderRule], headers: &mut HeaderMap, variables: &Variables) {
+    for rule in rules {
+        rule.apply(headers, variables);
+    }
+}
+
+/// The header rules for the requests routed to one pool, and for the responses to them
+#[derive(Clone, Debug)]
+pub struct HeaderRules {
+    pub request: Vec<HeaderRule>,
+    pub response: Vec<HeaderRule>,
+}
+
+/// Tells the upstream where the request came from, with X-Forwarded-For, X-Forwarded-Proto and
+/// Forwarded headers. If the client is a `trusted` proxy, what it says about the clients behind it
+/// is passed on and our hop is added to it; otherwise anything the client claimed is overwritten.
+pub fn add_forwarding_headers(
+    request: &mut http::Request<Vec<u8>>,
+    variables: &Variables,
+    trusted: bool,
+) {
+    if !trusted {
+        let headers = request.headers_mut();
+        headers.remove("x-forwarded-for");
+        headers.remove("x-forwarded-proto");
+        headers.remove(http::header::FORWARDED);
+    }
+
+    // Add X-Forwarded-For header so that the upstream server knows the client's IP address.
+    // (We're the ones connecting directly to the upstream server, so without this header, the
+    // upstream server will only know our IP, not the client's.)
+    request::extend_header_value(request, "x-forwarded-for", &variables.client_ip);
+    // A trusted proxy in front of us knows better which protocol the client used
+    if !request.headers().contains_key("x-forwarded-proto") {
+        request.headers_mut().insert(
+            "x-forwarded-proto",
+            HeaderValue::from_static(variables.scheme),
+        );
+    }
+
+    let node = match variables.client_ip.parse::<IpAddr>() {
+        Ok(IpAddr::V6(address)) => format!("\"[{}]\"", address),
+        _ => forwarded_value(&variables.client_ip),
+    };
+    let mut element = format!("for={}", node);
+    if !variables.host.is_empty() {
+        element.push_str(&format!(";host={}", forwarded_value(&variables.host)));
+    }
+    element.push_str(&format!(";proto={}", variables.scheme));
+    request::extend_header_value(request, "forwarded", &element);
+}
+
+/// Writes a Forwarded parameter value as a token if it can be one, or else as a quoted string
+/// (RFC 7239 section 4)
+fn forwarded_value(value: &str) -> String {
+    let is_token_char = |c: char| c.is_ascii_alphanumeric() || "!#$%&'*+-.^_`|~".contains(c);
+    if !value.is_empty() && value.chars().all(is_token_char) {
+        value.to_string()
+    } else {
+        format!("\"{}\"", value.replace('\\', "\\\\").replace('"', "\\\""))
+    }
+}
@@ -0,0 +1,161 @@
+use crate::tls::{self, UpstreamConnector};
+use crate::{request, response};
+use regex::Regex;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::time;
+
+/// How an upstream is checked
+#[derive(Clone, Debug)]
+pub enum CheckKind {
+    /// The upstream is healthy if we can open a TCP connection to it. This is meant for upstreams
+    /// that don't speak HTTP (or don't have anything worth requesting).
+    Tcp,
+    /// The upstream is healthy if it answers this request with an acceptable response
+    Http { method: http::Method, path: String },
+}
+
+impl std::str::FromStr for CheckKind {
+    type Err = String;
+
+    /// Parses a check of the form "tcp", "PATH" or "METHOD:PATH" (e.g. "HEAD:/healthz").
+    fn from_str(s: &str) -> Result<CheckKind, String> {
+        if s == "tcp" {
+            return Ok(CheckKind::Tcp);
+        }
+        let (method, path) = match s.find(':') {
+            Some(idx) if !s.starts_with('/') => (&s[..idx], &s[idx + 1..]),
+            _ => ("GET", s),
+        };
+        if !path.starts_with('/') {
+            return Err(format!(
+                "invalid health check \"{}\" (expected tcp, PATH or METHOD:PATH)",
+                s
+            ));
+        }
+        let method = http::Method::from_bytes(method.to_uppercase().as_bytes())
+            .map_err(|_| format!("invalid method in health check \"{}\"", s))?;
+        Ok(CheckKind::Http {
+            method,
+            path: path.to_string(),
+        })
+    }
+}
+
+/// The response status codes that count as healthy, e.g. "200,204" or "200-399"
+#[derive(Clone, Debug)]
+pub struct StatusSet(Vec<(u16, u16)>);
+
+impl std::str::FromStr for StatusSet {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<StatusSet, String> {
+        let invalid = || {
+            format!(
+                "invalid status set \"{}\" (expected e.g. 200,204,300-399)",
+                s
+            )
+        };
+        s.split(',')
+            .map(|range| {
+                let range = range.trim();
+                let (low, high) = match range.find('-') {
+                    Some(idx) => (&range[..idx], &range[idx + 1..]),
+                    None => (range, range),
+                };
+                let low = low.trim().parse::<u16>().map_err(|_| invalid())?;
+                let high = high.trim().parse::<u16>().map_err(|_| invalid())?;
+                if low > high {
+                    return Err(invalid());
+                }
+                Ok((low, high))
+            })
+            .collect::<Result<Vec<_>, _>>()
+            .map(StatusSet)
+    }
+}
+
+impl StatusSet {
+    pub fn contains(&self, status: http::StatusCode) -> bool {
+        let status = status.as_u16();
+        self.0
+            .iter()
+            .any(|(low, high)| *low <= status && status <= *high)
+    }
+}
+
+/// Settings shared by the health checks of all upstreams
+pub struct HealthCheckConfig {
+    /// Number of checks in a row that must pass before a dead upstream is put back into rotation
+    pub rise: usize,
+    /// Number of checks in a row that must fail before an upstream is taken out of rotation
+    pub fall: usize,
+    /// A check that takes longer than this fails
+    pub timeout: Duration,
+    /// Response statuses that count as healthy
+    pub statuses: StatusSet,
+    /// If set, the response body must also match this pattern
+    pub body_pattern: Option<Regex>,
+    /// Connects to upstreams the same way requests do, over TLS where needed
+    pub connector: Arc<UpstreamConnector>,
+}
+
+/// Checks whether the upstream at `address` is healthy.
+pub async fn check(address: &str, kind: &CheckKind, config: &HealthCheckConfig) -> bool {
+    match time::timeout(config.timeout, run_check(address, kind, config)).await {
+        Ok(Ok(())) => true,
+        Ok(Err(reason)) => {
+            log::debug!("Health check of {} failed: {}", address, reason);
+            false
+        }
+        Err(_elapsed) => {
+            log::debug!(
+                "Health check of {} failed: no answer within {:?}",
+                address,
+                config.timeout
+            );
+            false
+        }
+    }
+}
+
+async fn run_check(
+    address: &str,
+    kind: &CheckKind,
+    config: &HealthCheckConfig,
+) -> Result<(), String> {
+    let (_, host_port) = tls::parse_upstream_address(address)?;
+    let mut stream = config
+        .connector
+        .connect(address)
+        .await
+        .map_err(|err| format!("failed to connect: {}", err))?;
+    let (method, path) = match kind {
+        // For an https:// upstream, this includes the TLS handshake
+        CheckKind::Tcp => return Ok(()),
+        CheckKind::Http { method, path } => (method, path),
+    };
+
+    let request = http::Request::builder()
+        .method(method)
+        .uri(path)
+        .header("host", host_port)
+        .body(Vec::new())
+        .unwrap();
+    request::write_to_stream(&request, &mut stream)
+        .await
+        .map_err(|err| format!("failed to send request: {}", err))?;
+    let response = response::read_from_stream(&mut stream, request.method())
+        .await
+        .map_err(|err| format!("failed to read response: {}", err))?;
+
+    if !config.statuses.contains(response.status()) {
+        return Err(format!("unexpected status {}", response.status()));
+    }
+    if let Some(pattern) = &config.body_pattern {
+        if !pattern.is_match(&String::from_utf8_lossy(response.body())) {
+            return Err(format!("response body doesn't match {}", pattern));
+        }
+    }
+    Ok(())
+}
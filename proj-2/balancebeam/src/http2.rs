@@ -0,0 +1,595 @@
+//! Serving clients that speak HTTP/2, either over TLS (agreed on with ALPN) or in plain text with
+//! prior knowledge (h2c). Every stream is proxied as a request of its own, so the streams on one
+//! client connection are spread over the upstreams just like separate HTTP/1.1 requests would be.
+//! Upstreams are always spoken to in HTTP/1.1.
+//!
+//! Unlike HTTP/1.1 requests, whose bodies are streamed through, request and response bodies are
+//! read in full before being passed on (up to response::MAX_BODY_SIZE).
+
+use crate::access_log::{self, AccessLog};
+use crate::body::{self, Framing};
+use crate::cache::{self, Lookup, ResponseCache};
+use crate::chunked::Trailers;
+use crate::metrics::{self, Metrics};
+use crate::pool::PooledConnection;
+use crate::rate_limiting::Decision;
+use crate::tls::ClientStream;
+use crate::{
+    connect_to_upstream, headers, request, response, routing, shutting_down, wants_close,
+    ForwardError, ProxyState, SharedRateLimits, ShutdownSignal,
+};
+use bytes::Bytes;
+use h2::server::SendResponse;
+use h2::RecvStream;
+use http::StatusCode;
+use std::io;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::Mutex;
+use tokio::time;
+
+/// What a client speaking HTTP/2 with prior knowledge starts its connection with
+pub const PREFACE: &[u8] = b"PRI * HTTP/2.0\r\n\r\nSM\r\n\r\n";
+
+/// Number of streams a client may have open on one connection at once
+const MAX_CONCURRENT_STREAMS: u32 = 100;
+
+/// Headers that only mean something on a single HTTP/1.1 connection, which HTTP/2 forbids
+const CONNECTION_HEADERS: [&str; 5] = [
+    "connection",
+    "keep-alive",
+    "proxy-connection",
+    "transfer-encoding",
+    "upgrade",
+];
+
+/// Everything the streams on a connection share
+struct StreamContext {
+    client_ip: String,
+    /// Whether the client is a --trusted-proxy
+    trusted: bool,
+    tls: bool,
+    share_state: Arc<Mutex<ProxyState>>,
+    share_rate_limit: SharedRateLimits,
+    metrics: Arc<Metrics>,
+    /// How long a client may stall while sending a request body
+    body_timeout: Duration,
+    access_log: Option<Arc<AccessLog>>,
+    response_cache: Option<Arc<ResponseCache>>,
+}
+
+/// Serves an HTTP/2 client connection until the client hangs up. When we start shutting down, the
+/// client is told not to open any more streams, and the ones it already opened are finished.
+pub async fn serve_connection(
+    client_conn: ClientStream,
+    share_state: Arc<Mutex<ProxyState>>,
+    share_rate_limit: SharedRateLimits,
+    access_log: Option<Arc<AccessLog>>,
+    metrics: Arc<Metrics>,
+    mut shutdown: ShutdownSignal,
+) {
+    let client_ip = client_conn.peer_addr().ip().to_string();
+    let (trusted, body_timeout, idle_timeout, response_cache) = {
+        let state = share_state.lock().await;
+        (
+            state.is_trusted_proxy(&client_ip),
+            state.client_body_timeout,
+            state.client_idle_timeout,
+            state.response_cache.clone(),
+        )
+    };
+    let context = Arc::new(StreamContext {
+        client_ip,
+        trusted,
+        tls: client_conn.is_tls(),
+        share_state,
+        share_rate_limit,
+        metrics,
+        body_timeout,
+        access_log,
+        response_cache,
+    });
+    let mut connection = match h2::server::Builder::new()
+        .max_concurrent_streams(MAX_CONCURRENT_STREAMS)
+        .handshake::<_, Bytes>(client_conn)
+        .await
+    {
+        Ok(connection) => connection,
+        Err(err) => {
+            log::info!(
+                "HTTP/2 handshake with {} failed: {}",
+                context.client_ip,
+                err
+            );
+            return;
+        }
+    };
+    let mut closing = false;
+    loop {
+        tokio::select! {
+            next = connection.accept() => match next {
+                Some(Ok((request, respond))) => {
+                    let context = context.clone();
+                    tokio::spawn(async move { handle_stream(request, respond, &context).await });
+                }
+                Some(Err(err)) => {
+                    log::info!("HTTP/2 error on connection from {}: {}", context.client_ip, err);
+                    return;
+                }
+                None => {
+                    log::debug!("Client finished sending requests. Shutting down connection");
+                    return;
+                }
+            },
+            _ = shutting_down(&mut shutdown), if !closing => {
+                log::debug!("Asking HTTP/2 client to stop opening streams to shut down");
+                connection.graceful_shutdown();
+                closing = true;
+            }
+            _ = time::delay_for(idle_timeout), if !closing => {
+                // Every open stream holds on to the context, so this is the only reference to it
+                // when none are
+                if Arc::strong_count(&context) == 1 {
+                    log::debug!(
+                        "Closing HTTP/2 client connection that has been idle for {:?}",
+                        idle_timeout
+                    );
+                    connection.graceful_shutdown();
+                    closing = true;
+                }
+            }
+        }
+    }
+}
+
+async fn handle_stream(
+    request: http::Request<RecvStream>,
+    mut respond: SendResponse<Bytes>,
+    context: &StreamContext,
+) {
+    let client_ip = &context.client_ip;
+    let metrics = &context.metrics;
+    let mut entry = access_log::Entry::new(context.access_log.clone(), client_ip);
+    entry.set_request(&request);
+    let (mut request, body) = match read_head(request) {
+        Ok(head) => head,
+        Err(status) => {
+            send_error_response(&mut respond, status, None, &mut entry, metrics);
+            return;
+        }
+    };
+    let started = Instant::now();
+
+    // A client that is over its limit is turned away before we take in its body
+    let rate_limit =
+        context
+            .share_rate_limit
+            .lock()
+            .await
+            .check(client_ip, context.trusted, &request);
+    entry.set_rate_limit(rate_limit.as_ref());
+    if let Some(decision) = rate_limit.as_ref().filter(|decision| decision.limited) {
+        metrics.record_rate_limited();
+        send_error_response(
+            &mut respond,
+            StatusCode::TOO_MANY_REQUESTS,
+            Some(decision),
+            &mut entry,
+            metrics,
+        );
+        return;
+    }
+    if let Err(status) = read_body(&mut request, body, context.body_timeout).await {
+        send_error_response(&mut respond, status, None, &mut entry, metrics);
+        return;
+    }
+
+    let share_state = &context.share_state;
+    let (pool, key, retryable, max_retries, per_try_timeout, rate_limit_headers, header_rules) = {
+        let mut state = share_state.lock().await;
+        state.retry_policy.budget.record_request();
+        let pool = routing::route(&state.routes, &request).to_string();
+        let header_rules = state.pools[&pool].header_rules.clone();
+        (
+            pool,
+            state.hash_key.extract(client_ip, &request),
+            state.retry_policy.methods.contains(request.method()),
+            state.retry_policy.max_retries,
+            state.retry_policy.per_try_timeout,
+            state.rate_limit_headers,
+            header_rules,
+        )
+    };
+    let variables =
+        headers::Variables::new(&request, client_ip, context.tls, http::Version::HTTP_2);
+    headers::add_forwarding_headers(&mut request, &variables, context.trusted);
+    headers::apply_rules(&header_rules.request, request.headers_mut(), &variables);
+
+    let cacheable = request.body().is_empty() && cache::is_cacheable(&request);
+    let stale = match context.response_cache.as_ref().filter(|_| cacheable) {
+        Some(cache) => match cache.lookup(&pool, &request) {
+            Lookup::Hit(mut response) => {
+                metrics.record_cache_lookup("hit");
+                headers::apply_rules(&header_rules.response, response.headers_mut(), &variables);
+                if let (Some(decision), true) = (&rate_limit, rate_limit_headers) {
+                    decision.add_headers(response.headers_mut());
+                }
+                metrics.record_response(Some(metrics::FROM_CACHE), response.status());
+                entry.set_status(response.status());
+                entry.add_bytes(response.body().len() as u64);
+                if let Err(error) = send_response(&mut respond, response) {
+                    log::warn!("Failed to send response to client: {}", error);
+                    return;
+                }
+                metrics.record_request_duration(started.elapsed());
+                return;
+            }
+            Lookup::Stale(stored) => {
+                stored.add_condition(&mut request);
+                Some(stored)
+            }
+            Lookup::Miss => {
+                metrics.record_cache_lookup("miss");
+                None
+            }
+        },
+        None => None,
+    };
+
+    // The body has been read in full, so any retryable request can be sent again
+    let mut failed_upstreams = Vec::new();
+    let mut error_status = StatusCode::BAD_GATEWAY;
+    let mut new_connection = false;
+    let (mut upstream, _upstream_guard, mut response, response_framing, server_error) = loop {
+        let (mut upstream, upstream_guard) = match connect_to_upstream(
+            share_state,
+            &pool,
+            &key,
+            &mut failed_upstreams,
+            new_connection,
+            &mut error_status,
+        )
+        .await
+        {
+            Some(upstream) => upstream,
+            None => {
+                send_error_response(&mut respond, error_status, None, &mut entry, metrics);
+                return;
+            }
+        };
+        log::info!(
+            "{} -> {}: {} (HTTP/2)",
+            client_ip,
+            upstream.address,
+            request::format_request_line(&request)
+        );
+        let forward_started = Instant::now();
+        let result = forward_request(&request, &mut upstream, per_try_timeout, metrics).await;
+        entry.set_upstream(&upstream.address, forward_started.elapsed());
+        match result {
+            Ok((response, response_framing)) => {
+                metrics.record_upstream_duration(&upstream.address, forward_started.elapsed());
+                // As on HTTP/1, anything but a 5xx only counts as a success once its body has
+                // arrived intact
+                let server_error = response.status().is_server_error();
+                if server_error {
+                    share_state
+                        .lock()
+                        .await
+                        .record_outcome(&upstream.address, false)
+                        .await;
+                }
+                break (
+                    upstream,
+                    upstream_guard,
+                    response,
+                    response_framing,
+                    server_error,
+                );
+            }
+            Err(ForwardError::ConnectionClosed) => {
+                log::info!(
+                    "Sending {} again on a new connection",
+                    request::format_request_line(&request)
+                );
+                new_connection = true;
+            }
+            Err(ForwardError::Upstream(status)) => {
+                let mut state = share_state.lock().await;
+                state.record_outcome(&upstream.address, false).await;
+                failed_upstreams.push(upstream.address.clone());
+                if !retryable
+                    || failed_upstreams.len() > max_retries
+                    || !state.retry_policy.budget.try_spend()
+                {
+                    drop(state);
+                    send_error_response(&mut respond, status, None, &mut entry, metrics);
+                    return;
+                }
+                log::info!(
+                    "Retrying {} on another upstream",
+                    request::format_request_line(&request)
+                );
+                error_status = status;
+            }
+            // forward_request only reads the request body from the client on HTTP/1, and it has
+            // already been read in full here
+            Err(ForwardError::BadRequest)
+            | Err(ForwardError::ClientTimeout)
+            | Err(ForwardError::ClientGone) => unreachable!(),
+        }
+    };
+
+    let mut upstream_body = body::ReadTimeout::new(&mut upstream.stream, per_try_timeout);
+    if let Err(error) =
+        response::read_body(&mut upstream_body, &mut response, response_framing).await
+    {
+        log::error!(
+            "Error reading response body from upstream {}: {}",
+            upstream.address,
+            error
+        );
+        // As on HTTP/1, a body the upstream fails to send counts against it, though one that is
+        // merely too big for us to hold doesn't
+        if !matches!(error, response::Error::ResponseBodyTooLarge) {
+            share_state
+                .lock()
+                .await
+                .record_outcome(&upstream.address, false)
+                .await;
+        }
+        // The body is sent on in one piece, so the client hasn't seen any of the response yet
+        let status = match error {
+            response::Error::Connection(err) if err.kind() == io::ErrorKind::TimedOut => {
+                StatusCode::GATEWAY_TIMEOUT
+            }
+            _ => StatusCode::BAD_GATEWAY,
+        };
+        send_error_response(&mut respond, status, None, &mut entry, metrics);
+        return;
+    }
+    if !server_error {
+        share_state
+            .lock()
+            .await
+            .record_outcome(&upstream.address, true)
+            .await;
+    }
+    if let Some(cache) = &context.response_cache {
+        match stale {
+            Some(stored) if response.status() == StatusCode::NOT_MODIFIED => {
+                metrics.record_cache_lookup("revalidated");
+                response = cache.revalidated(&pool, &request, stored, &response);
+            }
+            stale => {
+                if stale.is_some() {
+                    metrics.record_cache_lookup("miss");
+                }
+                if cacheable {
+                    cache.store(&pool, &request, &response);
+                }
+            }
+        }
+        cache.invalidate(&pool, &request, &response);
+    }
+    log::info!(
+        "{} <- {} (HTTP/2)",
+        client_ip,
+        response::format_response_line(&response)
+    );
+    headers::apply_rules(&header_rules.response, response.headers_mut(), &variables);
+    if let (Some(decision), true) = (&rate_limit, rate_limit_headers) {
+        decision.add_headers(response.headers_mut());
+    }
+    let upstream_address = upstream.address.clone();
+    let body_bytes = response.body().len() as u64;
+    // The upstream connection can carry another request now, unless it was closed to end the body
+    // or either side asked for it to be closed
+    if response_framing != Framing::UntilClose
+        && !wants_close(request.headers())
+        && !wants_close(response.headers())
+    {
+        share_state.lock().await.connection_pool.release(upstream);
+    }
+    metrics.record_response(Some(&upstream_address), response.status());
+    entry.set_status(response.status());
+    if let Err(error) = send_response(&mut respond, response) {
+        log::warn!("Failed to send response to client: {}", error);
+        return;
+    }
+    metrics.add_response_body_bytes(&upstream_address, body_bytes);
+    entry.add_bytes(body_bytes);
+    metrics.record_request_duration(started.elapsed());
+}
+
+/// Turns the head of a request on an HTTP/2 stream into the HTTP/1.1 request we send upstream.
+/// The body is left on the stream for read_body.
+fn read_head(
+    request: http::Request<RecvStream>,
+) -> Result<(http::Request<Vec<u8>>, RecvStream), StatusCode> {
+    let (mut parts, stream) = request.into_parts();
+
+    // HTTP/2 carries the host in the :authority pseudo-header rather than a Host header, and
+    // allows the Cookie header to be split up, which HTTP/1.1 doesn't
+    if !parts.headers.contains_key(http::header::HOST) {
+        if let Some(authority) = parts.uri.authority() {
+            let host = http::HeaderValue::from_str(authority.as_str())
+                .map_err(|_| StatusCode::BAD_REQUEST)?;
+            parts.headers.insert(http::header::HOST, host);
+        }
+    }
+    let cookies = parts
+        .headers
+        .get_all(http::header::COOKIE)
+        .iter()
+        .map(|cookie| cookie.as_bytes())
+        .collect::<Vec<_>>()
+        .join(&b"; "[..]);
+    if !cookies.is_empty() {
+        let cookies =
+            http::HeaderValue::from_bytes(&cookies).map_err(|_| StatusCode::BAD_REQUEST)?;
+        parts.headers.insert(http::header::COOKIE, cookies);
+    }
+    for name in CONNECTION_HEADERS.iter() {
+        parts.headers.remove(*name);
+    }
+    parts.uri = parts
+        .uri
+        .path_and_query()
+        .map(|path| path.as_str())
+        .unwrap_or("/")
+        .parse()
+        .map_err(|_| StatusCode::BAD_REQUEST)?;
+    parts.version = http::Version::HTTP_11;
+    Ok((http::Request::from_parts(parts, Vec::new()), stream))
+}
+
+/// Reads the body (and any trailers) of a request off its HTTP/2 stream, and frames it the way
+/// HTTP/1.1 needs it.
+async fn read_body(
+    request: &mut http::Request<Vec<u8>>,
+    mut stream: RecvStream,
+    body_timeout: Duration,
+) -> Result<(), StatusCode> {
+    let mut body = Vec::new();
+    loop {
+        let data = match time::timeout(body_timeout, stream.data()).await {
+            Ok(Some(data)) => data,
+            Ok(None) => break,
+            Err(_elapsed) => return Err(StatusCode::REQUEST_TIMEOUT),
+        };
+        let data = data.map_err(|_| StatusCode::BAD_REQUEST)?;
+        if body.len() + data.len() > response::MAX_BODY_SIZE {
+            return Err(StatusCode::PAYLOAD_TOO_LARGE);
+        }
+        body.extend_from_slice(&data);
+        // Let the client send more
+        let _ = stream.flow_control().release_capacity(data.len());
+    }
+    let trailers = stream
+        .trailers()
+        .await
+        .map_err(|_| StatusCode::BAD_REQUEST)?;
+
+    *request.body_mut() = body;
+    match trailers {
+        Some(trailers) => {
+            request.headers_mut().remove(http::header::CONTENT_LENGTH);
+            request.headers_mut().insert(
+                http::header::TRANSFER_ENCODING,
+                http::HeaderValue::from_static("chunked"),
+            );
+            request.extensions_mut().insert(Trailers(trailers));
+        }
+        None if !request.body().is_empty()
+            || request.headers().contains_key(http::header::CONTENT_LENGTH) =>
+        {
+            let len = request.body().len();
+            request
+                .headers_mut()
+                .insert(http::header::CONTENT_LENGTH, len.into());
+        }
+        None => {}
+    }
+    Ok(())
+}
+
+/// Sends a request (body included) to the upstream, and waits up to `per_try_timeout` for the
+/// head of its final response. Interim 1xx responses are skipped, since the client has already
+/// sent everything.
+async fn forward_request(
+    request: &http::Request<Vec<u8>>,
+    upstream: &mut PooledConnection,
+    per_try_timeout: Duration,
+    metrics: &Metrics,
+) -> Result<(http::Response<Vec<u8>>, Framing), ForwardError> {
+    let upstream_failed = match upstream.reused {
+        true => ForwardError::ConnectionClosed,
+        false => ForwardError::Upstream(StatusCode::BAD_GATEWAY),
+    };
+    if let Err(error) = request::write_to_stream(request, &mut upstream.stream).await {
+        log::error!(
+            "Failed to send request to upstream {}: {}",
+            upstream.address,
+            error
+        );
+        return Err(upstream_failed);
+    }
+    metrics.add_request_body_bytes(&upstream.address, request.body().len() as u64);
+
+    let stream = &mut upstream.stream;
+    let mut responded = false;
+    let read_final_response = async {
+        loop {
+            match response::read_head(stream, request.method()).await {
+                Ok((response, _)) if response.status().is_informational() => responded = true,
+                result => return result,
+            }
+        }
+    };
+    match time::timeout(per_try_timeout, read_final_response).await {
+        Ok(Ok(head)) => Ok(head),
+        Ok(Err(response::Error::IncompleteResponse(0))) if !responded => {
+            log::error!("Upstream {} hung up without responding", upstream.address);
+            Err(upstream_failed)
+        }
+        Ok(Err(error)) => {
+            log::error!(
+                "Error reading response from upstream {}: {}",
+                upstream.address,
+                error
+            );
+            Err(ForwardError::Upstream(StatusCode::BAD_GATEWAY))
+        }
+        Err(_elapsed) => {
+            log::error!(
+                "Upstream {} didn't respond within {:?}",
+                upstream.address,
+                per_try_timeout
+            );
+            Err(ForwardError::Upstream(StatusCode::GATEWAY_TIMEOUT))
+        }
+    }
+}
+
+/// Sends a response (including its body and any trailers) on an HTTP/2 stream.
+fn send_response(
+    respond: &mut SendResponse<Bytes>,
+    response: http::Response<Vec<u8>>,
+) -> Result<(), h2::Error> {
+    let (mut parts, body) = response.into_parts();
+    let trailers = parts.extensions.remove::<Trailers>();
+    for name in CONNECTION_HEADERS.iter() {
+        parts.headers.remove(*name);
+    }
+    parts.version = http::Version::HTTP_2;
+    let end_of_stream = body.is_empty() && trailers.is_none();
+    let mut stream = respond.send_response(http::Response::from_parts(parts, ()), end_of_stream)?;
+    if !body.is_empty() {
+        stream.send_data(Bytes::from(body), trailers.is_none())?;
+    }
+    if let Some(Trailers(trailers)) = trailers {
+        stream.send_trailers(trailers)?;
+    }
+    Ok(())
+}
+
+/// Sends a response we made up ourselves, counting it in the metrics and the access log.
+fn send_error_response(
+    respond: &mut SendResponse<Bytes>,
+    status: StatusCode,
+    rate_limit: Option<&Decision>,
+    entry: &mut access_log::Entry,
+    metrics: &Metrics,
+) {
+    let mut response = response::make_http_error(status);
+    if let Some(decision) = rate_limit {
+        decision.add_headers(response.headers_mut());
+    }
+    metrics.record_response(None, status);
+    entry.set_status(status);
+    entry.add_bytes(response.body().len() as u64);
+    if let Err(error) = send_response(respond, response) {
+        log::warn!("Failed to send response to client: {}", error);
+    }
+}
@@ -0,0 +1,103 @@
+use crate::load_balancing::ConnectionGuard;
+use parking_lot::Mutex;
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+/// How long clients that are turned away because we're overloaded are asked to wait before trying
+/// again (in seconds), in a Retry-After header
+pub const RETRY_AFTER_SECS: u64 = 1;
+
+/// How long we spend telling a client that we don't have room for its connection before giving up
+/// on it
+pub const REJECTION_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// The most clients we spend time turning away at once. Beyond this we are being flooded, and
+/// answering every connection would use up the file descriptors and CPU we are trying to save, so
+/// any more connections we don't have room for are closed right away.
+const MAX_REJECTIONS: usize = 100;
+
+/// The most client connections we keep open at once. 0 means unlimited.
+#[derive(Clone, Copy, Debug)]
+pub struct ConnectionLimits {
+    pub max_total: usize,
+    pub max_per_client: usize,
+}
+
+/// Counts the client connections that are open, in total and per client address. This outlives
+/// configuration reloads, so that connections accepted under an old configuration still count.
+#[derive(Default)]
+pub struct OpenConnections {
+    counts: Mutex<Counts>,
+    /// Number of connections we are in the middle of turning away
+    rejecting: Arc<AtomicUsize>,
+}
+
+#[derive(Default)]
+struct Counts {
+    total: usize,
+    per_client: HashMap<IpAddr, usize>,
+}
+
+/// Holds a client connection's place in the counts until it is dropped
+pub struct ConnectionSlot {
+    connections: Arc<OpenConnections>,
+    client: IpAddr,
+}
+
+impl OpenConnections {
+    /// Counts a new connection from `client`, unless that would take us over `limits`, in which
+    /// case the error says which one.
+    pub fn try_open(
+        self: &Arc<Self>,
+        client: IpAddr,
+        limits: ConnectionLimits,
+    ) -> Result<ConnectionSlot, String> {
+        let mut counts = self.counts.lock();
+        if limits.max_total > 0 && counts.total >= limits.max_total {
+            return Err(format!(
+                "{} connections are already open (see --max-connections)",
+                counts.total
+            ));
+        }
+        let from_client = counts.per_client.get(&client).copied().unwrap_or(0);
+        if limits.max_per_client > 0 && from_client >= limits.max_per_client {
+            return Err(format!(
+                "{} connections from {} are already open (see --max-connections-per-client)",
+                from_client, client
+            ));
+        }
+        counts.total += 1;
+        counts.per_client.insert(client, from_client + 1);
+        Ok(ConnectionSlot {
+            connections: self.clone(),
+            client,
+        })
+    }
+
+    /// Counts a connection that try_open turned away while we tell its client so, unless
+    /// MAX_REJECTIONS are already in progress, in which case None means the connection should just
+    /// be closed.
+    pub fn try_reject(&self) -> Option<ConnectionGuard> {
+        let guard = ConnectionGuard::new(self.rejecting.clone());
+        if self.rejecting.load(Ordering::SeqCst) > MAX_REJECTIONS {
+            return None;
+        }
+        Some(guard)
+    }
+}
+
+impl Drop for ConnectionSlot {
+    fn drop(&mut self) {
+        let mut counts = self.connections.counts.lock();
+        counts.total -= 1;
+        if let Some(from_client) = counts.per_client.get_mut(&self.client) {
+            *from_client -= 1;
+            if *from_client == 0 {
+                counts.per_client.remove(&self.client);
+            }
+        }
+    }
+}
@@ -0,0 +1,390 @@
+use rand::{Rng, SeedableRng};
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+
+/// The policies that can be used to decide which upstream a new client connection goes to. These
+/// are selected with the --strategy command-line option.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Strategy {
+    /// Pick any live upstream uniformly at random
+    Random,
+    /// Cycle through the live upstreams in order
+    RoundRobin,
+    /// Pick the upstream with the fewest connections currently being proxied to it (relative to
+    /// its weight)
+    LeastConnections,
+    /// Pick a live upstream at random, with probability proportional to its weight
+    WeightedRandom,
+    /// Cycle through the live upstreams, visiting each one a number of times proportional to its
+    /// weight. Uses nginx's "smooth" algorithm, so heavy upstreams are interleaved with the others
+    /// rather than being sent long bursts of connections.
+    WeightedRoundRobin,
+    /// Hash a key identifying the client (see HashKey) onto a consistent-hash ring, so the same
+    /// client keeps going to the same upstream, and adding or removing an upstream only moves the
+    /// clients that were (or will be) on that upstream
+    ConsistentHash,
+}
+
+impl std::str::FromStr for Strategy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Strategy, String> {
+        match s {
+            "random" => Ok(Strategy::Random),
+            "round-robin" => Ok(Strategy::RoundRobin),
+            "least-connections" => Ok(Strategy::LeastConnections),
+            "weighted-random" => Ok(Strategy::WeightedRandom),
+            "weighted-round-robin" => Ok(Strategy::WeightedRoundRobin),
+            "consistent-hash" => Ok(Strategy::ConsistentHash),
+            _ => Err(format!(
+                "unknown strategy \"{}\" (expected one of random, round-robin, \
+                least-connections, weighted-random, weighted-round-robin, consistent-hash)",
+                s
+            )),
+        }
+    }
+}
+
+/// What the consistent-hash strategy hashes in order to decide where a client goes. This is
+/// selected with the --hash-key command-line option.
+#[derive(Clone, Debug, PartialEq)]
+pub enum HashKey {
+    /// The client's IP address
+    ClientIp,
+    /// The value of the named request header
+    Header(String),
+    /// The value of the named cookie (e.g. a session ID)
+    Cookie(String),
+}
+
+impl std::str::FromStr for HashKey {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<HashKey, String> {
+        if s == "ip" {
+            return Ok(HashKey::ClientIp);
+        }
+        match s.find(':') {
+            Some(idx) if s[..idx] == *"header" && idx + 1 < s.len() => {
+                Ok(HashKey::Header(s[idx + 1..].to_lowercase()))
+            }
+            Some(idx) if s[..idx] == *"cookie" && idx + 1 < s.len() => {
+                Ok(HashKey::Cookie(s[idx + 1..].to_string()))
+            }
+            _ => Err(format!(
+                "invalid hash key \"{}\" (expected ip, header:NAME or cookie:NAME)",
+                s
+            )),
+        }
+    }
+}
+
+impl HashKey {
+    /// Extracts the key for the given request. If the request doesn't carry the header or cookie
+    /// we are looking for, we fall back to hashing the client's IP address, so that the client at
+    /// least sticks to one upstream for as long as its address doesn't change.
+    pub fn extract(&self, client_ip: &str, request: &http::Request<Vec<u8>>) -> String {
+        let value = match self {
+            HashKey::ClientIp => None,
+            HashKey::Header(name) => request
+                .headers()
+                .get(name.as_str())
+                .and_then(|value| value.to_str().ok())
+                .map(|value| value.to_string()),
+            HashKey::Cookie(name) => find_cookie(request, name),
+        };
+        value.unwrap_or_else(|| client_ip.to_string())
+    }
+}
+
+/// Returns the value of the named cookie from the request's Cookie header(s), if present.
+fn find_cookie(request: &http::Request<Vec<u8>>, name: &str) -> Option<String> {
+    for header_value in request.headers().get_all("cookie") {
+        let header_value = match header_value.to_str() {
+            Ok(header_value) => header_value,
+            Err(_) => continue,
+        };
+        for pair in header_value.split(';') {
+            let mut parts = pair.trim().splitn(2, '=');
+            if parts.next() == Some(name) {
+                if let Some(value) = parts.next() {
+                    return Some(value.trim_matches('"').to_string());
+                }
+            }
+        }
+    }
+    None
+}
+
+/// What a load balancer gets to know about an upstream that is currently able to take requests.
+pub struct Candidate<'a> {
+    pub address: &'a str,
+    pub weight: usize,
+    pub active_connections: usize,
+}
+
+/// A load balancer picks one upstream out of the list of live candidates. Implementations may keep
+/// whatever state they need between calls (e.g. a round-robin position).
+pub trait LoadBalancer: Send {
+    /// Returns the index of the chosen candidate, or None if there are no candidates. `key`
+    /// identifies the client (see HashKey); strategies that don't care where a client went last
+    /// time ignore it.
+    fn select(&mut self, candidates: &[Candidate], key: &str) -> Option<usize>;
+}
+
+/// Constructs the load balancer implementing the given strategy.
+pub fn new_load_balancer(strategy: Strategy) -> Box<dyn LoadBalancer> {
+    match strategy {
+        Strategy::Random => Box::new(RandomBalancer::new()),
+        Strategy::RoundRobin => Box::new(RoundRobinBalancer { next: 0 }),
+        Strategy::LeastConnections => Box::new(LeastConnectionsBalancer { next: 0 }),
+        Strategy::WeightedRandom => Box::new(WeightedRandomBalancer::new()),
+        Strategy::WeightedRoundRobin => Box::new(WeightedRoundRobinBalancer {
+            current_weights: HashMap::new(),
+        }),
+        Strategy::ConsistentHash => Box::new(ConsistentHashBalancer {
+            members: Vec::new(),
+            ring: Vec::new(),
+        }),
+    }
+}
+
+struct RandomBalancer {
+    rng: rand::rngs::StdRng,
+}
+
+impl RandomBalancer {
+    fn new() -> RandomBalancer {
+        RandomBalancer {
+            rng: rand::rngs::StdRng::from_entropy(),
+        }
+    }
+}
+
+impl LoadBalancer for RandomBalancer {
+    fn select(&mut self, candidates: &[Candidate], _key: &str) -> Option<usize> {
+        if candidates.is_empty() {
+            return None;
+        }
+        Some(self.rng.gen_range(0, candidates.len()))
+    }
+}
+
+struct RoundRobinBalancer {
+    next: usize,
+}
+
+impl LoadBalancer for RoundRobinBalancer {
+    fn select(&mut self, candidates: &[Candidate], _key: &str) -> Option<usize> {
+        if candidates.is_empty() {
+            return None;
+        }
+        let idx = self.next % candidates.len();
+        self.next = self.next.wrapping_add(1);
+        Some(idx)
+    }
+}
+
+struct LeastConnectionsBalancer {
+    /// Where to start scanning the candidate list. This rotates on every call so that ties (e.g.
+    /// when every upstream is idle) are broken round-robin instead of always favoring the first
+    /// upstream.
+    next: usize,
+}
+
+impl LoadBalancer for LeastConnectionsBalancer {
+    fn select(&mut self, candidates: &[Candidate], _key: &str) -> Option<usize> {
+        if candidates.is_empty() {
+            return None;
+        }
+        let start = self.next % candidates.len();
+        self.next = self.next.wrapping_add(1);
+
+        let mut best = start;
+        for offset in 1..candidates.len() {
+            let idx = (start + offset) % candidates.len();
+            // Compare active_connections / weight without dividing:
+            // a.conns / a.weight < b.conns / b.weight  <=>  a.conns * b.weight < b.conns * a.weight
+            let candidate = &candidates[idx];
+            let current = &candidates[best];
+            if candidate.active_connections * current.weight
+                < current.active_connections * candidate.weight
+            {
+                best = idx;
+            }
+        }
+        Some(best)
+    }
+}
+
+struct WeightedRandomBalancer {
+    rng: rand::rngs::StdRng,
+}
+
+impl WeightedRandomBalancer {
+    fn new() -> WeightedRandomBalancer {
+        WeightedRandomBalancer {
+            rng: rand::rngs::StdRng::from_entropy(),
+        }
+    }
+}
+
+impl LoadBalancer for WeightedRandomBalancer {
+    fn select(&mut self, candidates: &[Candidate], _key: &str) -> Option<usize> {
+        let total_weight: usize = candidates.iter().map(|c| c.weight).sum();
+        if total_weight == 0 {
+            return None;
+        }
+        let mut point = self.rng.gen_range(0, total_weight);
+        for (idx, candidate) in candidates.iter().enumerate() {
+            if point < candidate.weight {
+                return Some(idx);
+            }
+            point -= candidate.weight;
+        }
+        unreachable!("point is always less than the total weight")
+    }
+}
+
+struct WeightedRoundRobinBalancer {
+    /// The "current weight" of each upstream, keyed by address. On every selection, each
+    /// candidate's current weight grows by its configured weight, the candidate with the highest
+    /// current weight wins, and the winner's current weight is reduced by the total weight.
+    current_weights: HashMap<String, isize>,
+}
+
+impl LoadBalancer for WeightedRoundRobinBalancer {
+    fn select(&mut self, candidates: &[Candidate], _key: &str) -> Option<usize> {
+        if candidates.is_empty() {
+            return None;
+        }
+        // Forget about upstreams that are no longer candidates (e.g. because they died), so they
+        // start from scratch if they come back
+        self.current_weights
+            .retain(|address, _| candidates.iter().any(|c| c.address == address));
+
+        let mut total_weight = 0;
+        let mut best: Option<(usize, isize)> = None;
+        for (idx, candidate) in candidates.iter().enumerate() {
+            let current = self
+                .current_weights
+                .entry(candidate.address.to_string())
+                .or_insert(0);
+            *current += candidate.weight as isize;
+            total_weight += candidate.weight as isize;
+            let is_best = match best {
+                Some((_, best_weight)) => *current > best_weight,
+                None => true,
+            };
+            if is_best {
+                best = Some((idx, *current));
+            }
+        }
+
+        let (best_idx, _) = best.unwrap();
+        *self
+            .current_weights
+            .get_mut(candidates[best_idx].address)
+            .unwrap() -= total_weight;
+        Some(best_idx)
+    }
+}
+
+/// Number of points each unit of weight gets on the consistent-hash ring. More points means a more
+/// even spread of keys, at the cost of a bigger ring.
+const VIRTUAL_NODES_PER_WEIGHT: usize = 100;
+
+/// Largest weight an upstream can be given, so that a big weight can't make the consistent-hash
+/// ring (which is rebuilt whenever an upstream comes or goes) huge
+pub const MAX_WEIGHT: usize = 1000;
+
+struct ConsistentHashBalancer {
+    /// The (address, weight) of each candidate the ring was built from, in candidate order
+    members: Vec<(String, usize)>,
+    /// Points on the ring sorted by hash, each pointing at an index into `members`
+    ring: Vec<(u64, usize)>,
+}
+
+impl ConsistentHashBalancer {
+    /// Rebuilds the ring if the set of candidates has changed since the last call (e.g. because an
+    /// upstream died or came back).
+    fn update_ring(&mut self, candidates: &[Candidate]) {
+        let unchanged = self.members.len() == candidates.len()
+            && self
+                .members
+                .iter()
+                .zip(candidates)
+                .all(|((address, weight), c)| address == c.address && *weight == c.weight);
+        if unchanged {
+            return;
+        }
+
+        self.members = candidates
+            .iter()
+            .map(|c| (c.address.to_string(), c.weight))
+            .collect();
+        // Each upstream's points only depend on its own address, so an upstream joining or leaving
+        // the ring doesn't move anyone else's points
+        self.ring = Vec::new();
+        for (idx, (address, weight)) in self.members.iter().enumerate() {
+            for vnode in 0..weight * VIRTUAL_NODES_PER_WEIGHT {
+                self.ring
+                    .push((hash(format!("{}#{}", address, vnode).as_bytes()), idx));
+            }
+        }
+        self.ring.sort_unstable();
+    }
+}
+
+impl LoadBalancer for ConsistentHashBalancer {
+    fn select(&mut self, candidates: &[Candidate], key: &str) -> Option<usize> {
+        if candidates.is_empty() {
+            return None;
+        }
+        self.update_ring(candidates);
+        // The key belongs to the first point at or after its hash, wrapping around the ring
+        let key_hash = hash(key.as_bytes());
+        let point = self
+            .ring
+            .partition_point(|(point_hash, _)| *point_hash < key_hash);
+        Some(self.ring[point % self.ring.len()].1)
+    }
+}
+
+/// A hash that is stable across runs and machines (unlike std's DefaultHasher, whose algorithm
+/// may change between Rust releases), so that separate balancebeam instances agree on where each
+/// key goes. This is 64-bit FNV-1a followed by MurmurHash3's finalizer, which spreads similar
+/// inputs (e.g. "10.0.0.1:80#1" and "10.0.0.1:80#2") across the whole range.
+fn hash(data: &[u8]) -> u64 {
+    let mut h: u64 = 0xcbf2_9ce4_8422_2325;
+    for byte in data {
+        h ^= *byte as u64;
+        h = h.wrapping_mul(0x0000_0100_0000_01b3);
+    }
+    h ^= h >> 33;
+    h = h.wrapping_mul(0xff51_afd7_ed55_8ccd);
+    h ^= h >> 33;
+    h = h.wrapping_mul(0xc4ce_b9fe_1a85_ec53);
+    h ^= h >> 33;
+    h
+}
+
+/// Counts a client connection as outstanding against an upstream for as long as the guard is
+/// alive. The least-connections strategy uses these counts to find the least busy upstream.
+pub struct ConnectionGuard {
+    counter: Arc<AtomicUsize>,
+}
+
+impl ConnectionGuard {
+    pub fn new(counter: Arc<AtomicUsize>) -> ConnectionGuard {
+        counter.fetch_add(1, Ordering::SeqCst);
+        ConnectionGuard { counter }
+    }
+}
+
+impl Drop for ConnectionGuard {
+    fn drop(&mut self) {
+        self.counter.fetch_sub(1, Ordering::SeqCst);
+    }
+}
@@ -1,20 +1,53 @@
+mod access_log;
+mod admin;
+mod body;
+mod cache;
+mod chunked;
+mod circuit_breaker;
+mod config;
+mod dates;
+mod headers;
+mod health_check;
+mod http2;
+mod limits;
+mod load_balancing;
+mod metrics;
+mod pool;
 mod rate_limiting;
 mod request;
 mod response;
+mod retry;
+mod routing;
+mod tls;
+mod tunnel;
 
 use clap::Clap;
-use rand::{Rng, SeedableRng};
-// use std::net::{TcpListener, TcpStream};
-// use std::sync::{Arc, Mutex};
-// use threadpool::ThreadPool;
-// use std::net::{TcpListener, TcpStream};
-use std::sync::Arc;
-use std::time::Duration;
+use std::collections::HashMap;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Weak};
+use std::time::{Duration, Instant};
+use tokio::io::AsyncWriteExt;
 use tokio::net::{TcpListener, TcpStream};
-use tokio::sync::Mutex;
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::sync::{watch, Mutex};
 use tokio::{task, time};
+use tokio_rustls::TlsAcceptor;
 
-use crate::rate_limiting::FixWindowRateLimit;
+use crate::access_log::AccessLog;
+use crate::admin::AdminContext;
+use crate::body::Framing;
+use crate::cache::{Lookup, ResponseCache};
+use crate::circuit_breaker::{BreakerConfig, CircuitBreaker};
+use crate::headers::HeaderRules;
+use crate::health_check::{CheckKind, HealthCheckConfig, StatusSet};
+use crate::limits::{ConnectionLimits, OpenConnections};
+use crate::load_balancing::{Candidate, ConnectionGuard, HashKey, LoadBalancer, Strategy};
+use crate::metrics::Metrics;
+use crate::pool::{ConnectionPool, PoolConfig, PooledConnection};
+use crate::rate_limiting::{RateLimitConfig, RateLimits};
+use crate::retry::{RetryBudget, RetryMethods, RetryPolicy};
+use crate::tls::ClientStream;
 
 /// Contains information parsed from the command-line invocation of balancebeam. The Clap macros
 /// provide a fancy way to automatically construct a command-line argument parser.
@@ -28,8 +61,116 @@ struct CmdOptions {
         default_value = "0.0.0.0:1100"
     )]
     bind: String,
-    #[clap(short, long, about = "Upstream host to forward requests to")]
+    #[clap(
+        long,
+        about = "IP/port to serve the admin API on, for managing upstreams at runtime and \
+        scraping metrics from /metrics (off unless set; only bind it to an address untrusted \
+        clients can't reach)"
+    )]
+    admin_bind: Option<String>,
+    #[clap(
+        long,
+        about = "TOML file to read settings from, reloaded on SIGHUP (options given on the \
+        command line take precedence)"
+    )]
+    config: Option<String>,
+    #[clap(
+        long,
+        about = "How long to let open connections finish their requests after SIGTERM or SIGINT \
+        before exiting anyway (in seconds)",
+        default_value = "30"
+    )]
+    drain_timeout: u64,
+    #[clap(
+        long,
+        about = "Maximum number of client connections to keep open at once; clients beyond it are \
+        sent a 503 and disconnected (0 = unlimited)",
+        default_value = "0"
+    )]
+    max_connections: usize,
+    #[clap(
+        long,
+        about = "Maximum number of connections to keep open from each client IP at once; further \
+        connections from it are sent a 503 and disconnected (0 = unlimited)",
+        default_value = "0"
+    )]
+    max_connections_per_client: usize,
+    #[clap(
+        short,
+        long,
+        about = "Upstream host to forward requests to, optionally followed by =WEIGHT (e.g. \
+        10.0.0.1:80=3, at most 1000) for use with the weighted strategies, and by @CHECK to override how it is \
+        health checked (e.g. 10.0.0.1:80@HEAD:/healthz or 10.0.0.1:5432@tcp)"
+    )]
     upstream: Vec<String>,
+    #[clap(
+        long,
+        about = "Upstream host to forward requests routed to POOL to, written POOL=UPSTREAM in \
+        --upstream syntax (may be repeated). Upstreams given with --upstream are in the \"default\" \
+        pool"
+    )]
+    pool_upstream: Vec<routing::PoolSetting<String>>,
+    #[clap(
+        long,
+        about = "Send requests to a pool of upstreams, written \"MATCHER... => POOL\" where each \
+        MATCHER is host=HOST (or host=*.DOMAIN), path=PREFIX, path~REGEX, method=GET|HEAD, \
+        header:NAME=VALUE or header:NAME~REGEX. The first route whose matchers all match wins; \
+        requests matching none go to the default pool (may be repeated)"
+    )]
+    route: Vec<routing::Route>,
+    #[clap(
+        long,
+        about = "Add, set or remove a header on requests before they are forwarded, written \
+        add:NAME=VALUE, set:NAME=VALUE or remove:NAME. VALUE may refer to $client_ip, $host, \
+        $scheme and $version (e.g. set:X-Real-IP=$client_ip or add:Via=$version balancebeam; may \
+        be repeated)"
+    )]
+    request_header: Vec<headers::HeaderRule>,
+    #[clap(
+        long,
+        about = "Add, set or remove a header on responses from upstreams, written like \
+        --request-header (may be repeated)"
+    )]
+    response_header: Vec<headers::HeaderRule>,
+    #[clap(
+        long,
+        about = "Apply a --request-header rule only to requests routed to POOL, written POOL=RULE \
+        (may be repeated)"
+    )]
+    pool_request_header: Vec<routing::PoolSetting<headers::HeaderRule>>,
+    #[clap(
+        long,
+        about = "Apply a --response-header rule only to responses to requests routed to POOL, \
+        written POOL=RULE (may be repeated)"
+    )]
+    pool_response_header: Vec<routing::PoolSetting<headers::HeaderRule>>,
+    #[clap(
+        long,
+        about = "Clients in this address range (e.g. 10.0.0.0/8) are proxies whose X-Forwarded-For, \
+        X-Forwarded-Proto and Forwarded headers are passed on; those of other clients are \
+        overwritten (may be repeated)"
+    )]
+    trusted_proxy: Vec<headers::IpRange>,
+    #[clap(
+        long,
+        about = "Load balancing strategy: random, round-robin, least-connections, \
+        weighted-random, weighted-round-robin or consistent-hash",
+        default_value = "random"
+    )]
+    strategy: Strategy,
+    #[clap(
+        long,
+        about = "Load balance a pool with its own strategy, written POOL=STRATEGY (may be \
+        repeated)"
+    )]
+    pool_strategy: Vec<routing::PoolSetting<Strategy>>,
+    #[clap(
+        long,
+        about = "What the consistent-hash strategy hashes to pick an upstream: ip, header:NAME \
+        or cookie:NAME",
+        default_value = "ip"
+    )]
+    hash_key: HashKey,
     #[clap(
         long,
         about = "Perform active health checks on this interval (in seconds)",
@@ -44,147 +185,842 @@ struct CmdOptions {
     active_health_check_path: String,
     #[clap(
         long,
-        about = "Maximum number of requests to accept per IP per minute (0 = unlimited)",
+        about = "Request method to use for active health checks",
+        default_value = "GET"
+    )]
+    active_health_check_method: http::Method,
+    #[clap(
+        long,
+        about = "Only check that upstreams accept TCP connections, instead of sending them an \
+        HTTP request"
+    )]
+    active_health_check_tcp: bool,
+    #[clap(
+        long,
+        about = "Health check the upstreams in a pool that don't have a CHECK of their own this \
+        way, written POOL=CHECK (e.g. api=HEAD:/healthz or db=tcp; may be repeated)"
+    )]
+    pool_health_check: Vec<routing::PoolSetting<CheckKind>>,
+    #[clap(
+        long,
+        about = "Number of health checks in a row that must pass to bring a dead upstream back",
+        default_value = "1"
+    )]
+    active_health_check_rise: usize,
+    #[clap(
+        long,
+        about = "Number of health checks in a row that must fail to take an upstream out of \
+        rotation",
+        default_value = "1"
+    )]
+    active_health_check_fall: usize,
+    #[clap(
+        long,
+        about = "Fail health checks that take longer than this (in seconds)",
+        default_value = "5"
+    )]
+    active_health_check_timeout: u64,
+    #[clap(
+        long,
+        about = "Response statuses that pass a health check (e.g. 200,204 or 200-399)",
+        default_value = "200"
+    )]
+    active_health_check_status: StatusSet,
+    #[clap(
+        long,
+        about = "Regular expression the response body must match to pass a health check"
+    )]
+    active_health_check_body: Option<regex::Regex>,
+    #[clap(
+        long,
+        about = "Maximum number of requests to accept per IP per rate limit window, which is a \
+        minute unless set with --rate-limit-window (0 = unlimited)",
         default_value = "0"
     )]
     max_requests_per_minute: usize,
+    #[clap(
+        long,
+        about = "Rate limiting algorithm: fixed-window, sliding-window-log, \
+        sliding-window-counter or token-bucket",
+        default_value = "fixed-window"
+    )]
+    rate_limit_algorithm: rate_limiting::Algorithm,
+    #[clap(
+        long,
+        about = "Length of the rate limit window (in seconds)",
+        default_value = "60"
+    )]
+    rate_limit_window: u64,
+    #[clap(
+        long,
+        about = "Largest burst of requests the token-bucket algorithm lets through at once \
+        (0 = the same as --max-requests-per-minute)",
+        default_value = "0"
+    )]
+    rate_limit_burst: usize,
+    #[clap(
+        long,
+        about = "Tell clients about their rate limit quota with RateLimit-* headers on every \
+        response, not just on 429s"
+    )]
+    rate_limit_headers: bool,
+    #[clap(
+        long,
+        about = "What requests are counted per for rate limiting: ip, forwarded-for (the last \
+        X-Forwarded-For hop, from a --trusted-proxy), header:NAME or path[:SEGMENTS], or several joined with +",
+        default_value = "ip"
+    )]
+    rate_limit_key: rate_limiting::RateLimitKey,
+    #[clap(
+        long,
+        about = "Give clients whose rate limit key starts with PREFIX a limit of their own, \
+        written PREFIX=LIMIT (may be repeated)"
+    )]
+    rate_limit_class: Vec<rate_limiting::RateLimitClass>,
+    #[clap(
+        long,
+        about = "Maximum number of clients to track for rate limiting (0 = no limit); the least \
+        recently seen client is forgotten when there are more",
+        default_value = "100000"
+    )]
+    rate_limit_max_clients: usize,
+    #[clap(
+        long,
+        about = "Respond 408 Request Timeout to clients that take longer than this to send the \
+        headers of a request, once they have started (in seconds)",
+        default_value = "10"
+    )]
+    client_header_timeout: u64,
+    #[clap(
+        long,
+        about = "Respond 408 Request Timeout to clients that send nothing for this long while \
+        sending a request body (in seconds)",
+        default_value = "30"
+    )]
+    client_body_timeout: u64,
+    #[clap(
+        long,
+        about = "Close keep-alive client connections that don't start another request within this \
+        long (in seconds)",
+        default_value = "60"
+    )]
+    client_idle_timeout: u64,
+    #[clap(
+        long,
+        about = "Give up on connecting to an upstream after this long, responding 504 Gateway \
+        Timeout unless another upstream can take the request (in seconds)",
+        default_value = "5"
+    )]
+    upstream_connect_timeout: u64,
+    #[clap(
+        long,
+        about = "Maximum number of idle keep-alive connections to keep open to each upstream",
+        default_value = "16"
+    )]
+    upstream_max_idle: usize,
+    #[clap(
+        long,
+        about = "Maximum number of connections carrying requests to each upstream at once \
+        (0 = unlimited)",
+        default_value = "0"
+    )]
+    upstream_max_connections: usize,
+    #[clap(
+        long,
+        about = "Maximum number of requests that can wait for a connection to an upstream that is \
+        at --upstream-max-connections; any more get a 503 unless another upstream can take them",
+        default_value = "100"
+    )]
+    upstream_queue_size: usize,
+    #[clap(
+        long,
+        about = "Give up on a request that has waited this long for a connection to an upstream \
+        that is at --upstream-max-connections (in seconds)",
+        default_value = "10"
+    )]
+    upstream_queue_timeout: u64,
+    #[clap(
+        long,
+        about = "Close idle upstream connections that haven't been used for this long (in seconds)",
+        default_value = "30"
+    )]
+    upstream_idle_timeout: u64,
+    #[clap(
+        long,
+        about = "Close connections that switched protocols (e.g. to WebSocket) once nothing has \
+        been sent either way for this long (in seconds)",
+        default_value = "300"
+    )]
+    upgrade_idle_timeout: u64,
+    #[clap(
+        long,
+        about = "Comma-separated request methods that are safe to retry on another upstream",
+        default_value = "GET,HEAD,PUT,DELETE"
+    )]
+    retry_methods: RetryMethods,
+    #[clap(
+        long,
+        about = "Maximum number of times to retry a failed request on another upstream",
+        default_value = "2"
+    )]
+    max_retries: usize,
+    #[clap(
+        long,
+        about = "Give up on an upstream if it hasn't started responding within this many seconds, \
+        or sends nothing for this long partway through a response body",
+        default_value = "30"
+    )]
+    per_try_timeout: u64,
+    #[clap(
+        long,
+        about = "Maximum number of retries as a percentage of recent requests",
+        default_value = "20"
+    )]
+    retry_budget: usize,
+    #[clap(
+        long,
+        about = "Stop sending requests to an upstream after this many failures in a row",
+        default_value = "5"
+    )]
+    breaker_failure_threshold: usize,
+    #[clap(
+        long,
+        about = "Stop sending requests to an upstream when this percentage of its recent requests \
+        failed (0 = disabled)",
+        default_value = "50"
+    )]
+    breaker_error_rate: usize,
+    #[clap(
+        long,
+        about = "Number of recent requests the error rate is calculated over",
+        default_value = "20"
+    )]
+    breaker_window: usize,
+    #[clap(
+        long,
+        about = "How long to keep requests away from a failing upstream before trying it again \
+        (in seconds)",
+        default_value = "10"
+    )]
+    breaker_cool_down: u64,
+    #[clap(
+        long,
+        about = "Number of trial requests that must succeed before a failing upstream gets its \
+        full share of requests again",
+        default_value = "3"
+    )]
+    breaker_trial_requests: usize,
+    #[clap(
+        long,
+        about = "Serve HTTPS with the certificate and private key in these PEM files, written \
+        [HOSTNAME=]CERT_FILE:KEY_FILE. Clients asking for HOSTNAME through SNI get that \
+        certificate; the one without a HOSTNAME goes to everyone else (may be repeated)"
+    )]
+    tls_certificate: Vec<tls::CertificateSpec>,
+    #[clap(
+        long,
+        about = "Verify https:// upstreams against the CA certificates in this PEM file (defaults to \
+        the system's CA certificates)"
+    )]
+    upstream_tls_ca: Option<String>,
+    #[clap(
+        long,
+        about = "Present this client certificate to https:// upstreams that ask for one, written \
+        CERT_FILE:KEY_FILE"
+    )]
+    upstream_tls_client_certificate: Option<tls::CertificateSpec>,
+    #[clap(
+        long,
+        about = "Refuse upstreams that aren't reached over TLS (i.e. not written https://HOST:PORT)"
+    )]
+    upstream_require_tls: bool,
+    #[clap(
+        long,
+        about = "Write a line for every request to this file (or to standard output if \"-\"); \
+        the file is reopened on SIGUSR1, for log rotation"
+    )]
+    access_log: Option<String>,
+    #[clap(
+        long,
+        about = "How access log lines are written: combined (the Apache combined format, \
+        followed by upstream, latency and rate-limit fields) or json",
+        default_value = "combined"
+    )]
+    access_log_format: access_log::Format,
+    #[clap(
+        long,
+        about = "Cache responses to GET and HEAD requests in memory, up to this many megabytes in \
+        total (0 turns the cache off)",
+        default_value = "0"
+    )]
+    cache_size: usize,
 }
 
+#[derive(Clone, Copy, Debug, PartialEq)]
 enum UpstreamState {
     Active,
     Dead,
+    /// Taken out of rotation through the admin API so that it can be shut down: requests already
+    /// in flight are finished, but it gets no new ones. It is still health checked.
+    Draining,
+    /// Taken out of rotation through the admin API, and not health checked either
+    Disabled,
+}
+
+impl UpstreamState {
+    fn name(self) -> &'static str {
+        match self {
+            UpstreamState::Active => "active",
+            UpstreamState::Dead => "dead",
+            UpstreamState::Draining => "draining",
+            UpstreamState::Disabled => "disabled",
+        }
+    }
 }
 
 struct Upstream {
     address: String,
+    /// The pool requests are routed to this upstream through
+    pool: String,
     state: UpstreamState,
+    /// Relative share of traffic this upstream should get under the weighted strategies
+    weight: usize,
+    /// Number of requests currently being proxied to this upstream
+    active_connections: Arc<AtomicUsize>,
+    /// Keeps requests away from the upstream while real traffic to it keeps failing
+    breaker: CircuitBreaker,
+    /// How active health checks probe this upstream
+    health_check: CheckKind,
+    /// Number of active health checks in a row that passed
+    checks_passed: usize,
+    /// Number of active health checks in a row that failed
+    checks_failed: usize,
+    /// Whether the health checks consider the upstream alive. This is kept up to date while the
+    /// upstream is drained, and decides whether it is active or dead once it is enabled again.
+    healthy: bool,
+}
+
+impl Upstream {
+    /// Parses an --upstream argument of the form ADDRESS[=WEIGHT][@CHECK], where ADDRESS is either
+    /// HOST:PORT or https://HOST:PORT, into an upstream in `pool`. Upstreams without their own
+    /// CHECK are health checked with `default_check`. If `require_tls` is set, only https://
+    /// upstreams are accepted.
+    fn parse(
+        spec: &str,
+        pool: &str,
+        default_check: &CheckKind,
+        breaker_config: &Arc<BreakerConfig>,
+        require_tls: bool,
+    ) -> Result<Upstream, String> {
+        let (spec, health_check) = match spec.find('@') {
+            Some(idx) => (&spec[..idx], spec[idx + 1..].parse::<CheckKind>()?),
+            None => (spec, default_check.clone()),
+        };
+        let (address, weight) = match spec.rfind('=') {
+            Some(idx) => {
+                let weight = spec[idx + 1..]
+                    .parse::<usize>()
+                    .ok()
+                    .filter(|weight| (1..=load_balancing::MAX_WEIGHT).contains(weight))
+                    .ok_or_else(|| {
+                        format!(
+                            "invalid weight in upstream \"{}\" (expected 1 to {})",
+                            spec,
+                            load_balancing::MAX_WEIGHT
+                        )
+                    })?;
+                (&spec[..idx], weight)
+            }
+            None => (spec, 1),
+        };
+        let (use_tls, _) = tls::parse_upstream_address(address)?;
+        if require_tls && !use_tls {
+            return Err(format!(
+                "upstream \"{}\" must be reached over TLS (see --upstream-require-tls)",
+                address
+            ));
+        }
+        Ok(Upstream {
+            address: address.to_string(),
+            pool: pool.to_string(),
+            state: UpstreamState::Active,
+            weight,
+            active_connections: Arc::new(AtomicUsize::new(0)),
+            breaker: CircuitBreaker::new(address, breaker_config.clone()),
+            health_check,
+            checks_passed: 0,
+            checks_failed: 0,
+            healthy: true,
+        })
+    }
+
+    /// The state the health checks put the upstream in, unless it was drained or disabled
+    fn health_state(&self) -> UpstreamState {
+        if self.healthy {
+            UpstreamState::Active
+        } else {
+            UpstreamState::Dead
+        }
+    }
+
+    /// Applies the result of an active health check. The upstream only changes state once enough
+    /// checks in a row agree (see --active-health-check-rise and --active-health-check-fall).
+    fn record_check(&mut self, healthy: bool, config: &HealthCheckConfig, metrics: &Metrics) {
+        metrics.record_health_check(&self.address, healthy);
+        let old_state = self.state;
+        if healthy {
+            self.checks_passed += 1;
+            self.checks_failed = 0;
+            if !self.healthy && self.checks_passed >= config.rise {
+                log::info!("Upstream {} passed its health checks again", self.address);
+                self.healthy = true;
+            }
+        } else {
+            self.checks_failed += 1;
+            self.checks_passed = 0;
+            if self.healthy && self.checks_failed >= config.fall {
+                log::warn!("Upstream {} failed its health checks", self.address);
+                self.healthy = false;
+            }
+        }
+        if let UpstreamState::Active | UpstreamState::Dead = self.state {
+            self.state = self.health_state();
+        }
+        if self.state != old_state {
+            metrics.record_transition(&self.address, old_state.name(), self.state.name());
+        }
+    }
+}
+
+/// A named group of upstreams that requests are routed to (see --route), load balanced and health
+/// checked with settings of its own
+struct Pool {
+    /// Decides which live upstream in the pool each request is sent to
+    load_balancer: Box<dyn LoadBalancer>,
+    /// How upstreams in the pool without a CHECK of their own are health checked
+    default_check: CheckKind,
+    /// Headers to add, set or remove on requests to the pool and their responses
+    header_rules: Arc<HeaderRules>,
 }
 
 /// Contains information about the state of balancebeam (e.g. what servers we are currently proxying
 /// to, what servers have failed, rate limiting counts, etc.)
-///
-/// You should add fields to this struct in later milestones.
 struct ProxyState {
-    /// How frequently we check whether upstream servers are alive (Milestone 4)
-    #[allow(dead_code)]
+    /// How frequently we check whether upstream servers are alive
     active_health_check_interval: usize,
-    /// Where we should send requests when doing active health checks (Milestone 4)
-    #[allow(dead_code)]
-    active_health_check_path: String,
-    /// Maximum number of requests an individual IP can make in a minute (Milestone 5)
-    #[allow(dead_code)]
-    max_requests_per_minute: usize,
+    /// How active health checks decide whether an upstream is healthy
+    health_check: Arc<HealthCheckConfig>,
     /// Addresses of servers that we are proxying to
     upstream_addresses: Arc<Mutex<Vec<Upstream>>>,
+    /// The pools of upstreams, by name. There is always a default pool.
+    pools: HashMap<String, Pool>,
+    /// Which pool each request is sent to
+    routes: Vec<routing::Route>,
+    /// Clients whose forwarding headers are passed on rather than overwritten
+    trusted_proxies: Vec<headers::IpRange>,
+    /// What identifies a client for the purposes of consistent hashing
+    hash_key: HashKey,
+    /// Idle keep-alive connections to the upstreams, ready to carry the next request
+    connection_pool: Arc<ConnectionPool>,
+    /// When failed requests are retried on another upstream
+    retry_policy: RetryPolicy,
+    /// Whether responses that weren't rate limited also carry RateLimit-* headers
+    rate_limit_headers: bool,
+    /// How long a connection that switched protocols may go without anything being sent
+    upgrade_idle_timeout: Duration,
+    /// How long a client may take to send the headers of a request
+    client_header_timeout: Duration,
+    /// How long a client may stall while sending a request body
+    client_body_timeout: Duration,
+    /// How long a keep-alive client connection may sit between requests
+    client_idle_timeout: Duration,
+    /// Thresholds for the upstreams' circuit breakers
+    breaker_config: Arc<BreakerConfig>,
+    /// Whether upstreams that aren't reached over TLS are refused
+    upstream_require_tls: bool,
+    /// Responses kept to answer repeated requests without bothering an upstream, if caching is on
+    /// (it starts out empty whenever the configuration is reloaded)
+    response_cache: Option<Arc<ResponseCache>>,
 }
 
-impl ProxyState {
-    pub async fn select_upstream(&mut self) -> Option<TcpStream> {
-        // check all upstream and change state one.
-        let mut upstreams = self.upstream_addresses.lock().await;
-        for i in 0..upstreams.len() {
-            if matches!(upstreams[i].state, UpstreamState::Dead) {
-                continue;
+/// A rate limiter shared by all connections
+type SharedRateLimits = Arc<Mutex<RateLimits>>;
+
+/// Everything built from one version of the configuration. Each connection holds on to the
+/// generation it was accepted under, so reloading the configuration only affects new connections;
+/// an old generation goes away (along with its background tasks) once its last connection closes.
+#[derive(Clone)]
+struct Generation {
+    state: Arc<Mutex<ProxyState>>,
+    rate_limits: SharedRateLimits,
+    /// How long to wait for connections to finish when shutting down
+    drain_timeout: Duration,
+    /// How many client connections we accept
+    connection_limits: ConnectionLimits,
+    /// Terminates TLS on client connections, if we serve HTTPS
+    tls: Option<TlsAcceptor>,
+    /// Where every request is logged, if anywhere. This is kept out of the (locked) state so that
+    /// connections we turn away when overloaded can be logged without waiting for the lock.
+    access_log: Option<Arc<AccessLog>>,
+}
+
+impl Generation {
+    async fn new(options: CmdOptions, metrics: &Arc<Metrics>) -> Result<Generation, String> {
+        if options.upstream.is_empty() && options.pool_upstream.is_empty() {
+            return Err(
+                "At least one upstream server must be specified using the --upstream option."
+                    .to_string(),
+            );
+        }
+        if options.breaker_trial_requests == 0 {
+            return Err("--breaker-trial-requests must be at least 1".to_string());
+        }
+        if options.rate_limit_window == 0 {
+            return Err("--rate-limit-window must be at least 1 second".to_string());
+        }
+        let breaker_config = Arc::new(BreakerConfig {
+            failure_threshold: options.breaker_failure_threshold,
+            error_rate_percent: options.breaker_error_rate,
+            window_size: options.breaker_window,
+            cool_down: Duration::from_secs(options.breaker_cool_down),
+            trial_requests: options.breaker_trial_requests,
+        });
+        let default_check = if options.active_health_check_tcp {
+            CheckKind::Tcp
+        } else {
+            CheckKind::Http {
+                method: options.active_health_check_method,
+                path: options.active_health_check_path,
             }
-            let address = &upstreams[i].address;
-            match TcpStream::connect(address).await {
-                Ok(_stream) => {
-                    upstreams[i].state = UpstreamState::Active;
-                    // return Some(stream);
-                }
-                Err(err) => {
-                    log::error!("Failed to connect to upstream {}: {}", address, err);
-                    upstreams[i].state = UpstreamState::Dead;
-                    // continue;
-                }
+        };
+        let connector = Arc::new(tls::UpstreamConnector::new(
+            options.upstream_tls_ca.as_deref(),
+            options.upstream_tls_client_certificate.as_ref(),
+        )?);
+        let require_tls = options.upstream_require_tls;
+
+        // Pools are named by the upstreams in them; each starts out with the global settings
+        let mut pools = HashMap::new();
+        let strategy = options.strategy;
+        let header_rules = Arc::new(HeaderRules {
+            request: options.request_header.clone(),
+            response: options.response_header.clone(),
+        });
+        let pool_names = std::iter::once(routing::DEFAULT_POOL)
+            .chain(options.pool_upstream.iter().map(|spec| spec.pool.as_str()));
+        for name in pool_names {
+            pools.entry(name.to_string()).or_insert_with(|| Pool {
+                load_balancer: load_balancing::new_load_balancer(strategy),
+                default_check: default_check.clone(),
+                header_rules: header_rules.clone(),
+            });
+        }
+        let unknown_pool =
+            |option: &str, name: &str| format!("{} names unknown pool {}", option, name);
+        for setting in &options.pool_strategy {
+            pools
+                .get_mut(&setting.pool)
+                .ok_or_else(|| unknown_pool("--pool-strategy", &setting.pool))?
+                .load_balancer = load_balancing::new_load_balancer(setting.value);
+        }
+        for setting in &options.pool_health_check {
+            pools
+                .get_mut(&setting.pool)
+                .ok_or_else(|| unknown_pool("--pool-health-check", &setting.pool))?
+                .default_check = setting.value.clone();
+        }
+        let settings = options
+            .pool_request_header
+            .iter()
+            .map(|setting| (setting, true))
+            .chain(
+                options
+                    .pool_response_header
+                    .iter()
+                    .map(|setting| (setting, false)),
+            );
+        for (setting, is_request) in settings {
+            let pool = pools.get_mut(&setting.pool).ok_or_else(|| {
+                let option = if is_request {
+                    "--pool-request-header"
+                } else {
+                    "--pool-response-header"
+                };
+                unknown_pool(option, &setting.pool)
+            })?;
+            // The pool's rules come after the ones that apply everywhere
+            let rules = Arc::make_mut(&mut pool.header_rules);
+            if is_request {
+                rules.request.push(setting.value.clone());
+            } else {
+                rules.response.push(setting.value.clone());
+            }
+        }
+        if let Some(route) = options
+            .route
+            .iter()
+            .find(|route| !pools.contains_key(&route.pool))
+        {
+            return Err(unknown_pool("--route", &route.pool));
+        }
+
+        let specs = options
+            .upstream
+            .iter()
+            .map(|spec| (routing::DEFAULT_POOL, spec.as_str()))
+            .chain(
+                options
+                    .pool_upstream
+                    .iter()
+                    .map(|spec| (spec.pool.as_str(), spec.value.as_str())),
+            );
+        let mut upstreams: Vec<Upstream> = Vec::new();
+        for (pool, spec) in specs {
+            let upstream = Upstream::parse(
+                spec,
+                pool,
+                &pools[pool].default_check,
+                &breaker_config,
+                require_tls,
+            )?;
+            // Upstreams are told apart by their address (e.g. in the admin API), so one can't be
+            // in two pools
+            if upstreams
+                .iter()
+                .any(|other| other.address == upstream.address)
+            {
+                return Err(format!(
+                    "Upstream {} is listed more than once",
+                    upstream.address
+                ));
+            }
+            upstreams.push(upstream);
+        }
+
+        let access_log = match &options.access_log {
+            Some(path) => Some(Arc::new(AccessLog::open(path, options.access_log_format)?)),
+            None => None,
+        };
+        let state = ProxyState {
+            upstream_addresses: Arc::new(Mutex::new(upstreams)),
+            active_health_check_interval: options.active_health_check_interval,
+            health_check: Arc::new(HealthCheckConfig {
+                rise: options.active_health_check_rise,
+                fall: options.active_health_check_fall,
+                timeout: Duration::from_secs(options.active_health_check_timeout),
+                statuses: options.active_health_check_status,
+                body_pattern: options.active_health_check_body,
+                connector: connector.clone(),
+            }),
+            pools,
+            routes: options.route,
+            trusted_proxies: options.trusted_proxy,
+            hash_key: options.hash_key,
+            connection_pool: Arc::new(ConnectionPool::new(PoolConfig {
+                max_idle: options.upstream_max_idle,
+                max_per_upstream: options.upstream_max_connections,
+                max_queued: options.upstream_queue_size,
+                queue_timeout: Duration::from_secs(options.upstream_queue_timeout),
+                idle_timeout: Duration::from_secs(options.upstream_idle_timeout),
+                connect_timeout: Duration::from_secs(options.upstream_connect_timeout),
+                connector,
+            })),
+            retry_policy: RetryPolicy {
+                methods: options.retry_methods,
+                max_retries: options.max_retries,
+                per_try_timeout: Duration::from_secs(options.per_try_timeout),
+                budget: RetryBudget::new(options.retry_budget),
+            },
+            rate_limit_headers: options.rate_limit_headers,
+            upgrade_idle_timeout: Duration::from_secs(options.upgrade_idle_timeout),
+            client_header_timeout: Duration::from_secs(options.client_header_timeout),
+            client_body_timeout: Duration::from_secs(options.client_body_timeout),
+            client_idle_timeout: Duration::from_secs(options.client_idle_timeout),
+            breaker_config,
+            upstream_require_tls: options.upstream_require_tls,
+            response_cache: match options.cache_size {
+                0 => None,
+                megabytes => Some(Arc::new(ResponseCache::new(megabytes * 1024 * 1024))),
+            },
+        };
+        let rate_limits = Arc::new(Mutex::new(RateLimits::new(RateLimitConfig {
+            algorithm: options.rate_limit_algorithm,
+            default_limit: options.max_requests_per_minute,
+            window: Duration::from_secs(options.rate_limit_window),
+            burst: options.rate_limit_burst,
+            max_clients: options.rate_limit_max_clients,
+            key: options.rate_limit_key,
+            classes: options.rate_limit_class,
+        })));
+        let tls = if options.tls_certificate.is_empty() {
+            None
+        } else {
+            Some(tls::new_acceptor(&options.tls_certificate)?)
+        };
+        let state = Arc::new(Mutex::new(state));
+
+        run_health_check_interval(Arc::downgrade(&state), metrics.clone()).await;
+        run_rate_limit_expiry(
+            Arc::downgrade(&rate_limits),
+            Duration::from_secs(options.rate_limit_window),
+        );
+        Ok(Generation {
+            state,
+            rate_limits,
+            drain_timeout: Duration::from_secs(options.drain_timeout),
+            connection_limits: ConnectionLimits {
+                max_total: options.max_connections,
+                max_per_client: options.max_connections_per_client,
+            },
+            tls,
+            access_log,
+        })
+    }
+
+    /// Carries over what we have learned about upstreams that are in both this generation and
+    /// `previous` (whether they are alive, and whether they were drained or disabled through the
+    /// admin API), so that a reload doesn't send requests to upstreams we know to be dead. If the
+    /// access log hasn't changed, connections from both generations keep sharing one file, so
+    /// that reopening it on SIGUSR1 covers all of them.
+    async fn inherit(&mut self, previous: &Generation) {
+        if let (Some(log), Some(previous_log)) = (&self.access_log, &previous.access_log) {
+            if log.same_as(previous_log) {
+                self.access_log = Some(previous_log.clone());
             }
         }
+        let previous = previous.state.lock().await.upstream_addresses.clone();
+        let previous = previous.lock().await;
+        let current = self.state.lock().await.upstream_addresses.clone();
+        for upstream in current.lock().await.iter_mut() {
+            if let Some(old) = previous.iter().find(|old| old.address == upstream.address) {
+                upstream.state = old.state;
+                upstream.active_connections = old.active_connections.clone();
+                upstream.checks_passed = old.checks_passed;
+                upstream.checks_failed = old.checks_failed;
+                upstream.healthy = old.healthy;
+            }
+        }
+    }
+}
 
-        // filter upstream with active upstream
+/// The configuration new connections are handled with; replaced when the configuration is
+/// reloaded
+type CurrentGeneration = Arc<parking_lot::RwLock<Generation>>;
+
+impl ProxyState {
+    /// Picks one of the live upstreams in `pool` for a request, returning its address. Connecting
+    /// to it is left to the caller, so that it can use a pooled connection. Upstreams listed in
+    /// `exclude` (e.g. because the request already failed there), and those whose circuit breaker
+    /// is open, are skipped.
+    pub async fn select_upstream(
+        &mut self,
+        pool: &str,
+        key: &str,
+        exclude: &[String],
+    ) -> Option<(String, ConnectionGuard)> {
+        let mut upstreams = self.upstream_addresses.lock().await;
         let active_upstreams = upstreams
+            .iter_mut()
+            .enumerate()
+            .filter(|(_, upstream)| upstream.pool == pool)
+            .filter(|(_, upstream)| upstream.state == UpstreamState::Active)
+            .filter(|(_, upstream)| !exclude.contains(&upstream.address))
+            .filter_map(|(idx, upstream)| upstream.breaker.is_available().then_some(idx))
+            .collect::<Vec<usize>>();
+
+        // let the pool's strategy pick one of the active upstreams
+        let candidates = active_upstreams
             .iter()
-            .filter(|upstream| matches!(upstream.state, UpstreamState::Active))
-            .collect::<Vec<&Upstream>>();
+            .map(|idx| Candidate {
+                address: &upstreams[*idx].address,
+                weight: upstreams[*idx].weight,
+                active_connections: upstreams[*idx].active_connections.load(Ordering::SeqCst),
+            })
+            .collect::<Vec<Candidate>>();
+        let load_balancer = &mut self.pools.get_mut(pool)?.load_balancer;
+        let selected = active_upstreams[load_balancer.select(&candidates, key)?];
+        let upstream = &mut upstreams[selected];
+        upstream.breaker.start_request();
+        Some((
+            upstream.address.clone(),
+            ConnectionGuard::new(upstream.active_connections.clone()),
+        ))
+    }
+
+    /// Returns true if the client at `client_ip` is one of the --trusted-proxy addresses.
+    pub fn is_trusted_proxy(&self, client_ip: &str) -> bool {
+        match client_ip.parse::<IpAddr>() {
+            Ok(address) => self
+                .trusted_proxies
+                .iter()
+                .any(|range| range.contains(address)),
+            Err(_) => false,
+        }
+    }
 
-        // random select active upstream
-        let mut rng = rand::rngs::StdRng::from_entropy();
-        let upstream_idx = rng.gen_range(0, active_upstreams.len());
-        let upstream_ip = &active_upstreams[upstream_idx].address;
-        match TcpStream::connect(upstream_ip).await {
-            Ok(stream) => Some(stream),
-            Err(_err) => None,
+    /// Feeds the outcome of a request to the upstream's circuit breaker.
+    pub async fn record_outcome(&mut self, address: &str, success: bool) {
+        let mut upstreams = self.upstream_addresses.lock().await;
+        if let Some(upstream) = upstreams
+            .iter_mut()
+            .find(|upstream| upstream.address == address)
+        {
+            if success {
+                upstream.breaker.record_success();
+            } else {
+                upstream.breaker.record_failure();
+            }
         }
     }
 }
 
-async fn run_health_check_interval(shared_state: Arc<Mutex<ProxyState>>) {
+/// Runs active health checks until `shared_state` is dropped (i.e. until the configuration has
+/// been reloaded and the last connection using this one has closed).
+async fn run_health_check_interval(shared_state: Weak<Mutex<ProxyState>>, metrics: Arc<Metrics>) {
+    let interval = match shared_state.upgrade() {
+        Some(state) => state.lock().await.active_health_check_interval as u64,
+        None => return,
+    };
     tokio::spawn(async move {
-        let mut interval;
-        let active_health_check_path;
-        {
-            let state = shared_state.lock().await;
-            interval = time::interval(Duration::from_secs(
-                state.active_health_check_interval as u64,
-            ));
-            active_health_check_path = state.active_health_check_path.clone();
-        }
+        let mut interval = time::interval(Duration::from_secs(interval));
+        interval.tick().await; // wait for 0s - zero wait here
         loop {
-            interval.tick().await; // wait for 0s - zero wait here
             interval.tick().await; // wait for interval
-            let len;
-            {
-                let state = shared_state.lock().await;
-                len = state.upstream_addresses.lock().await.len();
-            }
+            let (upstreams, config) = match shared_state.upgrade() {
+                Some(state) => {
+                    let state = state.lock().await;
+                    (state.upstream_addresses.clone(), state.health_check.clone())
+                }
+                None => return,
+            };
+
+            // Only hold the upstream list long enough to see what to check, so that requests
+            // aren't held up while the checks run
+            let targets = upstreams
+                .lock()
+                .await
+                .iter()
+                .filter(|upstream| upstream.state != UpstreamState::Disabled)
+                .map(|upstream| (upstream.address.clone(), upstream.health_check.clone()))
+                .collect::<Vec<_>>();
             let mut tasks = Vec::new();
-            for i in 0..len {
-                let shared_upstream_vec;
-                {
-                    let state = shared_state.lock().await;
-                    shared_upstream_vec = state.upstream_addresses.clone()
-                };
-                let active_health_check_path = active_health_check_path.clone();
+            for (address, kind) in targets {
+                let config = config.clone();
                 tasks.push(task::spawn(async move {
-                    let mut upstream = shared_upstream_vec.lock().await;
-                    let request = http::Request::builder()
-                        .method(http::Method::GET)
-                        .uri(active_health_check_path)
-                        .header("host", &upstream[i].address)
-                        .body(Vec::new())
-                        .unwrap();
-
-                    let mut stream = match TcpStream::connect(&upstream[i].address).await {
-                        Ok(stream) => stream,
-                        Err(err) => {
-                            log::error!(
-                                "Failed to connect to upstream {}: {}",
-                                upstream[i].address,
-                                err
-                            );
-                            // upstream[i].state = UpstreamState::Dead;
-                            return;
-                        }
-                    };
-
-                    if let Err(_error) = request::write_to_stream(&request, &mut stream).await {
-                        // upstream[i].state = UpstreamState::Dead;
-                        return;
-                    };
-                    match response::read_from_stream(&mut stream, request.method()).await {
-                        Ok(response) => {
-                            if matches!(response.status(), http::StatusCode::OK) {
-                                println!("receive response");
-                                upstream[i].state = UpstreamState::Active;
-                            } else {
-                                upstream[i].state = UpstreamState::Dead;
-                            }
-                        }
-                        Err(_) => upstream[i].state = UpstreamState::Dead,
-                    }
+                    let healthy = health_check::check(&address, &kind, &config).await;
+                    (address, healthy)
                 }));
             }
             for task in tasks {
-                task.await.unwrap();
+                let (address, healthy) = task.await.unwrap();
+                if let Some(upstream) = upstreams
+                    .lock()
+                    .await
+                    .iter_mut()
+                    .find(|upstream| upstream.address == address)
+                {
+                    upstream.record_check(healthy, &config, &metrics);
+                }
             }
         }
     });
@@ -195,16 +1031,23 @@ async fn main() {
     // Initialize the logging library. You can print log messages using the `log` macros:
     // https://docs.rs/log/0.4.8/log/ You are welcome to continue using print! statements; this
     // just looks a little prettier.
-    if let Err(_) = std::env::var("RUST_LOG") {
+    if std::env::var("RUST_LOG").is_err() {
         std::env::set_var("RUST_LOG", "debug");
     }
     pretty_env_logger::init();
 
-    // Parse the command line arguments passed to this program
-    let options = CmdOptions::parse();
-    if options.upstream.len() < 1 {
-        log::error!("At least one upstream server must be specified using the --upstream option.");
-        std::process::exit(1);
+    // Parse the command line arguments passed to this program, and the config file if it names
+    // one
+    let args = std::env::args().collect::<Vec<String>>();
+    let mut options = CmdOptions::parse();
+    if options.config.is_some() {
+        options = match config::load_options(&args) {
+            Ok(options) => options,
+            Err(err) => {
+                log::error!("{}", err);
+                std::process::exit(1);
+            }
+        };
     }
 
     // Start listening for connections
@@ -216,206 +1059,1056 @@ async fn main() {
         }
     };
     log::info!("Listening for requests on {}", options.bind);
+    let admin_listener = match &options.admin_bind {
+        Some(admin_bind) => match TcpListener::bind(admin_bind).await {
+            Ok(listener) => {
+                log::info!("Serving the admin API on {}", admin_bind);
+                Some(listener)
+            }
+            Err(err) => {
+                log::error!("Could not bind to {}: {}", admin_bind, err);
+                std::process::exit(1);
+            }
+        },
+        None => None,
+    };
+    let listeners = (options.bind.clone(), options.admin_bind.clone());
 
-    // Handle incoming connections
-    let mut upstream_state = Vec::new();
-    for it in options.upstream.iter() {
-        upstream_state.push(Upstream {
-            address: it.clone(),
-            state: UpstreamState::Active,
-        });
+    let metrics = Arc::new(Metrics::new());
+    let open_connections = Arc::new(OpenConnections::default());
+    let generation = match Generation::new(options, &metrics).await {
+        Ok(generation) => generation,
+        Err(err) => {
+            log::error!("{}", err);
+            std::process::exit(1);
+        }
+    };
+    let current: CurrentGeneration = Arc::new(parking_lot::RwLock::new(generation));
+    if let Some(admin_listener) = admin_listener {
+        admin::run(
+            admin_listener,
+            Arc::new(AdminContext {
+                current: current.clone(),
+                metrics: metrics.clone(),
+            }),
+        );
+    }
+    let listen_for = |kind: SignalKind, name: &str| match signal(kind) {
+        Ok(signals) => signals,
+        Err(err) => {
+            log::error!("Could not listen for {}: {}", name, err);
+            std::process::exit(1);
+        }
+    };
+    let mut hangups = listen_for(SignalKind::hangup(), "SIGHUP");
+    let mut terminates = listen_for(SignalKind::terminate(), "SIGTERM");
+    let mut interrupts = listen_for(SignalKind::interrupt(), "SIGINT");
+    let mut user_signals = listen_for(SignalKind::user_defined1(), "SIGUSR1");
+    let (shutdown_sender, shutdown) = watch::channel(false);
+    loop {
+        tokio::select! {
+            accepted = listener.accept() => match accepted {
+                Ok((stream, sock_addr)) => {
+                    let generation = current.read().clone();
+                    dispatch_connection_handle(
+                        stream,
+                        sock_addr,
+                        generation,
+                        &open_connections,
+                        metrics.clone(),
+                        shutdown.clone(),
+                    )
+                    .await;
+                }
+                Err(err) => log::error!("Could not accept connection: {}", err),
+            },
+            _ = hangups.recv() => reload(&args, &listeners, &current, &metrics).await,
+            _ = user_signals.recv() => reopen_access_log(&current),
+            _ = terminates.recv() => break,
+            _ = interrupts.recv() => break,
+        }
+    }
+
+    // Stop accepting connections, and let the open ones finish the requests they are working on
+    drop(listener);
+    log::info!("Shutting down, waiting for open connections to finish");
+    let _ = shutdown_sender.broadcast(true);
+    let deadline = Instant::now() + current.read().drain_timeout;
+    loop {
+        let open = metrics.active_client_connections.load(Ordering::SeqCst);
+        if open == 0 {
+            log::info!("All connections closed, exiting");
+            return;
+        }
+        if Instant::now() >= deadline {
+            log::warn!(
+                "{} connections still open after the drain timeout, exiting anyway",
+                open
+            );
+            return;
+        }
+        time::delay_for(Duration::from_millis(100)).await;
     }
+}
+
+/// Tells connections that we are shutting down; true once they should finish up
+type ShutdownSignal = watch::Receiver<bool>;
+
+/// Waits until shutdown has started.
+async fn shutting_down(shutdown: &mut ShutdownSignal) {
+    while let Some(false) = shutdown.recv().await {}
+}
 
-    let state = ProxyState {
-        upstream_addresses: Arc::new(Mutex::new(upstream_state)),
-        active_health_check_interval: options.active_health_check_interval,
-        active_health_check_path: options.active_health_check_path,
-        max_requests_per_minute: options.max_requests_per_minute,
+/// Re-reads the configuration, and switches new connections over to it. If the new configuration
+/// is invalid, we keep using the old one.
+async fn reload(
+    args: &[String],
+    listeners: &(String, Option<String>),
+    current: &CurrentGeneration,
+    metrics: &Arc<Metrics>,
+) {
+    if !args
+        .iter()
+        .any(|arg| arg == "--config" || arg.starts_with("--config="))
+    {
+        log::warn!("Received SIGHUP, but there is no --config file to reload");
+        return;
+    }
+    log::info!("Received SIGHUP, reloading configuration");
+    let options = match config::load_options(args) {
+        Ok(options) => options,
+        Err(err) => {
+            log::error!("Keeping the old configuration: {}", err);
+            return;
+        }
+    };
+    if (&options.bind, &options.admin_bind) != (&listeners.0, &listeners.1) {
+        log::warn!("Changing the listening addresses requires a restart; keeping the old ones");
+    }
+    let mut generation = match Generation::new(options, metrics).await {
+        Ok(generation) => generation,
+        Err(err) => {
+            log::error!("Keeping the old configuration: {}", err);
+            return;
+        }
     };
+    let previous = current.read().clone();
+    generation.inherit(&previous).await;
+    *current.write() = generation;
+    log::info!("Configuration reloaded");
+}
+
+/// Reopens the access log (on SIGUSR1), e.g. after logrotate has moved it aside.
+fn reopen_access_log(current: &CurrentGeneration) {
+    match &current.read().access_log {
+        Some(access_log) => access_log.reopen(),
+        None => log::warn!("Received SIGUSR1, but there is no --access-log to reopen"),
+    }
+}
 
-    let shared_rate_limit: Arc<Mutex<FixWindowRateLimit>> = Arc::new(Mutex::new(
-        FixWindowRateLimit::new(state.max_requests_per_minute.clone()),
-    ));
-    let share_state: Arc<Mutex<ProxyState>> = Arc::new(Mutex::new(state));
-    // let num_threads = num_cpus::get();
-    // let thread_pool = ThreadPool::new(num_threads);
-
-    // for stream in listener.incoming() {
-    //     if let Ok(stream) = stream {
-    //         // Handle the connection!
-    //         // handle_connection(stream, &state);
-    //         dispatch_connection_handle(&thread_pool, stream, share_state.clone());
-    //     }
-    // }
-    run_health_check_interval(share_state.clone()).await;
+/// Picks an upstream in `pool` for a request (other than the ones in `failed_upstreams`) and
+/// checks out a connection to it. If we can't connect to the chosen upstream, the failure is
+/// counted against its circuit breaker, the upstream is added to `failed_upstreams`, and we move
+/// on to whichever other upstream is picked next. Moving on counts as a retry, so it is limited by
+/// max_retries and the retry budget (though it is fine for any request method, since nothing has
+/// been sent yet). `error_status` is updated with the status the client should get if we give up
+/// (504 if the last upstream timed out, 503 if it was too busy, 502 otherwise). If
+/// `new_connection` is set, idle pooled connections are passed over.
+async fn connect_to_upstream(
+    share_state: &Mutex<ProxyState>,
+    pool: &str,
+    key: &str,
+    failed_upstreams: &mut Vec<String>,
+    new_connection: bool,
+    error_status: &mut http::StatusCode,
+) -> Option<(PooledConnection, ConnectionGuard)> {
     loop {
-        match listener.accept().await {
-            Ok((stream, _sock_addr)) => {
-                // task::spawn(async );
-                dispatch_connection_handle(stream, share_state.clone(), shared_rate_limit.clone())
-                    .await;
+        let (address, guard, connections) = {
+            let mut state = share_state.lock().await;
+            let (address, guard) = state.select_upstream(pool, key, failed_upstreams).await?;
+            (address, guard, state.connection_pool.clone())
+        };
+        let connection = match new_connection {
+            true => connections.get_new(&address).await,
+            false => connections.get(&address).await,
+        };
+        let mut state = match connection {
+            Ok(connection) => return Some((connection, guard)),
+            Err(err @ pool::Error::QueueFull) | Err(err @ pool::Error::QueueTimeout) => {
+                // The upstream is only busy, not failing, so its circuit breaker is left alone
+                log::warn!("Upstream {} is at capacity: {}", address, err);
+                *error_status = http::StatusCode::SERVICE_UNAVAILABLE;
+                share_state.lock().await
+            }
+            Err(pool::Error::Connect(err)) => {
+                log::error!("Failed to connect to upstream {}: {}", address, err);
+                *error_status = match err.kind() {
+                    std::io::ErrorKind::TimedOut => http::StatusCode::GATEWAY_TIMEOUT,
+                    _ => http::StatusCode::BAD_GATEWAY,
+                };
+                let mut state = share_state.lock().await;
+                state.record_outcome(&address, false).await;
+                state
             }
-            Err(e) => {
-                println!("couldn't get client: {:?}", e);
+        };
+        failed_upstreams.push(address);
+        if failed_upstreams.len() > state.retry_policy.max_retries
+            || !state.retry_policy.budget.try_spend()
+        {
+            return None;
+        }
+    }
+}
+
+/// Ways that forwarding a request to an upstream can fail
+enum ForwardError {
+    /// The upstream failed, or took too long, before it started responding. The client hasn't
+    /// been sent a final response yet, so it gets one with this status (unless we retry).
+    Upstream(http::StatusCode),
+    /// A connection reused from the pool was closed before the upstream sent anything back. The
+    /// upstream most likely timed it out while idle just as we picked it up, so this says nothing
+    /// about its health, and the request can be sent again on a new connection.
+    ConnectionClosed,
+    /// The client sent a malformed request body
+    BadRequest,
+    /// The client stalled while sending the request body
+    ClientTimeout,
+    /// The client hung up or we couldn't read from it
+    ClientGone,
+}
+
+/// Sends a request to the upstream, followed by its body (the rest of which is read from the
+/// client as it arrives), and waits up to `per_try_timeout` for the head of the upstream's final
+/// response. Interim 1xx responses (e.g. 100 Continue) are passed on to the client along the way.
+async fn forward_request(
+    client_conn: &mut ClientStream,
+    request: &http::Request<Vec<u8>>,
+    request_framing: Framing,
+    upstream: &mut PooledConnection,
+    body_timeout: Duration,
+    per_try_timeout: Duration,
+    metrics: &Metrics,
+) -> Result<(http::Response<Vec<u8>>, Framing), ForwardError> {
+    let upstream_conn = &mut upstream.stream;
+    let upstream_failed = match upstream.reused {
+        true => ForwardError::ConnectionClosed,
+        false => ForwardError::Upstream(http::StatusCode::BAD_GATEWAY),
+    };
+    if let Err(error) = request::write_head(request, upstream_conn).await {
+        log::error!(
+            "Failed to send request to upstream {}: {}",
+            upstream.address,
+            error
+        );
+        return Err(upstream_failed);
+    }
+    let mut client_body = body::ReadTimeout::new(client_conn, body_timeout);
+    match body::copy(
+        &mut client_body,
+        request.body(),
+        upstream_conn,
+        request_framing,
+    )
+    .await
+    {
+        Ok(bytes) => {
+            log::debug!("Forwarded request to server");
+            metrics.add_request_body_bytes(&upstream.address, bytes);
+        }
+        Err(body::Error::Write(error)) => {
+            log::error!(
+                "Failed to send request to upstream {}: {}",
+                upstream.address,
+                error
+            );
+            return Err(upstream_failed);
+        }
+        Err(body::Error::Malformed) => {
+            log::debug!("Client sent a malformed request body");
+            return Err(ForwardError::BadRequest);
+        }
+        Err(body::Error::TimedOut) => {
+            log::info!("Client stalled while sending a request body");
+            return Err(ForwardError::ClientTimeout);
+        }
+        Err(error) => {
+            log::info!("Error reading request body from client: {}", error);
+            return Err(ForwardError::ClientGone);
+        }
+    }
+
+    // A 101 to a request asking to switch protocols is as final as a response gets
+    let upgrading = wants_upgrade(request.headers());
+    let mut responded = false;
+    let read_final_response = async {
+        loop {
+            match response::read_head(upstream_conn, request.method()).await {
+                Ok((response, _))
+                    if response.status().is_informational()
+                        && !(upgrading
+                            && response.status() == http::StatusCode::SWITCHING_PROTOCOLS) =>
+                {
+                    responded = true;
+                    send_response(client_conn, &response).await;
+                }
+                result => return result,
             }
         }
+    };
+    match time::timeout(per_try_timeout, read_final_response).await {
+        Ok(Ok(head)) => Ok(head),
+        Ok(Err(response::Error::IncompleteResponse(0))) if !responded => {
+            log::error!("Upstream {} hung up without responding", upstream.address);
+            Err(upstream_failed)
+        }
+        Ok(Err(error)) => {
+            log::error!(
+                "Error reading response from upstream {}: {}",
+                upstream.address,
+                error
+            );
+            Err(ForwardError::Upstream(http::StatusCode::BAD_GATEWAY))
+        }
+        Err(_elapsed) => {
+            log::error!(
+                "Upstream {} didn't respond within {:?}",
+                upstream.address,
+                per_try_timeout
+            );
+            Err(ForwardError::Upstream(http::StatusCode::GATEWAY_TIMEOUT))
+        }
     }
 }
 
-// async fn connect_to_upstream(state: &ProxyState) -> Result<TcpStream, std::io::Error> {
-//     let mut rng = rand::rngs::StdRng::from_entropy();
-//     let upstream_idx = rng.gen_range(0, state.upstream_addresses.len());
-//     let upstream_ip = &state.upstream_addresses[upstream_idx].address;
-//     TcpStream::connect(upstream_ip).await.or_else(|err| {
-//         log::error!("Failed to connect to upstream {}: {}", upstream_ip, err);
-//         Err(err)
-//     })
-//     // TODO: implement failover (milestone 3)
-// }
-
-async fn send_response(client_conn: &mut TcpStream, response: &http::Response<Vec<u8>>) {
-    let client_ip = client_conn.peer_addr().unwrap().ip().to_string();
+/// Returns true if the message's Connection header asks for the connection to be closed after it.
+fn wants_close(headers: &http::HeaderMap) -> bool {
+    has_connection_option(headers, "close")
+}
+
+/// Returns true if the request asks to switch the connection over to another protocol (e.g.
+/// WebSocket).
+fn wants_upgrade(headers: &http::HeaderMap) -> bool {
+    has_connection_option(headers, "upgrade") && headers.contains_key(http::header::UPGRADE)
+}
+
+fn has_connection_option(headers: &http::HeaderMap, option: &str) -> bool {
+    headers
+        .get_all("connection")
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .any(|value| value.trim().eq_ignore_ascii_case(option))
+}
+
+/// Sends a response we made up ourselves (rather than one from an upstream), counting it in the
+/// metrics and the access log.
+async fn send_error_response(
+    client_conn: &mut ClientStream,
+    response: &http::Response<Vec<u8>>,
+    entry: &mut access_log::Entry,
+    metrics: &Metrics,
+) {
+    metrics.record_response(None, response.status());
+    entry.set_status(response.status());
+    entry.add_bytes(response.body().len() as u64);
+    send_response(client_conn, response).await;
+}
+
+/// Tells a client that took too long sending a request that we've given up on it. The rest of the
+/// request may still be on its way, so the connection is closed afterwards.
+async fn send_request_timeout(
+    client_conn: &mut ClientStream,
+    entry: &mut access_log::Entry,
+    metrics: &Metrics,
+) {
+    let mut response = response::make_http_error(http::StatusCode::REQUEST_TIMEOUT);
+    response.headers_mut().insert(
+        http::header::CONNECTION,
+        http::HeaderValue::from_static("close"),
+    );
+    send_error_response(client_conn, &response, entry, metrics).await;
+}
+
+async fn send_response(client_conn: &mut ClientStream, response: &http::Response<Vec<u8>>) {
+    let client_ip = client_conn.peer_addr().ip().to_string();
     log::info!(
         "{} <- {}",
         client_ip,
-        response::format_response_line(&response)
+        response::format_response_line(response)
     );
-    if let Err(error) = response::write_to_stream(&response, client_conn).await {
+    if let Err(error) = response::write_to_stream(response, client_conn).await {
         log::warn!("Failed to send response to client: {}", error);
-        return;
     }
 }
 
-// fn dispatch_connection_handle(
-//     thread_pool: &ThreadPool,
-//     client_conn: TcpStream,
-//     share_state: Arc<Mutex<ProxyState>>,
-// ) {
-//     thread_pool.execute(move || {
-//         let state;
-//         {
-//             state = share_state.lock().unwrap();
-//         }
-//         handle_connection(client_conn, &state);
-//     })
-// }
+/// Periodically forgets about rate-limited clients that have gone quiet, so that the rate limiters
+/// only hold on to clients that are actually sending requests.
+fn run_rate_limit_expiry(rate_limits: Weak<Mutex<RateLimits>>, window: Duration) {
+    tokio::spawn(async move {
+        let mut interval = time::interval(window);
+        // The first tick completes immediately
+        interval.tick().await;
+        loop {
+            interval.tick().await;
+            match rate_limits.upgrade() {
+                Some(rate_limits) => rate_limits.lock().await.remove_idle(),
+                None => return,
+            }
+        }
+    });
+}
 
 async fn dispatch_connection_handle(
-    client_conn: TcpStream,
-    share_state: Arc<Mutex<ProxyState>>,
-    rate_limit: Arc<Mutex<FixWindowRateLimit>>,
+    stream: TcpStream,
+    sock_addr: SocketAddr,
+    generation: Generation,
+    open_connections: &Arc<OpenConnections>,
+    metrics: Arc<Metrics>,
+    shutdown: ShutdownSignal,
 ) {
-    tokio::spawn(async move { handle_connection(client_conn, share_state, rate_limit).await })
+    let slot = match open_connections.try_open(sock_addr.ip(), generation.connection_limits) {
+        Ok(slot) => slot,
+        Err(reason) => {
+            let rejecting = match open_connections.try_reject() {
+                Some(rejecting) => rejecting,
+                None => {
+                    // Logging every one of these would only add to the load
+                    log::debug!(
+                        "Closing connection from {}: {} (and too many other clients are being \
+                        turned away to answer it)",
+                        sock_addr.ip(),
+                        reason
+                    );
+                    return;
+                }
+            };
+            log::warn!(
+                "Turning away connection from {}: {}",
+                sock_addr.ip(),
+                reason
+            );
+            let rejection = reject_connection(
+                stream,
+                sock_addr,
+                generation.tls,
+                generation.access_log,
+                metrics,
+            );
+            tokio::spawn(async move {
+                let _rejecting = rejecting;
+                let _ = time::timeout(limits::REJECTION_TIMEOUT, rejection).await;
+            });
+            return;
+        }
+    };
+
+    // Don't wait for the connection to finish; otherwise a single keep-alive client would stop us
+    // from accepting anyone else (and every upstream would always have at most one connection).
+    // The TLS handshake happens here too, so that a slow client can't hold up the others.
+    tokio::spawn(async move {
+        let _slot = slot;
+        let client_conn = match &generation.tls {
+            Some(acceptor) => {
+                // The handshake gets as long as the request headers would, so that a client can't
+                // hold on to its connection slot by never finishing it
+                let timeout = generation.state.lock().await.client_header_timeout;
+                match time::timeout(timeout, ClientStream::accept(stream, sock_addr, acceptor))
+                    .await
+                {
+                    Ok(Ok(client_conn)) => client_conn,
+                    Ok(Err(err)) => {
+                        log::info!("TLS handshake with {} failed: {}", sock_addr.ip(), err);
+                        return;
+                    }
+                    Err(_) => {
+                        log::info!(
+                            "{} didn't finish its TLS handshake within {:?}",
+                            sock_addr.ip(),
+                            timeout
+                        );
+                        return;
+                    }
+                }
+            }
+            None => ClientStream::plain(stream, sock_addr),
+        };
+        handle_connection(
+            client_conn,
+            generation.state,
+            generation.rate_limits,
+            generation.access_log,
+            metrics,
+            shutdown,
+        )
         .await
-        .unwrap();
+    });
+}
+
+/// Sends a 503 to a client we don't have room for, and hangs up. Its first request is read before
+/// responding, so that the client gets to see the response rather than the connection being reset
+/// under a request it is still sending.
+async fn reject_connection(
+    stream: TcpStream,
+    sock_addr: SocketAddr,
+    tls: Option<TlsAcceptor>,
+    access_log: Option<Arc<AccessLog>>,
+    metrics: Arc<Metrics>,
+) {
+    let mut client_conn = match &tls {
+        Some(acceptor) => match ClientStream::accept(stream, sock_addr, acceptor).await {
+            Ok(client_conn) => client_conn,
+            Err(_) => return,
+        },
+        None => ClientStream::plain(stream, sock_addr),
+    };
+    let mut entry = access_log::Entry::new(access_log, &sock_addr.ip().to_string());
+    if let Ok((request, _)) = request::read_head(&mut client_conn).await {
+        entry.set_request(&request);
+    }
+    let mut response = response::make_http_error(http::StatusCode::SERVICE_UNAVAILABLE);
+    response.headers_mut().insert(
+        http::header::CONNECTION,
+        http::HeaderValue::from_static("close"),
+    );
+    send_error_response(&mut client_conn, &response, &mut entry, &metrics).await;
 }
+
 async fn handle_connection(
-    mut client_conn: TcpStream,
+    mut client_conn: ClientStream,
     share_state: Arc<Mutex<ProxyState>>,
-    share_rate_limit: Arc<Mutex<FixWindowRateLimit>>,
+    share_rate_limit: SharedRateLimits,
+    access_log: Option<Arc<AccessLog>>,
+    metrics: Arc<Metrics>,
+    mut shutdown: ShutdownSignal,
 ) {
-    let client_ip = client_conn.peer_addr().unwrap().ip().to_string();
+    let client_ip = client_conn.peer_addr().ip().to_string();
     log::info!("Connection received from {}", client_ip);
+    let _client_guard = ConnectionGuard::new(metrics.active_client_connections.clone());
 
-    // Open a connection to a random destination server
-    // let mut upstream_conn = match connect_to_upstream(state).await {
-    //     Ok(stream) => stream,
-    //     Err(_error) => {
-    //         let response = response::make_http_error(http::StatusCode::BAD_GATEWAY);
-    //         send_response(&mut client_conn, &response).await;
-    //         return;
-    //     }
-    // };
-    let mut state = share_state.lock().await;
-    let mut upstream_conn = match state.select_upstream().await {
-        Some(stream) => stream,
-        None => {
-            let response = response::make_http_error(http::StatusCode::BAD_GATEWAY);
-            send_response(&mut client_conn, &response).await;
-            drop(state);
-            return;
-        }
+    let (
+        trusted,
+        rate_limit_headers,
+        upgrade_idle_timeout,
+        header_timeout,
+        body_timeout,
+        idle_timeout,
+        response_cache,
+    ) = {
+        let state = share_state.lock().await;
+        (
+            state.is_trusted_proxy(&client_ip),
+            state.rate_limit_headers,
+            state.upgrade_idle_timeout,
+            state.client_header_timeout,
+            state.client_body_timeout,
+            state.client_idle_timeout,
+            state.response_cache.clone(),
+        )
     };
-    drop(state);
 
-    let upstream_ip = client_conn.peer_addr().unwrap().ip().to_string();
-    let mut rate_limit;
-    {
-        rate_limit = share_rate_limit.lock().await;
-    }
-    if rate_limit.rate_limit(upstream_ip.as_str()).await {
-        let response = response::make_http_error(http::StatusCode::TOO_MANY_REQUESTS);
-        send_response(&mut client_conn, &response).await;
+    // Clients speak HTTP/2 either because they agreed to with ALPN, or because they know we do
+    // and start right away with its preface
+    let http2 = match client_conn.alpn_protocol() {
+        Some(protocol) => protocol == b"h2",
+        None if client_conn.is_tls() => false,
+        None => tokio::select! {
+            http2 = client_conn.starts_with(http2::PREFACE) => http2,
+            _ = time::delay_for(idle_timeout) => {
+                log::debug!("Closing client connection that has been idle for {:?}", idle_timeout);
+                return;
+            }
+            _ = shutting_down(&mut shutdown) => {
+                log::debug!("Closing idle client connection to shut down");
+                return;
+            }
+        },
+    };
+    if http2 {
+        http2::serve_connection(
+            client_conn,
+            share_state,
+            share_rate_limit,
+            access_log,
+            metrics.clone(),
+            shutdown,
+        )
+        .await;
         return;
     }
+
     // The cliet may now send us one or more requests. Keep trying to read requests until the
     // client hangs up or we get an error.
     loop {
-        // Read a request from the client
-        let mut request = match request::read_from_stream(&mut client_conn).await {
-            Ok(request) => request,
+        // Between requests the connection is idle, and can be closed as soon as we start shutting
+        // down. Once the client has started sending a request, we see it through.
+        tokio::select! {
+            _ = client_conn.wait_for_data() => {}
+            _ = time::delay_for(idle_timeout) => {
+                log::debug!("Closing client connection that has been idle for {:?}", idle_timeout);
+                return;
+            }
+            _ = shutting_down(&mut shutdown) => {
+                log::debug!("Closing idle client connection to shut down");
+                return;
+            }
+        }
+        let mut entry = access_log::Entry::new(access_log.clone(), &client_ip);
+
+        // Read a request from the client. A client that trickles its headers in (whether it's
+        // just slow or trying to tie up connections) only gets so long.
+        let head = match time::timeout(header_timeout, request::read_head(&mut client_conn)).await {
+            Ok(head) => head,
+            Err(_elapsed) => {
+                log::info!("{} took too long to send request headers", client_ip);
+                send_request_timeout(&mut client_conn, &mut entry, &metrics).await;
+                return;
+            }
+        };
+        let (mut request, request_framing) = match head {
+            Ok(head) => head,
             // Handle case where client closed connection and is no longer sending requests
             Err(request::Error::IncompleteRequest(0)) => {
                 log::debug!("Client finished sending requests. Shutting down connection");
                 return;
             }
             // Handle I/O error in reading from the client
-            Err(request::Error::ConnectionError(io_err)) => {
+            Err(request::Error::Connection(io_err)) => {
                 log::info!("Error reading request from client stream: {}", io_err);
                 return;
             }
             Err(error) => {
-                log::debug!("Error parsing request: {:?}", error);
+                log::debug!("Error parsing request: {}", error);
                 let response = response::make_http_error(match error {
                     request::Error::IncompleteRequest(_)
                     | request::Error::MalformedRequest(_)
                     | request::Error::InvalidContentLength
-                    | request::Error::ContentLengthMismatch => http::StatusCode::BAD_REQUEST,
-                    request::Error::RequestBodyTooLarge => http::StatusCode::PAYLOAD_TOO_LARGE,
-                    request::Error::ConnectionError(_) => http::StatusCode::SERVICE_UNAVAILABLE,
+                    | request::Error::InvalidTransferEncoding => http::StatusCode::BAD_REQUEST,
+                    request::Error::Connection(_) => http::StatusCode::SERVICE_UNAVAILABLE,
                 });
-                send_response(&mut client_conn, &response).await;
+                send_error_response(&mut client_conn, &response, &mut entry, &metrics).await;
                 continue;
             }
         };
-        log::info!(
-            "{} -> {}: {}",
-            client_ip,
-            upstream_ip,
-            request::format_request_line(&request)
+        entry.set_request(&request);
+        let started = Instant::now();
+
+        // Every request counts against the client's rate limit, not just the first one on each
+        // connection. We read the request before turning the client away, so that it doesn't see
+        // the connection being reset under a request it is still sending.
+        let rate_limit = share_rate_limit
+            .lock()
+            .await
+            .check(&client_ip, trusted, &request);
+        entry.set_rate_limit(rate_limit.as_ref());
+        if let Some(decision) = rate_limit.as_ref().filter(|decision| decision.limited) {
+            metrics.record_rate_limited();
+            let mut response = response::make_http_error(http::StatusCode::TOO_MANY_REQUESTS);
+            decision.add_headers(response.headers_mut());
+            if request_framing != Framing::Empty {
+                // We haven't read the body, so we can't tell where the next request would start
+                response.headers_mut().insert(
+                    http::header::CONNECTION,
+                    http::HeaderValue::from_static("close"),
+                );
+                send_error_response(&mut client_conn, &response, &mut entry, &metrics).await;
+                return;
+            }
+            send_error_response(&mut client_conn, &response, &mut entry, &metrics).await;
+            continue;
+        }
+
+        // Every request is routed and dispatched on its own, so requests on the same client
+        // connection may go to different pools and upstreams
+        let (pool, key, retryable, max_retries, per_try_timeout, header_rules) = {
+            let mut state = share_state.lock().await;
+            state.retry_policy.budget.record_request();
+            let pool = routing::route(&state.routes, &request).to_string();
+            let header_rules = state.pools[&pool].header_rules.clone();
+            (
+                pool,
+                state.hash_key.extract(&client_ip, &request),
+                state.retry_policy.methods.contains(request.method()),
+                state.retry_policy.max_retries,
+                state.retry_policy.per_try_timeout,
+                header_rules,
+            )
+        };
+        let variables = headers::Variables::new(
+            &request,
+            &client_ip,
+            client_conn.is_tls(),
+            request.version(),
         );
+        headers::add_forwarding_headers(&mut request, &variables, trusted);
+        headers::apply_rules(&header_rules.request, request.headers_mut(), &variables);
+
+        // Fresh responses from the cache are sent right away. A stale one might only need the
+        // upstream to tell us that it is still good.
+        let cacheable = request_framing == Framing::Empty && cache::is_cacheable(&request);
+        let stale = match response_cache.as_ref().filter(|_| cacheable) {
+            Some(cache) => match cache.lookup(&pool, &request) {
+                Lookup::Hit(mut response) => {
+                    metrics.record_cache_lookup("hit");
+                    headers::apply_rules(
+                        &header_rules.response,
+                        response.headers_mut(),
+                        &variables,
+                    );
+                    if let (Some(decision), true) = (&rate_limit, rate_limit_headers) {
+                        decision.add_headers(response.headers_mut());
+                    }
+                    let closing = *shutdown.borrow();
+                    if closing {
+                        response.headers_mut().insert(
+                            http::header::CONNECTION,
+                            http::HeaderValue::from_static("close"),
+                        );
+                    }
+                    metrics.record_response(Some(metrics::FROM_CACHE), response.status());
+                    entry.set_status(response.status());
+                    entry.add_bytes(response.body().len() as u64);
+                    send_response(&mut client_conn, &response).await;
+                    metrics.record_request_duration(started.elapsed());
+                    if closing || wants_close(request.headers()) {
+                        return;
+                    }
+                    continue;
+                }
+                Lookup::Stale(stored) => {
+                    stored.add_condition(&mut request);
+                    Some(stored)
+                }
+                Lookup::Miss => {
+                    metrics.record_cache_lookup("miss");
+                    None
+                }
+            },
+            None => None,
+        };
 
-        // Add X-Forwarded-For header so that the upstream server knows the client's IP address.
-        // (We're the ones connecting directly to the upstream server, so without this header, the
-        // upstream server will only know our IP, not the client's.)
-        request::extend_header_value(&mut request, "x-forwarded-for", &client_ip);
+        // A request can only be sent again (to another upstream if the first one fails and it is
+        // idempotent, or on a new connection if a pooled one turns out to be closed) if we still
+        // have its body. Small bodies are read in full up front for that reason; anything else is
+        // streamed through and only gets one try.
+        let replayable = match request_framing {
+            Framing::Empty => true,
+            Framing::ContentLength(len) => len <= retry::MAX_RETRYABLE_BODY_SIZE,
+            Framing::Chunked | Framing::UntilClose => false,
+        };
+        let retryable = retryable && replayable;
+        if let (true, Framing::ContentLength(len)) = (replayable, request_framing) {
+            let mut client_body = body::ReadTimeout::new(&mut client_conn, body_timeout);
+            match body::read_to_vec(&mut client_body, request.body(), len).await {
+                Ok(body) => *request.body_mut() = body,
+                Err(body::Error::Malformed) => {
+                    log::debug!("Client sent a malformed request body");
+                    let response = response::make_http_error(http::StatusCode::BAD_REQUEST);
+                    send_error_response(&mut client_conn, &response, &mut entry, &metrics).await;
+                    return;
+                }
+                Err(body::Error::TimedOut) => {
+                    log::info!("{} stalled while sending a request body", client_ip);
+                    send_request_timeout(&mut client_conn, &mut entry, &metrics).await;
+                    return;
+                }
+                Err(error) => {
+                    log::info!("Error reading request body from client: {}", error);
+                    return;
+                }
+            }
+        }
 
-        // Forward the request to the server
-        if let Err(error) = request::write_to_stream(&request, &mut upstream_conn).await {
-            log::error!(
-                "Failed to send request to upstream {}: {}",
-                upstream_ip,
-                error
+        let mut failed_upstreams = Vec::new();
+        let mut error_status = http::StatusCode::BAD_GATEWAY;
+        let mut new_connection = false;
+        let (mut upstream, _upstream_guard, mut response, mut response_framing, server_error) = loop {
+            let (mut upstream, upstream_guard) = match connect_to_upstream(
+                &share_state,
+                &pool,
+                &key,
+                &mut failed_upstreams,
+                new_connection,
+                &mut error_status,
+            )
+            .await
+            {
+                Some(upstream) => upstream,
+                None => {
+                    let response = response::make_http_error(error_status);
+                    send_error_response(&mut client_conn, &response, &mut entry, &metrics).await;
+                    return;
+                }
+            };
+            log::info!(
+                "{} -> {}: {}",
+                client_ip,
+                upstream.address,
+                request::format_request_line(&request)
+            );
+            let forward_started = Instant::now();
+            let result = forward_request(
+                &mut client_conn,
+                &request,
+                request_framing,
+                &mut upstream,
+                body_timeout,
+                per_try_timeout,
+                &metrics,
+            )
+            .await;
+            entry.set_upstream(&upstream.address, forward_started.elapsed());
+            match result {
+                Ok((response, response_framing)) => {
+                    metrics.record_upstream_duration(&upstream.address, forward_started.elapsed());
+                    // A 5xx still goes to the client, but counts as a failure of the upstream. Any
+                    // other response only counts as a success once its body has arrived intact.
+                    let server_error = response.status().is_server_error();
+                    if server_error {
+                        share_state
+                            .lock()
+                            .await
+                            .record_outcome(&upstream.address, false)
+                            .await;
+                    }
+                    break (
+                        upstream,
+                        upstream_guard,
+                        response,
+                        response_framing,
+                        server_error,
+                    );
+                }
+                Err(ForwardError::Upstream(status)) => {
+                    let mut state = share_state.lock().await;
+                    state.record_outcome(&upstream.address, false).await;
+                    failed_upstreams.push(upstream.address.clone());
+                    if !retryable
+                        || failed_upstreams.len() > max_retries
+                        || !state.retry_policy.budget.try_spend()
+                    {
+                        drop(state);
+                        let response = response::make_http_error(status);
+                        send_error_response(&mut client_conn, &response, &mut entry, &metrics)
+                            .await;
+                        return;
+                    }
+                    log::info!(
+                        "Retrying {} on another upstream",
+                        request::format_request_line(&request)
+                    );
+                    error_status = status;
+                }
+                Err(ForwardError::ConnectionClosed) if replayable => {
+                    log::info!(
+                        "Sending {} again on a new connection",
+                        request::format_request_line(&request)
+                    );
+                    new_connection = true;
+                }
+                Err(ForwardError::ConnectionClosed) => {
+                    let response = response::make_http_error(http::StatusCode::BAD_GATEWAY);
+                    send_error_response(&mut client_conn, &response, &mut entry, &metrics).await;
+                    return;
+                }
+                Err(ForwardError::BadRequest) => {
+                    let response = response::make_http_error(http::StatusCode::BAD_REQUEST);
+                    send_error_response(&mut client_conn, &response, &mut entry, &metrics).await;
+                    return;
+                }
+                Err(ForwardError::ClientTimeout) => {
+                    send_request_timeout(&mut client_conn, &mut entry, &metrics).await;
+                    return;
+                }
+                Err(ForwardError::ClientGone) => return,
+            }
+        };
+
+        if let Some(cache) = &response_cache {
+            match stale {
+                Some(stored) if response.status() == http::StatusCode::NOT_MODIFIED => {
+                    metrics.record_cache_lookup("revalidated");
+                    response = cache.revalidated(&pool, &request, stored, &response);
+                    response_framing = Framing::ContentLength(response.body().len());
+                }
+                stale => {
+                    if stale.is_some() {
+                        metrics.record_cache_lookup("miss");
+                    }
+                    // Only bodies of a known (and small enough) size are held on to for the
+                    // cache; anything else is streamed through as usual
+                    let body_len = match response_framing {
+                        Framing::Empty => Some(0),
+                        Framing::ContentLength(len) => Some(len),
+                        Framing::Chunked | Framing::UntilClose => None,
+                    };
+                    if let Some(len) = body_len
+                        .filter(|len| cacheable && cache.should_store(&request, &response, *len))
+                    {
+                        let mut upstream_body =
+                            body::ReadTimeout::new(&mut upstream.stream, per_try_timeout);
+                        let body = match len {
+                            0 => Ok(Vec::new()),
+                            len => {
+                                body::read_to_vec(&mut upstream_body, response.body(), len).await
+                            }
+                        };
+                        match body {
+                            Ok(body) => {
+                                *response.body_mut() = body;
+                                cache.store(&pool, &request, &response);
+                            }
+                            Err(error) => {
+                                log::error!(
+                                    "Error reading response body from upstream {}: {}",
+                                    upstream.address,
+                                    error
+                                );
+                                share_state
+                                    .lock()
+                                    .await
+                                    .record_outcome(&upstream.address, false)
+                                    .await;
+                                let response = response::make_http_error(match error {
+                                    body::Error::TimedOut => http::StatusCode::GATEWAY_TIMEOUT,
+                                    _ => http::StatusCode::BAD_GATEWAY,
+                                });
+                                send_error_response(
+                                    &mut client_conn,
+                                    &response,
+                                    &mut entry,
+                                    &metrics,
+                                )
+                                .await;
+                                return;
+                            }
+                        }
+                    }
+                }
+            }
+            cache.invalidate(&pool, &request, &response);
+        }
+        let upstream_conn = &mut upstream.stream;
+
+        // Forward the response to the client, streaming the body through as the server sends it
+        log::info!(
+            "{} <- {}",
+            client_ip,
+            response::format_response_line(&response)
+        );
+        headers::apply_rules(&header_rules.response, response.headers_mut(), &variables);
+        if let (Some(decision), true) = (&rate_limit, rate_limit_headers) {
+            decision.add_headers(response.headers_mut());
+        }
+        let switching_protocols = response.status() == http::StatusCode::SWITCHING_PROTOCOLS;
+        let closing = *shutdown.borrow();
+        if closing && !switching_protocols {
+            // Let the client know not to send another request on this connection
+            response.headers_mut().insert(
+                http::header::CONNECTION,
+                http::HeaderValue::from_static("close"),
             );
-            let response = response::make_http_error(http::StatusCode::BAD_GATEWAY);
-            send_response(&mut client_conn, &response).await;
+        }
+        entry.set_status(response.status());
+        if let Err(error) = response::write_head(&response, &mut client_conn).await {
+            log::warn!("Failed to send response to client: {}", error);
             return;
         }
-        log::debug!("Forwarded request to server");
+        metrics.record_response(Some(&upstream.address), response.status());
 
-        // Read the server's response
-        let response = match response::read_from_stream(&mut upstream_conn, request.method()).await
+        if switching_protocols {
+            share_state
+                .lock()
+                .await
+                .record_outcome(&upstream.address, true)
+                .await;
+            // From here on, the connection carries whatever protocol the client and upstream
+            // switched to, so all we can do is relay bytes until either of them is done. Anything
+            // the upstream sent right after its response goes first.
+            let mut transferred = tunnel::Transferred::default();
+            if let Err(error) = client_conn.write_all(response.body()).await {
+                log::warn!("Failed to send response to client: {}", error);
+                return;
+            }
+            tokio::select! {
+                ending = tunnel::relay(
+                    &mut client_conn,
+                    upstream_conn,
+                    upgrade_idle_timeout,
+                    &mut transferred,
+                ) => match ending {
+                    tunnel::Ending::Error(error) => {
+                        log::info!("Error relaying upgraded connection: {}", error);
+                    }
+                    ending => {
+                        log::debug!("Upgraded connection from {} ended: {:?}", client_ip, ending);
+                    }
+                },
+                _ = shutting_down(&mut shutdown) => {
+                    log::debug!("Closing upgraded connection from {} to shut down", client_ip);
+                }
+            }
+            metrics.add_request_body_bytes(&upstream.address, transferred.from_client);
+            let response_bytes = response.body().len() as u64 + transferred.from_upstream;
+            metrics.add_response_body_bytes(&upstream.address, response_bytes);
+            entry.add_bytes(response_bytes);
+            return;
+        }
+        let mut upstream_body = body::ReadTimeout::new(upstream_conn, per_try_timeout);
+        match body::copy(
+            &mut upstream_body,
+            response.body(),
+            &mut client_conn,
+            response_framing,
+        )
+        .await
         {
-            Ok(response) => response,
+            Ok(bytes) => {
+                metrics.add_response_body_bytes(&upstream.address, bytes);
+                entry.add_bytes(bytes);
+                if !server_error {
+                    share_state
+                        .lock()
+                        .await
+                        .record_outcome(&upstream.address, true)
+                        .await;
+                }
+            }
+            Err(body::Error::Write(error)) => {
+                // It's too late to send the client an error response, since it has already
+                // received the headers; all we can do is hang up
+                log::warn!("Failed to forward response body to client: {}", error);
+                return;
+            }
             Err(error) => {
-                log::error!("Error reading response from server: {:?}", error);
-                let response = response::make_http_error(http::StatusCode::BAD_GATEWAY);
-                send_response(&mut client_conn, &response).await;
+                // Too late for a 502 or 504 as well, but the upstream gets the blame
+                log::error!(
+                    "Error reading response body from upstream {}: {}",
+                    upstream.address,
+                    error
+                );
+                share_state
+                    .lock()
+                    .await
+                    .record_outcome(&upstream.address, false)
+                    .await;
                 return;
             }
-        };
-        // Forward the response to the client
-        send_response(&mut client_conn, &response).await;
+        }
         log::debug!("Forwarded response to client");
+        metrics.record_request_duration(started.elapsed());
+
+        if response_framing == Framing::UntilClose {
+            // The server ended the body by closing the connection, and closing the connection is
+            // the only way to tell the client that the body is over
+            return;
+        }
+        // The upstream connection can carry another request now, unless either side asked for it
+        // to be closed
+        if closing {
+            return;
+        }
+        if !wants_close(request.headers()) && !wants_close(response.headers()) {
+            share_state.lock().await.connection_pool.release(upstream);
+        }
     }
 }
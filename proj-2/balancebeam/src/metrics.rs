@@ -0,0 +1,344 @@
+use parking_lot::Mutex;
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+/// Upper bounds of the latency histogram buckets, in seconds
+const LATENCY_BUCKETS: [f64; 12] = [
+    0.001, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0,
+];
+
+/// Label used for responses we made up ourselves (e.g. 429s, or 502s when no upstream could be
+/// reached), which didn't come from any upstream
+const NO_UPSTREAM: &str = "none";
+
+/// Label used for responses served from the response cache
+pub const FROM_CACHE: &str = "cache";
+
+#[derive(Clone, Default)]
+struct Histogram {
+    /// Number of observations in each bucket (not cumulative; that's done when rendering)
+    buckets: [u64; LATENCY_BUCKETS.len()],
+    sum: f64,
+    count: u64,
+}
+
+impl Histogram {
+    fn observe(&mut self, duration: Duration) {
+        let seconds = duration.as_secs_f64();
+        if let Some(idx) = LATENCY_BUCKETS.iter().position(|bound| seconds <= *bound) {
+            self.buckets[idx] += 1;
+        }
+        self.sum += seconds;
+        self.count += 1;
+    }
+
+    fn render(&self, out: &mut String, name: &str, labels: &str) {
+        let separator = if labels.is_empty() { "" } else { "," };
+        let mut cumulative = 0;
+        for (bound, count) in LATENCY_BUCKETS.iter().zip(self.buckets.iter()) {
+            cumulative += count;
+            let _ = writeln!(
+                out,
+                "{}_bucket{{{}{}le=\"{}\"}} {}",
+                name, labels, separator, bound, cumulative
+            );
+        }
+        let _ = writeln!(
+            out,
+            "{}_bucket{{{}{}le=\"+Inf\"}} {}",
+            name, labels, separator, self.count
+        );
+        let braced = if labels.is_empty() {
+            String::new()
+        } else {
+            format!("{{{}}}", labels)
+        };
+        let _ = writeln!(out, "{}_sum{} {}", name, braced, self.sum);
+        let _ = writeln!(out, "{}_count{} {}", name, braced, self.count);
+    }
+}
+
+/// Everything that's counted per label. BTreeMaps keep the output in a stable order.
+#[derive(Default)]
+struct Counters {
+    /// Responses sent to clients, by upstream and status code
+    responses: BTreeMap<(String, u16), u64>,
+    request_duration: Histogram,
+    upstream_duration: BTreeMap<String, Histogram>,
+    /// Active health check results, by upstream and whether the upstream was healthy
+    health_checks: BTreeMap<(String, bool), u64>,
+    /// Changes of upstream state, by upstream, old state and new state
+    state_transitions: BTreeMap<(String, &'static str, &'static str), u64>,
+    request_body_bytes: BTreeMap<String, u64>,
+    response_body_bytes: BTreeMap<String, u64>,
+    /// Requests looked up in the response cache, by result (hit, miss or revalidated)
+    cache_lookups: BTreeMap<&'static str, u64>,
+}
+
+/// Counts what the proxy has been doing, for Prometheus to scrape from the admin API
+#[derive(Default)]
+pub struct Metrics {
+    /// Number of client connections currently open
+    pub active_client_connections: Arc<AtomicUsize>,
+    rate_limited_requests: AtomicU64,
+    counters: Mutex<Counters>,
+}
+
+impl Metrics {
+    pub fn new() -> Metrics {
+        Metrics::default()
+    }
+
+    /// Counts a response sent to a client. `upstream` is None if we made up the response.
+    pub fn record_response(&self, upstream: Option<&str>, status: http::StatusCode) {
+        let upstream = upstream.unwrap_or(NO_UPSTREAM).to_string();
+        *self
+            .counters
+            .lock()
+            .responses
+            .entry((upstream, status.as_u16()))
+            .or_default() += 1;
+    }
+
+    /// Records how long it took to proxy a request, from reading its head to sending the last byte
+    /// of the response.
+    pub fn record_request_duration(&self, duration: Duration) {
+        self.counters.lock().request_duration.observe(duration);
+    }
+
+    /// Records how long an upstream took to take a request and start responding to it.
+    pub fn record_upstream_duration(&self, upstream: &str, duration: Duration) {
+        self.counters
+            .lock()
+            .upstream_duration
+            .entry(upstream.to_string())
+            .or_default()
+            .observe(duration);
+    }
+
+    pub fn record_health_check(&self, upstream: &str, healthy: bool) {
+        *self
+            .counters
+            .lock()
+            .health_checks
+            .entry((upstream.to_string(), healthy))
+            .or_default() += 1;
+    }
+
+    pub fn record_transition(&self, upstream: &str, from: &'static str, to: &'static str) {
+        *self
+            .counters
+            .lock()
+            .state_transitions
+            .entry((upstream.to_string(), from, to))
+            .or_default() += 1;
+    }
+
+    pub fn record_cache_lookup(&self, result: &'static str) {
+        *self
+            .counters
+            .lock()
+            .cache_lookups
+            .entry(result)
+            .or_default() += 1;
+    }
+
+    pub fn record_rate_limited(&self) {
+        self.rate_limited_requests.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Counts request body bytes sent to an upstream (a retried body counts once per attempt).
+    pub fn add_request_body_bytes(&self, upstream: &str, bytes: u64) {
+        *self
+            .counters
+            .lock()
+            .request_body_bytes
+            .entry(upstream.to_string())
+            .or_default() += bytes;
+    }
+
+    /// Counts response body bytes forwarded from an upstream to a client.
+    pub fn add_response_body_bytes(&self, upstream: &str, bytes: u64) {
+        *self
+            .counters
+            .lock()
+            .response_body_bytes
+            .entry(upstream.to_string())
+            .or_default() += bytes;
+    }
+
+    /// Renders all metrics in the Prometheus text format. `upstream_states` holds the address and
+    /// current state of each upstream.
+    pub fn render(&self, upstream_states: &[(String, &'static str)]) -> String {
+        let counters = self.counters.lock();
+        let mut out = String::new();
+
+        header(
+            &mut out,
+            "balancebeam_responses_total",
+            "counter",
+            "Responses sent to clients, by upstream and status code",
+        );
+        for ((upstream, status), count) in &counters.responses {
+            let _ = writeln!(
+                out,
+                "balancebeam_responses_total{{upstream=\"{}\",status=\"{}\"}} {}",
+                escape(upstream),
+                status,
+                count
+            );
+        }
+
+        header(
+            &mut out,
+            "balancebeam_request_duration_seconds",
+            "histogram",
+            "Time taken to proxy a request, from reading its head to sending the whole response",
+        );
+        counters
+            .request_duration
+            .render(&mut out, "balancebeam_request_duration_seconds", "");
+
+        header(
+            &mut out,
+            "balancebeam_upstream_duration_seconds",
+            "histogram",
+            "Time taken by an upstream to take a request and start responding",
+        );
+        for (upstream, histogram) in &counters.upstream_duration {
+            histogram.render(
+                &mut out,
+                "balancebeam_upstream_duration_seconds",
+                &format!("upstream=\"{}\"", escape(upstream)),
+            );
+        }
+
+        header(
+            &mut out,
+            "balancebeam_active_client_connections",
+            "gauge",
+            "Client connections currently open",
+        );
+        let _ = writeln!(
+            out,
+            "balancebeam_active_client_connections {}",
+            self.active_client_connections.load(Ordering::SeqCst)
+        );
+
+        header(
+            &mut out,
+            "balancebeam_upstream_state",
+            "gauge",
+            "Current state of each upstream (1 for the state it is in)",
+        );
+        for (upstream, state) in upstream_states {
+            let _ = writeln!(
+                out,
+                "balancebeam_upstream_state{{upstream=\"{}\",state=\"{}\"}} 1",
+                escape(upstream),
+                state
+            );
+        }
+
+        header(
+            &mut out,
+            "balancebeam_health_checks_total",
+            "counter",
+            "Active health checks, by upstream and result",
+        );
+        for ((upstream, healthy), count) in &counters.health_checks {
+            let _ = writeln!(
+                out,
+                "balancebeam_health_checks_total{{upstream=\"{}\",result=\"{}\"}} {}",
+                escape(upstream),
+                if *healthy { "pass" } else { "fail" },
+                count
+            );
+        }
+
+        header(
+            &mut out,
+            "balancebeam_upstream_state_transitions_total",
+            "counter",
+            "Upstream state changes, by upstream, old state and new state",
+        );
+        for ((upstream, from, to), count) in &counters.state_transitions {
+            let _ = writeln!(
+                out,
+                "balancebeam_upstream_state_transitions_total{{upstream=\"{}\",from=\"{}\",\
+                to=\"{}\"}} {}",
+                escape(upstream),
+                from,
+                to,
+                count
+            );
+        }
+
+        header(
+            &mut out,
+            "balancebeam_rate_limited_requests_total",
+            "counter",
+            "Requests turned away by the rate limiter",
+        );
+        let _ = writeln!(
+            out,
+            "balancebeam_rate_limited_requests_total {}",
+            self.rate_limited_requests.load(Ordering::Relaxed)
+        );
+
+        header(
+            &mut out,
+            "balancebeam_cache_lookups_total",
+            "counter",
+            "Requests looked up in the response cache, by result",
+        );
+        for (result, count) in &counters.cache_lookups {
+            let _ = writeln!(
+                out,
+                "balancebeam_cache_lookups_total{{result=\"{}\"}} {}",
+                result, count
+            );
+        }
+
+        for (name, help, bytes) in &[
+            (
+                "balancebeam_request_body_bytes_total",
+                "Request body bytes sent to upstreams",
+                &counters.request_body_bytes,
+            ),
+            (
+                "balancebeam_response_body_bytes_total",
+                "Response body bytes forwarded from upstreams to clients",
+                &counters.response_body_bytes,
+            ),
+        ] {
+            header(&mut out, name, "counter", help);
+            for (upstream, count) in bytes.iter() {
+                let _ = writeln!(
+                    out,
+                    "{}{{upstream=\"{}\"}} {}",
+                    name,
+                    escape(upstream),
+                    count
+                );
+            }
+        }
+        out
+    }
+}
+
+fn header(out: &mut String, name: &str, kind: &str, help: &str) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} {}", name, kind);
+}
+
+/// Escapes a label value as the text format requires.
+fn escape(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
@@ -0,0 +1,221 @@
+use crate::load_balancing::ConnectionGuard;
+use crate::tls::{UpstreamConnector, UpstreamStream};
+use parking_lot::Mutex;
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+
+/// Settings for the pool of keep-alive connections we hold open to each upstream
+pub struct PoolConfig {
+    /// Maximum number of idle connections to keep around per upstream
+    pub max_idle: usize,
+    /// Maximum number of connections that can be in use (i.e. carrying a request) to a single
+    /// upstream at once. Requests beyond this wait for a connection to be released. 0 means
+    /// unlimited.
+    pub max_per_upstream: usize,
+    /// Maximum number of requests that can wait for a connection to an upstream that is at
+    /// max_per_upstream. Any more are turned away right away.
+    pub max_queued: usize,
+    /// Requests that have waited this long for a connection are turned away
+    pub queue_timeout: Duration,
+    /// Idle connections that haven't been used for this long are closed instead of reused
+    pub idle_timeout: Duration,
+    /// Opening a new connection fails with a TimedOut error if it takes longer than this
+    pub connect_timeout: Duration,
+    /// Opens new connections, over TLS for https:// upstreams
+    pub connector: Arc<UpstreamConnector>,
+}
+
+struct IdleConnection {
+    stream: UpstreamStream,
+    idle_since: Instant,
+}
+
+#[derive(Default)]
+struct UpstreamPool {
+    /// Connections that are ready for reuse, most recently used last
+    idle: Vec<IdleConnection>,
+    /// Hands out a permit for every connection in use, if there is a per-upstream limit
+    in_use_limit: Option<Arc<Semaphore>>,
+    /// Number of requests waiting for a permit
+    queued: Arc<AtomicUsize>,
+}
+
+/// Ways that getting a connection to an upstream can fail
+#[derive(Debug)]
+pub enum Error {
+    /// Opening a new connection failed (with a TimedOut error if it took too long)
+    Connect(std::io::Error),
+    /// The upstream has as many connections in use, and requests waiting for one, as it may
+    QueueFull,
+    /// No connection was released while the request waited for one
+    QueueTimeout,
+}
+
+impl std::fmt::Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Error::Connect(err) => write!(f, "{}", err),
+            Error::QueueFull => write!(f, "too many requests are waiting for a connection"),
+            Error::QueueTimeout => write!(f, "timed out waiting for a connection"),
+        }
+    }
+}
+
+/// Keeps idle keep-alive connections to each upstream around, so that requests don't have to pay
+/// for a new TCP connection (and TLS handshake) every time.
+pub struct ConnectionPool {
+    config: PoolConfig,
+    upstreams: Mutex<HashMap<String, UpstreamPool>>,
+}
+
+/// A connection to an upstream that has been checked out of the pool. Hand it back with
+/// ConnectionPool::release once a response has been read completely and the connection can carry
+/// another request; dropping it instead closes the connection.
+pub struct PooledConnection {
+    pub stream: UpstreamStream,
+    /// The upstream this connection goes to
+    pub address: String,
+    /// True if this connection was idle in the pool, rather than opened for this request. The
+    /// upstream may have closed it just as we picked it up.
+    pub reused: bool,
+    _permit: Option<OwnedSemaphorePermit>,
+}
+
+impl ConnectionPool {
+    pub fn new(config: PoolConfig) -> ConnectionPool {
+        ConnectionPool {
+            config,
+            upstreams: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Returns a connection to the given upstream, reusing an idle one if possible. If the
+    /// upstream already has max_per_upstream connections in use, this waits in line (for up to
+    /// queue_timeout) for one of them to be released.
+    pub async fn get(&self, address: &str) -> Result<PooledConnection, Error> {
+        self.checkout(address, true).await
+    }
+
+    /// Like get, but always opens a new connection, e.g. because a reused one turned out to have
+    /// been closed by the upstream.
+    pub async fn get_new(&self, address: &str) -> Result<PooledConnection, Error> {
+        self.checkout(address, false).await
+    }
+
+    async fn checkout(&self, address: &str, reuse_idle: bool) -> Result<PooledConnection, Error> {
+        let (limit, queued) = self.upstream_pool(address, |pool| {
+            if pool.in_use_limit.is_none() && self.config.max_per_upstream > 0 {
+                pool.in_use_limit = Some(Arc::new(Semaphore::new(self.config.max_per_upstream)));
+            }
+            (pool.in_use_limit.clone(), pool.queued.clone())
+        });
+        let permit = match limit {
+            Some(limit) => match limit.clone().try_acquire_owned() {
+                Ok(permit) => Some(permit),
+                Err(_) => {
+                    let _in_line = ConnectionGuard::new(queued.clone());
+                    if queued.load(Ordering::SeqCst) > self.config.max_queued {
+                        return Err(Error::QueueFull);
+                    }
+                    match tokio::time::timeout(self.config.queue_timeout, limit.acquire_owned())
+                        .await
+                    {
+                        Ok(permit) => Some(permit),
+                        Err(_elapsed) => return Err(Error::QueueTimeout),
+                    }
+                }
+            },
+            None => None,
+        };
+
+        let take_idle = || match reuse_idle {
+            true => self.upstream_pool(address, |pool| pool.idle.pop()),
+            false => None,
+        };
+        while let Some(mut idle) = take_idle() {
+            if idle.idle_since.elapsed() > self.config.idle_timeout {
+                continue;
+            }
+            if !is_still_open(&mut idle.stream).await {
+                log::debug!("Pooled connection to {} was closed while idle", address);
+                continue;
+            }
+            log::debug!("Reusing pooled connection to {}", address);
+            return Ok(PooledConnection {
+                stream: idle.stream,
+                address: address.to_string(),
+                reused: true,
+                _permit: permit,
+            });
+        }
+
+        let connect = self.config.connector.connect(address);
+        let stream = match tokio::time::timeout(self.config.connect_timeout, connect).await {
+            Ok(result) => result.map_err(Error::Connect)?,
+            Err(_elapsed) => {
+                return Err(Error::Connect(std::io::Error::new(
+                    std::io::ErrorKind::TimedOut,
+                    "timed out connecting",
+                )))
+            }
+        };
+        Ok(PooledConnection {
+            stream,
+            address: address.to_string(),
+            reused: false,
+            _permit: permit,
+        })
+    }
+
+    /// Returns a connection to the pool so that it can be reused for later requests. The caller
+    /// must only do this once the previous response has been read in full, and neither side asked
+    /// for the connection to be closed.
+    pub fn release(&self, connection: PooledConnection) {
+        let max_idle = self.config.max_idle;
+        let PooledConnection {
+            stream, address, ..
+        } = connection;
+        let mut upstreams = self.upstreams.lock();
+        let pool = match upstreams.get_mut(&address) {
+            Some(pool) => pool,
+            // The upstream has been removed since the connection was checked out
+            None => return,
+        };
+        // Close connections that have been idle for too long while we're here
+        let idle_timeout = self.config.idle_timeout;
+        pool.idle
+            .retain(|idle| idle.idle_since.elapsed() <= idle_timeout);
+        if pool.idle.len() < max_idle {
+            pool.idle.push(IdleConnection {
+                stream,
+                idle_since: Instant::now(),
+            });
+        }
+    }
+
+    /// Closes the idle connections to an upstream that is no longer used, and forgets its
+    /// connection limit. Connections to it that are still in use are closed once they are
+    /// released, instead of going back into the pool.
+    pub fn remove(&self, address: &str) {
+        self.upstreams.lock().remove(address);
+    }
+
+    fn upstream_pool<T>(&self, address: &str, f: impl FnOnce(&mut UpstreamPool) -> T) -> T {
+        let mut upstreams = self.upstreams.lock();
+        f(upstreams.entry(address.to_string()).or_default())
+    }
+}
+
+/// Checks whether the upstream has closed an idle connection (or, unexpectedly, sent something on
+/// it) without waiting for any data to arrive.
+async fn is_still_open(stream: &mut UpstreamStream) -> bool {
+    // A zero timeout still polls the check once, so this returns immediately either way. If the
+    // check would block, nothing has arrived and the connection is still usable.
+    match tokio::time::timeout(Duration::from_secs(0), stream.closed()).await {
+        Err(_elapsed) => true,
+        Ok(_) => false,
+    }
+}
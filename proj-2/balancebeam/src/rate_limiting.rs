@@ -1,59 +1,711 @@
-use std::collections::HashMap;
+use lru::LruCache;
+use serde::Serialize;
+use std::collections::VecDeque;
 use std::time::{Duration, Instant};
-use tokio::sync::Mutex;
 
-struct RequestState {
-    last_time: Instant,
-    requests: usize,
+/// Rate limiting algorithms, selected with the --rate-limit-algorithm command-line option
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Algorithm {
+    /// Count requests in consecutive fixed windows. Cheap, but a client can send up to twice the
+    /// limit in a short burst that straddles the boundary between two windows.
+    FixedWindow,
+    /// Remember the time of every request in the last window. Exact, but uses memory proportional
+    /// to the limit for every client.
+    SlidingWindowLog,
+    /// Approximate a sliding window by weighting the previous fixed window's count by how much of
+    /// it still overlaps the sliding window.
+    SlidingWindowCounter,
+    /// Give every client a bucket of tokens that refills at a steady rate, allowing short bursts
+    /// of up to the bucket's capacity.
+    TokenBucket,
+}
+
+impl std::str::FromStr for Algorithm {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Algorithm, String> {
+        match s {
+            "fixed-window" => Ok(Algorithm::FixedWindow),
+            "sliding-window-log" => Ok(Algorithm::SlidingWindowLog),
+            "sliding-window-counter" => Ok(Algorithm::SlidingWindowCounter),
+            "token-bucket" => Ok(Algorithm::TokenBucket),
+            _ => Err(format!(
+                "unknown rate limit algorithm \"{}\" (expected one of fixed-window, \
+                sliding-window-log, sliding-window-counter, token-bucket)",
+                s
+            )),
+        }
+    }
+}
+
+/// The outcome of checking a request against a rate limiter, along with what the client should be
+/// told about its quota (see the IETF RateLimit header fields draft)
+#[derive(Debug)]
+pub struct Decision {
+    /// The request exceeds the limit and should be turned away
+    pub limited: bool,
+    /// Number of requests the client is allowed (per window, or the burst size for the token
+    /// bucket)
+    pub limit: usize,
+    /// Number of requests the client can still make right now
+    pub remaining: usize,
+    /// How long until the client's quota is replenished
+    pub reset: Duration,
+    /// How long a rate-limited client should wait before trying again
+    pub retry_after: Duration,
+}
+
+impl Decision {
+    /// Adds RateLimit-Limit, RateLimit-Remaining and RateLimit-Reset headers describing the
+    /// client's quota, plus Retry-After if the request was turned away.
+    pub fn add_headers(&self, headers: &mut http::HeaderMap) {
+        headers.insert("ratelimit-limit", self.limit.into());
+        headers.insert("ratelimit-remaining", self.remaining.into());
+        headers.insert("ratelimit-reset", whole_seconds(self.reset).into());
+        if self.limited {
+            // Never tell a client to retry right away; it would just be turned away again
+            headers.insert(
+                http::header::RETRY_AFTER,
+                whole_seconds(self.retry_after).max(1).into(),
+            );
+        }
+    }
+}
+
+/// Rounds a duration up to whole seconds, so that a client waiting that long is sure to have
+/// waited long enough
+fn whole_seconds(duration: Duration) -> u64 {
+    duration.as_secs() + if duration.subsec_nanos() > 0 { 1 } else { 0 }
+}
+
+/// Decides whether a client has sent too many requests
+pub trait RateLimiter: Send {
+    /// Records a request from the client identified by `key`, and decides whether it exceeds the
+    /// limit.
+    fn rate_limit(&mut self, key: &str) -> Decision;
+
+    /// Forgets about clients that have been idle long enough to have their full quota back.
+    fn remove_idle(&mut self);
+
+    /// Returns the number of clients whose state we are holding on to.
+    fn tracked_clients(&self) -> usize;
 }
 
-impl Default for RequestState {
-    fn default() -> Self {
-        RequestState {
-            last_time: Instant::now(),
-            requests: 1,
+/// Creates a rate limiter that allows `limit` requests per `window` for each client. `burst` is
+/// only used by the token bucket, as the size of the bucket; it defaults to `limit` if 0. At most
+/// `max_clients` clients are tracked at once (0 = no cap).
+pub fn new_rate_limiter(
+    algorithm: Algorithm,
+    limit: usize,
+    window: Duration,
+    burst: usize,
+    max_clients: usize,
+) -> Box<dyn RateLimiter> {
+    match algorithm {
+        Algorithm::FixedWindow => Box::new(FixWindowRateLimit::new(limit, window, max_clients)),
+        Algorithm::SlidingWindowLog => {
+            Box::new(SlidingWindowLogRateLimit::new(limit, window, max_clients))
         }
+        Algorithm::SlidingWindowCounter => Box::new(SlidingWindowCounterRateLimit::new(
+            limit,
+            window,
+            max_clients,
+        )),
+        Algorithm::TokenBucket => Box::new(TokenBucketRateLimit::new(
+            limit,
+            window,
+            if burst == 0 { limit } else { burst },
+            max_clients,
+        )),
     }
 }
 
+/// One piece of what identifies a client for rate limiting
+#[derive(Clone, Debug, PartialEq)]
+enum KeyPart {
+    /// The IP address the connection came from
+    ClientIp,
+    /// The last address in X-Forwarded-For, i.e. the client as seen by the trusted proxy in front
+    /// of us. Earlier entries were supplied by the client and can't be trusted, and neither can
+    /// the header at all if the connection didn't come from a --trusted-proxy.
+    ForwardedFor,
+    /// The value of a header, such as an API key (name stored lowercase)
+    Header(String),
+    /// The first this-many segments of the request path
+    PathPrefix(usize),
+}
+
+/// What rate limits are counted per, selected with the --rate-limit-key command-line option. This
+/// is one or more of ip, forwarded-for, header:NAME and path[:SEGMENTS], joined with "+" (e.g.
+/// header:x-api-key+path counts each API key's requests to each top-level path separately).
+#[derive(Clone, Debug, PartialEq)]
+pub struct RateLimitKey(Vec<KeyPart>);
+
+impl std::str::FromStr for RateLimitKey {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<RateLimitKey, String> {
+        let invalid = || {
+            format!(
+                "invalid rate limit key \"{}\" (expected ip, forwarded-for, header:NAME or \
+                path[:SEGMENTS], optionally joined with +)",
+                s
+            )
+        };
+        s.split('+')
+            .map(|part| match part {
+                "ip" => Ok(KeyPart::ClientIp),
+                "forwarded-for" => Ok(KeyPart::ForwardedFor),
+                "path" => Ok(KeyPart::PathPrefix(1)),
+                _ if part.starts_with("header:") && part.len() > "header:".len() => {
+                    Ok(KeyPart::Header(part["header:".len()..].to_lowercase()))
+                }
+                _ if part.starts_with("path:") => part["path:".len()..]
+                    .parse::<usize>()
+                    .ok()
+                    .filter(|segments| *segments > 0)
+                    .map(KeyPart::PathPrefix)
+                    .ok_or_else(invalid),
+                _ => Err(invalid()),
+            })
+            .collect::<Result<Vec<_>, _>>()
+            .map(RateLimitKey)
+    }
+}
+
+impl RateLimitKey {
+    /// Extracts the key for the given request. If the request doesn't carry the header we are
+    /// looking for, we fall back to the client's IP address, so that clients without one don't
+    /// all share a single quota. `trusted_proxy` is true if the client is a --trusted-proxy.
+    pub fn extract(
+        &self,
+        client_ip: &str,
+        trusted_proxy: bool,
+        request: &http::Request<Vec<u8>>,
+    ) -> String {
+        let values = self
+            .0
+            .iter()
+            .map(|part| match part {
+                KeyPart::ClientIp => client_ip.to_string(),
+                // Otherwise a client could get a fresh quota with every request by making up a
+                // new X-Forwarded-For header each time
+                KeyPart::ForwardedFor if !trusted_proxy => client_ip.to_string(),
+                KeyPart::ForwardedFor => request
+                    .headers()
+                    .get_all("x-forwarded-for")
+                    .iter()
+                    .filter_map(|value| value.to_str().ok())
+                    .flat_map(|value| value.split(','))
+                    .map(|hop| hop.trim())
+                    .rfind(|hop| !hop.is_empty())
+                    .unwrap_or(client_ip)
+                    .to_string(),
+                KeyPart::Header(name) => request
+                    .headers()
+                    .get(name.as_str())
+                    .and_then(|value| value.to_str().ok())
+                    .unwrap_or(client_ip)
+                    .to_string(),
+                KeyPart::PathPrefix(segments) => {
+                    let path = request.uri().path();
+                    match path.match_indices('/').nth(*segments) {
+                        Some((idx, _)) => path[..idx].to_string(),
+                        None => path.to_string(),
+                    }
+                }
+            })
+            .collect::<Vec<String>>();
+        values.join("|")
+    }
+}
+
+/// A group of clients with a limit of its own: clients whose rate limit key starts with `prefix`
+/// are allowed `limit` requests per window instead of the default (see --rate-limit-class)
+#[derive(Clone, Debug)]
+pub struct RateLimitClass {
+    pub prefix: String,
+    pub limit: usize,
+}
+
+impl std::str::FromStr for RateLimitClass {
+    type Err = String;
+
+    /// Parses a class of the form PREFIX=LIMIT.
+    fn from_str(s: &str) -> Result<RateLimitClass, String> {
+        let idx = s
+            .rfind('=')
+            .ok_or_else(|| format!("invalid rate limit class \"{}\" (expected PREFIX=LIMIT)", s))?;
+        let limit = s[idx + 1..]
+            .parse::<usize>()
+            .map_err(|_| format!("invalid limit in rate limit class \"{}\"", s))?;
+        Ok(RateLimitClass {
+            prefix: s[..idx].to_string(),
+            limit,
+        })
+    }
+}
+
+/// Settings shared by every rate limiter
+pub struct RateLimitConfig {
+    pub algorithm: Algorithm,
+    /// Requests allowed per window for clients that don't fall into any class (0 = unlimited)
+    pub default_limit: usize,
+    pub window: Duration,
+    pub burst: usize,
+    /// Maximum number of clients each limiter keeps track of
+    pub max_clients: usize,
+    pub key: RateLimitKey,
+    pub classes: Vec<RateLimitClass>,
+}
+
+/// One of the limits we enforce, along with counts of how it has been deciding
+struct Limit {
+    /// The key prefix of the class this limit is for, or None for the default limit
+    class: Option<String>,
+    limit: usize,
+    /// None if the limit is 0, which means requests are never turned away
+    limiter: Option<Box<dyn RateLimiter>>,
+    allowed: u64,
+    limited: u64,
+}
+
+/// How one of the limits has been doing, for the admin API
+#[derive(Serialize)]
+pub struct LimitStats {
+    /// The key prefix of the class this limit is for, or None for the default limit
+    pub class: Option<String>,
+    pub limit: usize,
+    /// Number of clients the limiter currently keeps track of
+    pub clients: usize,
+    /// Number of requests let through since we started
+    pub allowed: u64,
+    /// Number of requests turned away since we started
+    pub limited: u64,
+}
+
+/// All of the rate limits we enforce: the default one, plus one for each class of client
+pub struct RateLimits {
+    key: RateLimitKey,
+    /// Sorted by prefix length, longest first, so that the most specific class wins. The default
+    /// limit comes last, and applies to every client that isn't in a class.
+    limits: Vec<Limit>,
+}
+
+impl RateLimits {
+    pub fn new(config: RateLimitConfig) -> RateLimits {
+        let limit = |class: Option<String>, limit: usize| Limit {
+            class,
+            limit,
+            limiter: if limit == 0 {
+                None
+            } else {
+                Some(new_rate_limiter(
+                    config.algorithm,
+                    limit,
+                    config.window,
+                    config.burst,
+                    config.max_clients,
+                ))
+            },
+            allowed: 0,
+            limited: 0,
+        };
+        let mut limits = config
+            .classes
+            .iter()
+            .map(|class| limit(Some(class.prefix.clone()), class.limit))
+            .collect::<Vec<_>>();
+        limits.sort_by_key(|limit| std::cmp::Reverse(limit.class.as_ref().unwrap().len()));
+        limits.push(limit(None, config.default_limit));
+        RateLimits {
+            limits,
+            key: config.key,
+        }
+    }
+
+    /// Counts a request against the limit that applies to it. Returns None if the request isn't
+    /// subject to any limit.
+    pub fn check(
+        &mut self,
+        client_ip: &str,
+        trusted_proxy: bool,
+        request: &http::Request<Vec<u8>>,
+    ) -> Option<Decision> {
+        let key = self.key.extract(client_ip, trusted_proxy, request);
+        let limit = self
+            .limits
+            .iter_mut()
+            .find(|limit| match &limit.class {
+                Some(prefix) => key.starts_with(prefix.as_str()),
+                None => true,
+            })
+            .unwrap();
+        let decision = limit.limiter.as_mut()?.rate_limit(&key);
+        if decision.limited {
+            limit.limited += 1;
+        } else {
+            limit.allowed += 1;
+        }
+        Some(decision)
+    }
+
+    /// Forgets about clients that haven't been seen for long enough that they would start over
+    /// with a full quota anyway.
+    pub fn remove_idle(&mut self) {
+        for limiter in self
+            .limits
+            .iter_mut()
+            .filter_map(|limit| limit.limiter.as_mut())
+        {
+            limiter.remove_idle();
+        }
+    }
+
+    pub fn stats(&self) -> Vec<LimitStats> {
+        self.limits
+            .iter()
+            .map(|limit| LimitStats {
+                class: limit.class.clone(),
+                limit: limit.limit,
+                clients: limit
+                    .limiter
+                    .as_ref()
+                    .map_or(0, |limiter| limiter.tracked_clients()),
+                allowed: limit.allowed,
+                limited: limit.limited,
+            })
+            .collect()
+    }
+}
+
+/// The per-client state of a rate limiter. This holds at most a fixed number of clients; when it is
+/// full, the client we heard from least recently is forgotten to make room.
+struct ClientTable<T> {
+    clients: LruCache<String, (T, Instant)>,
+    /// How long a client has to be idle before its state is no different from a new client's
+    idle_ttl: Duration,
+}
+
+impl<T> ClientTable<T> {
+    fn new(max_clients: usize, idle_ttl: Duration) -> ClientTable<T> {
+        ClientTable {
+            clients: if max_clients == 0 {
+                LruCache::unbounded()
+            } else {
+                LruCache::new(max_clients)
+            },
+            idle_ttl,
+        }
+    }
+
+    /// Returns the state of the client with the given key, creating it with `new` if we don't have
+    /// it yet.
+    fn get_or_insert(&mut self, key: &str, new: impl FnOnce() -> T) -> &mut T {
+        let now = Instant::now();
+        let key = key.to_string();
+        if !self.clients.contains(&key) {
+            self.clients.put(key.clone(), (new(), now));
+        }
+        let (state, last_seen) = self.clients.get_mut(&key).unwrap();
+        *last_seen = now;
+        state
+    }
+
+    fn len(&self) -> usize {
+        self.clients.len()
+    }
+
+    fn remove_idle(&mut self) {
+        while let Some((_, (_, last_seen))) = self.clients.peek_lru() {
+            if last_seen.elapsed() < self.idle_ttl {
+                break;
+            }
+            self.clients.pop_lru();
+        }
+    }
+}
+
+struct RequestState {
+    window_start: Instant,
+    requests: usize,
+}
+
 pub struct FixWindowRateLimit {
-    max_requests_per_minute: usize,
-    requests_per_ip: Mutex<HashMap<String, RequestState>>,
+    max_requests: usize,
+    window: Duration,
+    requests_per_client: ClientTable<RequestState>,
 }
 
 impl FixWindowRateLimit {
-    pub fn new(max_requests_per_minute: usize) -> FixWindowRateLimit {
+    pub fn new(max_requests: usize, window: Duration, max_clients: usize) -> FixWindowRateLimit {
         FixWindowRateLimit {
-            max_requests_per_minute,
-            requests_per_ip: Mutex::new(HashMap::new()),
+            max_requests,
+            window,
+            requests_per_client: ClientTable::new(max_clients, window),
         }
     }
+}
+
+impl RateLimiter for FixWindowRateLimit {
+    fn rate_limit(&mut self, key: &str) -> Decision {
+        let now = Instant::now();
+        let state = self
+            .requests_per_client
+            .get_or_insert(key, || RequestState {
+                window_start: now,
+                requests: 0,
+            });
+        if now.duration_since(state.window_start) >= self.window {
+            state.window_start = now;
+            state.requests = 0;
+        }
 
-    pub async fn rate_limit(&mut self, ip: &str) -> bool {
-        if self.max_requests_per_minute == 0 {
-            return false;
+        state.requests += 1;
+        let reset = self.window - now.duration_since(state.window_start);
+        Decision {
+            limited: state.requests > self.max_requests,
+            limit: self.max_requests,
+            remaining: self.max_requests.saturating_sub(state.requests),
+            reset,
+            retry_after: reset,
         }
+    }
 
-        let mut hm = self.requests_per_ip.lock().await;
-        let requests_per_ip = match hm.get_mut(ip) {
-            Some(entry) => entry,
-            None => {
-                hm.insert(ip.to_string(), Default::default());
-                return false;
+    fn remove_idle(&mut self) {
+        self.requests_per_client.remove_idle();
+    }
+
+    fn tracked_clients(&self) -> usize {
+        self.requests_per_client.len()
+    }
+}
+
+pub struct SlidingWindowLogRateLimit {
+    max_requests: usize,
+    window: Duration,
+    /// Times of the requests each client made in the last window, oldest first
+    requests_per_client: ClientTable<VecDeque<Instant>>,
+}
+
+impl SlidingWindowLogRateLimit {
+    pub fn new(
+        max_requests: usize,
+        window: Duration,
+        max_clients: usize,
+    ) -> SlidingWindowLogRateLimit {
+        SlidingWindowLogRateLimit {
+            max_requests,
+            window,
+            requests_per_client: ClientTable::new(max_clients, window),
+        }
+    }
+}
+
+impl RateLimiter for SlidingWindowLogRateLimit {
+    fn rate_limit(&mut self, key: &str) -> Decision {
+        let now = Instant::now();
+        let window = self.window;
+        let log = self.requests_per_client.get_or_insert(key, VecDeque::new);
+        while let Some(oldest) = log.front() {
+            if now.duration_since(*oldest) < window {
+                break;
             }
+            log.pop_front();
+        }
+
+        // Rejected requests aren't logged, so that a client that keeps retrying still gets let
+        // back in once its earlier requests age out of the window
+        let limited = log.len() >= self.max_requests;
+        if !limited {
+            log.push_back(now);
+        }
+        // Another request is allowed once the oldest one leaves the window, and the whole quota is
+        // back once the newest one has
+        let age_out = |time: Option<&Instant>| {
+            time.map_or(Duration::from_secs(0), |time| {
+                window - now.duration_since(*time)
+            })
+        };
+        Decision {
+            limited,
+            limit: self.max_requests,
+            remaining: self.max_requests - log.len(),
+            reset: age_out(log.back()),
+            retry_after: age_out(log.front()),
+        }
+    }
+
+    fn remove_idle(&mut self) {
+        self.requests_per_client.remove_idle();
+    }
+
+    fn tracked_clients(&self) -> usize {
+        self.requests_per_client.len()
+    }
+}
+
+struct WindowCounts {
+    window_start: Instant,
+    current: usize,
+    previous: usize,
+}
+
+pub struct SlidingWindowCounterRateLimit {
+    max_requests: usize,
+    window: Duration,
+    requests_per_client: ClientTable<WindowCounts>,
+}
+
+impl SlidingWindowCounterRateLimit {
+    pub fn new(
+        max_requests: usize,
+        window: Duration,
+        max_clients: usize,
+    ) -> SlidingWindowCounterRateLimit {
+        SlidingWindowCounterRateLimit {
+            max_requests,
+            window,
+            // A client's requests still count against it until the window after the one they were
+            // made in is over
+            requests_per_client: ClientTable::new(max_clients, window * 2),
+        }
+    }
+}
+
+impl RateLimiter for SlidingWindowCounterRateLimit {
+    fn rate_limit(&mut self, key: &str) -> Decision {
+        let now = Instant::now();
+        let window = self.window;
+        let counts = self
+            .requests_per_client
+            .get_or_insert(key, || WindowCounts {
+                window_start: now,
+                current: 0,
+                previous: 0,
+            });
+        let mut elapsed = now.duration_since(counts.window_start);
+        if elapsed >= window {
+            // If more than one whole window has passed, the previous window was empty
+            counts.previous = if elapsed < window * 2 {
+                counts.current
+            } else {
+                0
+            };
+            counts.current = 0;
+            let windows_passed = (elapsed.as_nanos() / window.as_nanos()) as u32;
+            counts.window_start += window * windows_passed;
+            elapsed = now.duration_since(counts.window_start);
+        }
+
+        // The sliding window covers the current fixed window so far, plus the tail end of the
+        // previous one. Assume the previous window's requests were spread evenly across it.
+        let max_requests = self.max_requests as f64;
+        let previous_weight = 1.0 - elapsed.as_secs_f64() / window.as_secs_f64();
+        let mut estimate = counts.previous as f64 * previous_weight + counts.current as f64;
+        let limited = estimate + 1.0 > max_requests;
+        if !limited {
+            counts.current += 1;
+            estimate += 1.0;
+        }
+
+        // The estimate falls as the previous window slides out. Work out when it will have fallen
+        // enough to let another request in; if the current window alone is already full, that
+        // won't happen before the window ends.
+        let window_end = window - elapsed;
+        let retry_after = if counts.previous > 0 && (counts.current as f64) + 1.0 <= max_requests {
+            let weight_needed =
+                (max_requests - counts.current as f64 - 1.0) / counts.previous as f64;
+            let wait = (1.0 - weight_needed) * window.as_secs_f64() - elapsed.as_secs_f64();
+            Duration::from_secs_f64(wait.max(0.0))
+        } else {
+            window_end
         };
+        Decision {
+            limited,
+            limit: self.max_requests,
+            remaining: (max_requests - estimate).max(0.0) as usize,
+            reset: window_end,
+            retry_after,
+        }
+    }
 
-        if requests_per_ip.last_time + Duration::from_secs(60) < Instant::now() {
-            requests_per_ip.last_time = Instant::now();
-            requests_per_ip.requests = 0;
-            return false;
+    fn remove_idle(&mut self) {
+        self.requests_per_client.remove_idle();
+    }
+
+    fn tracked_clients(&self) -> usize {
+        self.requests_per_client.len()
+    }
+}
+
+struct Bucket {
+    tokens: f64,
+    last_refill: Instant,
+}
+
+pub struct TokenBucketRateLimit {
+    /// Tokens added to each bucket per second
+    refill_rate: f64,
+    /// Maximum number of tokens a bucket holds, i.e. the largest burst a client can send at once
+    capacity: f64,
+    buckets: ClientTable<Bucket>,
+}
+
+impl TokenBucketRateLimit {
+    /// Creates a rate limiter that refills `max_requests` tokens per `window`, into buckets that
+    /// hold up to `burst` tokens.
+    pub fn new(
+        max_requests: usize,
+        window: Duration,
+        burst: usize,
+        max_clients: usize,
+    ) -> TokenBucketRateLimit {
+        let refill_rate = max_requests as f64 / window.as_secs_f64();
+        TokenBucketRateLimit {
+            refill_rate,
+            capacity: burst as f64,
+            // After this long, even an empty bucket is full again
+            buckets: ClientTable::new(
+                max_clients,
+                Duration::from_secs_f64(burst as f64 / refill_rate),
+            ),
         }
+    }
+}
+
+impl RateLimiter for TokenBucketRateLimit {
+    fn rate_limit(&mut self, key: &str) -> Decision {
+        let now = Instant::now();
+        let capacity = self.capacity;
+        let refill_rate = self.refill_rate;
+        let bucket = self.buckets.get_or_insert(key, || Bucket {
+            tokens: capacity,
+            last_refill: now,
+        });
+        let refill = now.duration_since(bucket.last_refill).as_secs_f64() * refill_rate;
+        bucket.tokens = (bucket.tokens + refill).min(capacity);
+        bucket.last_refill = now;
 
-        requests_per_ip.requests += 1;
-        if requests_per_ip.requests > self.max_requests_per_minute {
-            return true;
+        let limited = bucket.tokens < 1.0;
+        if !limited {
+            bucket.tokens -= 1.0;
         }
+        let time_to_refill = |tokens: f64| Duration::from_secs_f64(tokens.max(0.0) / refill_rate);
+        Decision {
+            limited,
+            limit: capacity as usize,
+            remaining: bucket.tokens as usize,
+            reset: time_to_refill(capacity - bucket.tokens),
+            retry_after: time_to_refill(1.0 - bucket.tokens),
+        }
+    }
+
+    fn remove_idle(&mut self) {
+        self.buckets.remove_idle();
+    }
 
-        return false;
+    fn tracked_clients(&self) -> usize {
+        self.buckets.len()
     }
 }
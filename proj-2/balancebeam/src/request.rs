@@ -1,11 +1,8 @@
-use std::cmp::min;
-// use std::io::{Read, Write};
-// use std::net::TcpStream;
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
-use tokio::net::TcpStream;
+use crate::body::Framing;
+use crate::chunked;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
 
 const MAX_HEADERS_SIZE: usize = 8000;
-const MAX_BODY_SIZE: usize = 10000000;
 const MAX_NUM_HEADERS: usize = 32;
 
 #[derive(Debug)]
@@ -17,52 +14,73 @@ pub enum Error {
     MalformedRequest(httparse::Error),
     /// The Content-Length header is present, but does not contain a valid numeric value
     InvalidContentLength,
-    /// The Content-Length header does not match the size of the request body that was sent
-    ContentLengthMismatch,
-    /// The request body is bigger than MAX_BODY_SIZE
-    RequestBodyTooLarge,
-    /// Encountered an I/O error when reading/writing a TcpStream
-    ConnectionError(std::io::Error),
+    /// The Transfer-Encoding header names a coding other than chunked last, or is combined with
+    /// Content-Length (which upstreams might interpret differently than we do)
+    InvalidTransferEncoding,
+    /// Encountered an I/O error when reading/writing a stream
+    Connection(std::io::Error),
 }
 
+impl std::fmt::Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Error::IncompleteRequest(_) => write!(f, "client hung up in the middle of a request"),
+            Error::MalformedRequest(err) => write!(f, "malformed request: {}", err),
+            Error::InvalidContentLength => write!(f, "invalid Content-Length"),
+            Error::InvalidTransferEncoding => write!(f, "invalid Transfer-Encoding"),
+            Error::Connection(err) => write!(f, "{}", err),
+        }
+    }
+}
+
+/// A parsed request, and the number of bytes of the buffer its headers took up
+type Head = (http::Request<Vec<u8>>, usize);
+
 /// Extracts the Content-Length header value from the provided request. Returns Ok(Some(usize)) if
 /// the Content-Length is present and valid, Ok(None) if Content-Length is not present, or
 /// Err(Error) if Content-Length is present but invalid.
 ///
-/// You won't need to touch this function.
+/// Content-Length may be repeated (in several headers, or as a comma-separated list), but only with
+/// the same value each time. Given conflicting values, an upstream might pick a different one than
+/// we do, and take the rest of the body for another request.
 fn get_content_length(request: &http::Request<Vec<u8>>) -> Result<Option<usize>, Error> {
-    // Look for content-length header
-    if let Some(header_value) = request.headers().get("content-length") {
-        // If it exists, parse it as a usize (or return InvalidContentLength if it can't be parsed as such)
-        Ok(Some(
-            header_value
-                .to_str()
-                .or(Err(Error::InvalidContentLength))?
+    let mut content_length = None;
+    for header_value in request.headers().get_all("content-length") {
+        let header_value = header_value.to_str().or(Err(Error::InvalidContentLength))?;
+        for value in header_value.split(',').map(|value| value.trim()) {
+            // parse would also take a leading "+"
+            if value.is_empty() || !value.bytes().all(|byte| byte.is_ascii_digit()) {
+                return Err(Error::InvalidContentLength);
+            }
+            let value = value
                 .parse::<usize>()
-                .or(Err(Error::InvalidContentLength))?,
-        ))
-    } else {
-        // If it doesn't exist, return None
-        Ok(None)
+                .or(Err(Error::InvalidContentLength))?;
+            if content_length.is_some_and(|content_length| content_length != value) {
+                return Err(Error::InvalidContentLength);
+            }
+            content_length = Some(value);
+        }
     }
+    Ok(content_length)
 }
 
 /// This function appends to a header value (adding a new header if the header is not already
 /// present). This is used to add the client's IP address to the end of the X-Forwarded-For list,
-/// or to add a new X-Forwarded-For header if one is not already present.
-///
-/// You won't need to touch this function.
+/// or to add a new X-Forwarded-For header if one is not already present. If the header appears
+/// more than once, its values are joined into a single list first, so that none of them are lost.
 pub fn extend_header_value(
     request: &mut http::Request<Vec<u8>>,
     name: &'static str,
     extend_value: &str,
 ) {
-    let new_value = match request.headers().get(name) {
-        Some(existing_value) => {
-            [existing_value.as_bytes(), b", ", extend_value.as_bytes()].concat()
-        }
-        None => extend_value.as_bytes().to_owned(),
-    };
+    let new_value = request
+        .headers()
+        .get_all(name)
+        .iter()
+        .map(|existing_value| existing_value.as_bytes())
+        .chain(std::iter::once(extend_value.as_bytes()))
+        .collect::<Vec<_>>()
+        .join(&b", "[..]);
     request
         .headers_mut()
         .insert(name, http::HeaderValue::from_bytes(&new_value).unwrap());
@@ -76,12 +94,10 @@ pub fn extend_header_value(
 /// * If there is data in the buffer that is definitely not a valid HTTP request, returns Err(Error)
 ///
 /// You won't need to touch this function.
-fn parse_request(buffer: &[u8]) -> Result<Option<(http::Request<Vec<u8>>, usize)>, Error> {
+fn parse_request(buffer: &[u8]) -> Result<Option<Head>, Error> {
     let mut headers = [httparse::EMPTY_HEADER; MAX_NUM_HEADERS];
     let mut req = httparse::Request::new(&mut headers);
-    let res = req
-        .parse(buffer)
-        .or_else(|err| Err(Error::MalformedRequest(err)))?;
+    let res = req.parse(buffer).map_err(Error::MalformedRequest)?;
 
     if let httparse::Status::Complete(len) = res {
         let mut request = http::Request::builder()
@@ -105,7 +121,9 @@ fn parse_request(buffer: &[u8]) -> Result<Option<(http::Request<Vec<u8>>, usize)
 /// Returns Ok(http::Request) if a valid request is received, or Error if not.
 ///
 /// You will need to modify this function in Milestone 2.
-async fn read_headers(stream: &mut TcpStream) -> Result<http::Request<Vec<u8>>, Error> {
+async fn read_headers<S: AsyncRead + Unpin>(
+    stream: &mut S,
+) -> Result<http::Request<Vec<u8>>, Error> {
     // Try reading the headers from the request. We may not receive all the headers in one shot
     // (e.g. we might receive the first few bytes of a request, and then the rest follows later).
     // Try parsing repeatedly until we read a valid HTTP request
@@ -116,7 +134,7 @@ async fn read_headers(stream: &mut TcpStream) -> Result<http::Request<Vec<u8>>,
         let new_bytes = stream
             .read(&mut request_buffer[bytes_read..])
             .await
-            .or_else(|err| Err(Error::ConnectionError(err)))?;
+            .map_err(Error::Connection)?;
         if new_bytes == 0 {
             // We didn't manage to read a complete request
             return Err(Error::IncompleteRequest(bytes_read));
@@ -137,90 +155,77 @@ async fn read_headers(stream: &mut TcpStream) -> Result<http::Request<Vec<u8>>,
     }
 }
 
-/// This function reads the body for a request from the stream. The client only sends a body if the
-/// Content-Length header is present; this function reads that number of bytes from the stream. It
-/// returns Ok(()) if successful, or Err(Error) if Content-Length bytes couldn't be read.
-///
-/// You will need to modify this function in Milestone 2.
-async fn read_body(
-    stream: &mut TcpStream,
-    request: &mut http::Request<Vec<u8>>,
-    content_length: usize,
-) -> Result<(), Error> {
-    // Keep reading data until we read the full body length, or until we hit an error.
-    while request.body().len() < content_length {
-        // Read up to 512 bytes at a time. (If the client only sent a small body, then only allocate
-        // space to read that body.)
-        let mut buffer = vec![0_u8; min(512, content_length)];
-        let bytes_read = stream
-            .read(&mut buffer)
-            .await
-            .or_else(|err| Err(Error::ConnectionError(err)))?;
-
-        // Make sure the client is still sending us bytes
-        if bytes_read == 0 {
-            log::debug!(
-                "Client hung up after sending a body of length {}, even though it said the content \
-                length is {}",
-                request.body().len(),
-                content_length
-            );
-            return Err(Error::ContentLengthMismatch);
+/// Works out how the body of the request is framed (RFC 7230, section 3.3.3). A request body is
+/// only delimited by Transfer-Encoding if chunked is the final coding. A request with both
+/// Transfer-Encoding and Content-Length could be framed differently by the upstream than by us, so
+/// we reject it.
+fn body_framing(request: &mut http::Request<Vec<u8>>) -> Result<Framing, Error> {
+    if request.headers().contains_key("transfer-encoding") {
+        if !chunked::is_chunked(request.headers())
+            || request.headers().contains_key("content-length")
+        {
+            return Err(Error::InvalidTransferEncoding);
         }
-
-        // Make sure the client didn't send us *too many* bytes
-        if request.body().len() + bytes_read > content_length {
-            log::debug!(
-                "Client sent more bytes than we expected based on the given content length!"
-            );
-            return Err(Error::ContentLengthMismatch);
-        }
-
-        // Store the received bytes in the request body
-        request.body_mut().extend_from_slice(&buffer[..bytes_read]);
+        return Ok(Framing::Chunked);
+    }
+    // The client only sends a body if the Content-Length header is present (which it does for
+    // POST requests)
+    let content_length = get_content_length(request)?;
+    if let Some(content_length) = content_length {
+        // Pass on a repeated Content-Length just once
+        request
+            .headers_mut()
+            .insert(http::header::CONTENT_LENGTH, content_length.into());
+    }
+    match content_length {
+        Some(0) | None => Ok(Framing::Empty),
+        Some(content_length) => Ok(Framing::ContentLength(content_length)),
     }
-    Ok(())
 }
 
-/// This function reads and returns an HTTP request from a stream, returning an Error if the client
-/// closes the connection prematurely or sends an invalid request.
-///
-/// You will need to modify this function in Milestone 2.
-pub async fn read_from_stream(stream: &mut TcpStream) -> Result<http::Request<Vec<u8>>, Error> {
-    // Read headers
+/// This function reads an HTTP request's headers from a stream, returning an Error if the client
+/// closes the connection prematurely or sends an invalid request. It also returns how the request
+/// body is framed; the caller is expected to forward the body with body::copy. Any part of the
+/// body that was read along with the headers is left in the request body.
+pub async fn read_head<S: AsyncRead + Unpin>(
+    stream: &mut S,
+) -> Result<(http::Request<Vec<u8>>, Framing), Error> {
     let mut request = read_headers(stream).await?;
-    // Read body if the client supplied the Content-Length header (which it does for POST requests)
-    if let Some(content_length) = get_content_length(&request)? {
-        if content_length > MAX_BODY_SIZE {
-            return Err(Error::RequestBodyTooLarge);
-        } else {
-            read_body(stream, &mut request, content_length).await?;
-        }
+    let framing = body_framing(&mut request)?;
+    Ok((request, framing))
+}
+
+/// This function serializes the request line and headers of a request and writes them to the
+/// provided stream. The body is not written.
+pub async fn write_head<S: AsyncWrite + Unpin>(
+    request: &http::Request<Vec<u8>>,
+    stream: &mut S,
+) -> Result<(), std::io::Error> {
+    // Assemble the head in memory first so it goes out in one write instead of a packet per line
+    let mut head = format_request_line(request).into_bytes();
+    head.extend_from_slice(b"\r\n");
+    for (header_name, header_value) in request.headers() {
+        head.extend_from_slice(header_name.as_str().as_bytes());
+        head.extend_from_slice(b": ");
+        head.extend_from_slice(header_value.as_bytes());
+        head.extend_from_slice(b"\r\n");
     }
-    Ok(request)
+    head.extend_from_slice(b"\r\n");
+    stream.write_all(&head).await
 }
 
 /// This function serializes a request to bytes and writes those bytes to the provided stream.
 ///
 /// You will need to modify this function in Milestone 2.
-pub async fn write_to_stream(
+pub async fn write_to_stream<S: AsyncWrite + Unpin>(
     request: &http::Request<Vec<u8>>,
-    stream: &mut TcpStream,
+    stream: &mut S,
 ) -> Result<(), std::io::Error> {
-    stream
-        .write(&format_request_line(request).into_bytes())
-        .await?;
-    stream.write(&['\r' as u8, '\n' as u8]).await?; // \r\n
-    for (header_name, header_value) in request.headers() {
-        stream
-            .write(&format!("{}: ", header_name).as_bytes())
-            .await?;
-        stream.write(header_value.as_bytes()).await?;
-        stream.write(&['\r' as u8, '\n' as u8]).await?; // \r\n
-    }
-    stream.write(&['\r' as u8, '\n' as u8]).await?;
-    if request.body().len() > 0 {
-        stream.write(request.body()).await?;
+    write_head(request, stream).await?;
+    if let Some(trailers) = request.extensions().get::<chunked::Trailers>() {
+        chunked::write_body(stream, request.body(), trailers).await?;
+    } else if !request.body().is_empty() {
+        stream.write_all(request.body()).await?;
     }
     Ok(())
 }
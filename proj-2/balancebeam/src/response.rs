@@ -1,28 +1,50 @@
-// use std::io::{Read, Write};
-// use std::net::TcpStream;
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
-use tokio::net::TcpStream;
+use crate::body::Framing;
+use crate::chunked;
+use crate::limits;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
 
 const MAX_HEADERS_SIZE: usize = 8000;
-const MAX_BODY_SIZE: usize = 10000000;
+pub const MAX_BODY_SIZE: usize = 10000000;
 const MAX_NUM_HEADERS: usize = 32;
 
 #[derive(Debug)]
 pub enum Error {
-    /// Client hung up before sending a complete request
-    IncompleteResponse,
+    /// Upstream hung up before sending a complete response. IncompleteResponse contains the number
+    /// of bytes that were successfully read before the upstream hung up
+    IncompleteResponse(usize),
     /// Client sent an invalid HTTP request. httparse::Error contains more details
     MalformedResponse(httparse::Error),
     /// The Content-Length header is present, but does not contain a valid numeric value
     InvalidContentLength,
     /// The Content-Length header does not match the size of the request body that was sent
     ContentLengthMismatch,
+    /// The chunked response body is malformed, or the server hung up before the last chunk
+    MalformedChunkedBody,
     /// The request body is bigger than MAX_BODY_SIZE
     ResponseBodyTooLarge,
-    /// Encountered an I/O error when reading/writing a TcpStream
-    ConnectionError(std::io::Error),
+    /// Encountered an I/O error when reading/writing a stream
+    Connection(std::io::Error),
 }
 
+impl std::fmt::Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Error::IncompleteResponse(_) => {
+                write!(f, "upstream hung up in the middle of a response")
+            }
+            Error::MalformedResponse(err) => write!(f, "malformed response: {}", err),
+            Error::InvalidContentLength => write!(f, "invalid Content-Length"),
+            Error::ContentLengthMismatch => write!(f, "body doesn't match its Content-Length"),
+            Error::MalformedChunkedBody => write!(f, "malformed chunked body"),
+            Error::ResponseBodyTooLarge => write!(f, "body is too large"),
+            Error::Connection(err) => write!(f, "{}", err),
+        }
+    }
+}
+
+/// A parsed response, and the number of bytes of the buffer its headers took up
+type Head = (http::Response<Vec<u8>>, usize);
+
 /// Extracts the Content-Length header value from the provided response. Returns Ok(Some(usize)) if
 /// the Content-Length is present and valid, Ok(None) if Content-Length is not present, or
 /// Err(Error) if Content-Length is present but invalid.
@@ -54,12 +76,10 @@ fn get_content_length(response: &http::Response<Vec<u8>>) -> Result<Option<usize
 ///   Err(Error)
 ///
 /// You won't need to touch this function.
-fn parse_response(buffer: &[u8]) -> Result<Option<(http::Response<Vec<u8>>, usize)>, Error> {
+fn parse_response(buffer: &[u8]) -> Result<Option<Head>, Error> {
     let mut headers = [httparse::EMPTY_HEADER; MAX_NUM_HEADERS];
     let mut resp = httparse::Response::new(&mut headers);
-    let res = resp
-        .parse(buffer)
-        .or_else(|err| Err(Error::MalformedResponse(err)))?;
+    let res = resp.parse(buffer).map_err(Error::MalformedResponse)?;
 
     if let httparse::Status::Complete(len) = res {
         let mut response = http::Response::builder()
@@ -82,7 +102,9 @@ fn parse_response(buffer: &[u8]) -> Result<Option<(http::Response<Vec<u8>>, usiz
 /// Returns Ok(http::Response) if a valid response is received, or Error if not.
 ///
 /// You will need to modify this function in Milestone 2.
-async fn read_headers(stream: &mut TcpStream) -> Result<http::Response<Vec<u8>>, Error> {
+async fn read_headers<S: AsyncRead + Unpin>(
+    stream: &mut S,
+) -> Result<http::Response<Vec<u8>>, Error> {
     // Try reading the headers from the response. We may not receive all the headers in one shot
     // (e.g. we might receive the first few bytes of a response, and then the rest follows later).
     // Try parsing repeatedly until we read a valid HTTP response
@@ -90,13 +112,15 @@ async fn read_headers(stream: &mut TcpStream) -> Result<http::Response<Vec<u8>>,
     let mut bytes_read = 0;
     loop {
         // Read bytes from the connection into the buffer, starting at position bytes_read
-        let new_bytes = stream
-            .read(&mut response_buffer[bytes_read..])
-            .await
-            .or_else(|err| Err(Error::ConnectionError(err)))?;
+        let new_bytes = match stream.read(&mut response_buffer[bytes_read..]).await {
+            Ok(new_bytes) => new_bytes,
+            // Resetting the connection before responding is just another way of hanging up
+            Err(err) if bytes_read == 0 && err.kind() == std::io::ErrorKind::ConnectionReset => 0,
+            Err(err) => return Err(Error::Connection(err)),
+        };
         if new_bytes == 0 {
             // We didn't manage to read a complete response
-            return Err(Error::IncompleteResponse);
+            return Err(Error::IncompleteResponse(bytes_read));
         }
         bytes_read += new_bytes;
 
@@ -113,25 +137,92 @@ async fn read_headers(stream: &mut TcpStream) -> Result<http::Response<Vec<u8>>,
     }
 }
 
-/// This function reads the body for a response from the stream. If the Content-Length header is
-/// present, it reads that many bytes; otherwise, it reads bytes until the connection is closed.
+/// Works out how the body of a response to a request with the given method is framed (RFC 7230,
+/// section 3.3.3).
+fn body_framing(
+    response: &http::Response<Vec<u8>>,
+    request_method: &http::Method,
+) -> Result<Framing, Error> {
+    // A response may have a body as long as it is not responding to a HEAD request and as long as
+    // the response status code is not 1xx, 204 (no content), or 304 (not modified).
+    if request_method == http::Method::HEAD
+        || response.status().as_u16() < 200
+        || response.status() == http::StatusCode::NO_CONTENT
+        || response.status() == http::StatusCode::NOT_MODIFIED
+    {
+        return Ok(Framing::Empty);
+    }
+    // Transfer-Encoding overrides Content-Length. If chunked isn't the final coding, the body
+    // runs until the server closes the connection.
+    if response.headers().contains_key("transfer-encoding") {
+        if chunked::is_chunked(response.headers()) {
+            return Ok(Framing::Chunked);
+        }
+        return Ok(Framing::UntilClose);
+    }
+    // The response may or may not supply a Content-Length header. If it provides the header, then
+    // the body is that number of bytes; if it does not, the body continues until the connection is
+    // closed.
+    match get_content_length(response)? {
+        Some(content_length) => Ok(Framing::ContentLength(content_length)),
+        None => Ok(Framing::UntilClose),
+    }
+}
+
+/// This function reads an HTTP response's headers from a stream, returning an Error if the server
+/// closes the connection prematurely or sends an invalid response. It also returns how the
+/// response body is framed; the caller can forward the body with body::copy. Any part of the body
+/// that was read along with the headers is left in the response body.
+pub async fn read_head<S: AsyncRead + Unpin>(
+    stream: &mut S,
+    request_method: &http::Method,
+) -> Result<(http::Response<Vec<u8>>, Framing), Error> {
+    let mut response = read_headers(stream).await?;
+    let framing = body_framing(&response, request_method)?;
+    if response.headers().contains_key("transfer-encoding") {
+        // We mustn't forward a Content-Length that doesn't describe how the body is actually
+        // framed
+        response.headers_mut().remove("content-length");
+    }
+    Ok((response, framing))
+}
+
+/// This function reads the body for a response from the stream. If the body is sent with chunked
+/// encoding, it is decoded until the last chunk (and the trailers are stored in the response's
+/// extensions, so that write_to_stream re-encodes it the same way). Otherwise, if the
+/// Content-Length header is present, it reads that many bytes; if not, it reads bytes until the
+/// connection is closed.
 ///
 /// You will need to modify this function in Milestone 2.
-async fn read_body(
-    stream: &mut TcpStream,
+pub async fn read_body<S: AsyncRead + Unpin>(
+    stream: &mut S,
     response: &mut http::Response<Vec<u8>>,
+    framing: Framing,
 ) -> Result<(), Error> {
-    // The response may or may not supply a Content-Length header. If it provides the header, then
-    // we want to read that number of bytes; if it does not, we want to keep reading bytes until
-    // the connection is closed.
-    let content_length = get_content_length(response)?;
+    let content_length = match framing {
+        Framing::Empty => return Ok(()),
+        Framing::Chunked => {
+            let trailers = chunked::read_body(stream, response.body_mut(), MAX_BODY_SIZE)
+                .await
+                .map_err(|err| match err {
+                    chunked::Error::MalformedChunk | chunked::Error::UnexpectedEof => {
+                        Error::MalformedChunkedBody
+                    }
+                    chunked::Error::BodyTooLarge => Error::ResponseBodyTooLarge,
+                    chunked::Error::Connection(io_err) | chunked::Error::Write(io_err) => {
+                        Error::Connection(io_err)
+                    }
+                })?;
+            response.extensions_mut().insert(trailers);
+            return Ok(());
+        }
+        Framing::ContentLength(content_length) => Some(content_length),
+        Framing::UntilClose => None,
+    };
 
     while content_length.is_none() || response.body().len() < content_length.unwrap() {
         let mut buffer = [0_u8; 512];
-        let bytes_read = stream
-            .read(&mut buffer)
-            .await
-            .or_else(|err| Err(Error::ConnectionError(err)))?;
+        let bytes_read = stream.read(&mut buffer).await.map_err(Error::Connection)?;
         if bytes_read == 0 {
             // The server has hung up!
             if content_length.is_none() {
@@ -165,44 +256,46 @@ async fn read_body(
 /// closes the connection prematurely or sends an invalid response.
 ///
 /// You will need to modify this function in Milestone 2.
-pub async fn read_from_stream(
-    stream: &mut TcpStream,
+pub async fn read_from_stream<S: AsyncRead + Unpin>(
+    stream: &mut S,
     request_method: &http::Method,
 ) -> Result<http::Response<Vec<u8>>, Error> {
-    let mut response = read_headers(stream).await?;
-    // A response may have a body as long as it is not responding to a HEAD request and as long as
-    // the response status code is not 1xx, 204 (no content), or 304 (not modified).
-    if !(request_method == http::Method::HEAD
-        || response.status().as_u16() < 200
-        || response.status() == http::StatusCode::NO_CONTENT
-        || response.status() == http::StatusCode::NOT_MODIFIED)
-    {
-        read_body(stream, &mut response).await?;
-    }
+    let (mut response, framing) = read_head(stream, request_method).await?;
+    read_body(stream, &mut response, framing).await?;
     Ok(response)
 }
 
+/// This function serializes the status line and headers of a response and writes them to the
+/// provided stream. The body is not written.
+pub async fn write_head<S: AsyncWrite + Unpin>(
+    response: &http::Response<Vec<u8>>,
+    stream: &mut S,
+) -> Result<(), std::io::Error> {
+    // Assemble the head in memory first so it goes out in one write instead of a packet per line
+    let mut head = format_response_line(response).into_bytes();
+    head.extend_from_slice(b"\r\n");
+    for (header_name, header_value) in response.headers() {
+        head.extend_from_slice(header_name.as_str().as_bytes());
+        head.extend_from_slice(b": ");
+        head.extend_from_slice(header_value.as_bytes());
+        head.extend_from_slice(b"\r\n");
+    }
+    head.extend_from_slice(b"\r\n");
+    stream.write_all(&head).await
+}
+
 /// This function serializes a response to bytes and writes those bytes to the provided stream.
 ///
 /// You will need to modify this function in Milestone 2.
-pub async fn write_to_stream(
+pub async fn write_to_stream<S: AsyncWrite + Unpin>(
     response: &http::Response<Vec<u8>>,
-    stream: &mut TcpStream,
+    stream: &mut S,
 ) -> Result<(), std::io::Error> {
-    stream
-        .write(&format_response_line(response).into_bytes())
-        .await?;
-    stream.write(&['\r' as u8, '\n' as u8]).await?; // \r\n
-    for (header_name, header_value) in response.headers() {
-        stream
-            .write(&format!("{}: ", header_name).as_bytes())
-            .await?;
-        stream.write(header_value.as_bytes()).await?;
-        stream.write(&['\r' as u8, '\n' as u8]).await?; // \r\n
-    }
-    stream.write(&['\r' as u8, '\n' as u8]).await?;
-    if response.body().len() > 0 {
-        stream.write(response.body()).await?;
+    write_head(response, stream).await?;
+    if let Some(trailers) = response.extensions().get::<chunked::Trailers>() {
+        chunked::write_body(stream, response.body(), trailers).await?;
+    } else if !response.body().is_empty() {
+        stream.write_all(response.body()).await?;
     }
     Ok(())
 }
@@ -225,11 +318,19 @@ pub fn make_http_error(status: http::StatusCode) -> http::Response<Vec<u8>> {
         status.canonical_reason().unwrap_or("")
     )
     .into_bytes();
-    http::Response::builder()
+    let mut response = http::Response::builder()
         .status(status)
         .header("Content-Type", "text/plain")
         .header("Content-Length", body.len().to_string())
         .version(http::Version::HTTP_11)
         .body(body)
-        .unwrap()
+        .unwrap();
+    // We only send a 503 when we're too busy, so the client may as well try again shortly
+    if status == http::StatusCode::SERVICE_UNAVAILABLE {
+        response.headers_mut().insert(
+            http::header::RETRY_AFTER,
+            http::HeaderValue::from(limits::RETRY_AFTER_SECS),
+        );
+    }
+    response
 }
@@ -0,0 +1,97 @@
+use std::time::{Duration, Instant};
+
+/// Retries are only worth attempting for request bodies we can afford to hold on to so that they
+/// can be sent again. Larger (or chunked) bodies are streamed straight through and never retried.
+pub const MAX_RETRYABLE_BODY_SIZE: usize = 64 * 1024;
+
+/// How long the retry budget counts requests and retries for before starting over
+const BUDGET_WINDOW: Duration = Duration::from_secs(10);
+
+/// Number of retries allowed in each budget window no matter how few requests there were, so that
+/// a quiet proxy can still retry the odd failure
+const MIN_RETRIES_PER_WINDOW: usize = 10;
+
+/// The request methods that are safe to send to a second upstream after a first one failed (i.e.
+/// idempotent methods). This is selected with the --retry-methods command-line option.
+#[derive(Clone, Debug)]
+pub struct RetryMethods(Vec<http::Method>);
+
+impl std::str::FromStr for RetryMethods {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<RetryMethods, String> {
+        s.split(',')
+            .map(|method| method.trim())
+            .filter(|method| !method.is_empty())
+            .map(|method| {
+                http::Method::from_bytes(method.to_uppercase().as_bytes())
+                    .map_err(|_| format!("invalid retry method \"{}\"", method))
+            })
+            .collect::<Result<Vec<_>, _>>()
+            .map(RetryMethods)
+    }
+}
+
+impl RetryMethods {
+    pub fn contains(&self, method: &http::Method) -> bool {
+        self.0.contains(method)
+    }
+}
+
+/// Caps retries at a fraction of the requests we have been handling, so that when the upstreams
+/// are struggling, retries don't pile even more load onto them.
+pub struct RetryBudget {
+    /// Maximum number of retries as a percentage of requests
+    percent: usize,
+    window_start: Instant,
+    requests: usize,
+    retries: usize,
+}
+
+impl RetryBudget {
+    pub fn new(percent: usize) -> RetryBudget {
+        RetryBudget {
+            percent,
+            window_start: Instant::now(),
+            requests: 0,
+            retries: 0,
+        }
+    }
+
+    fn roll_window(&mut self) {
+        if self.window_start.elapsed() >= BUDGET_WINDOW {
+            self.window_start = Instant::now();
+            self.requests = 0;
+            self.retries = 0;
+        }
+    }
+
+    /// Counts a request towards the budget.
+    pub fn record_request(&mut self) {
+        self.roll_window();
+        self.requests += 1;
+    }
+
+    /// Takes a retry out of the budget, returning false if it has run out.
+    pub fn try_spend(&mut self) -> bool {
+        self.roll_window();
+        let allowed = std::cmp::max(MIN_RETRIES_PER_WINDOW, self.requests * self.percent / 100);
+        if self.retries >= allowed {
+            return false;
+        }
+        self.retries += 1;
+        true
+    }
+}
+
+/// Decides whether, and how often, a failed request may be retried on another upstream
+pub struct RetryPolicy {
+    /// Methods that are safe to retry
+    pub methods: RetryMethods,
+    /// Maximum number of extra attempts after the first one fails
+    pub max_retries: usize,
+    /// How long to wait for an upstream to start responding before giving up on it
+    pub per_try_timeout: Duration,
+    /// Limits how many retries we make overall
+    pub budget: RetryBudget,
+}
@@ -0,0 +1,180 @@
+use regex::Regex;
+
+/// The pool that upstreams given with --upstream go into, and that requests matching no route are
+/// sent to
+pub const DEFAULT_POOL: &str = "default";
+
+/// A setting that applies to one pool of upstreams, written POOL=VALUE
+#[derive(Clone, Debug)]
+pub struct PoolSetting<T> {
+    pub pool: String,
+    pub value: T,
+}
+
+impl<T> std::str::FromStr for PoolSetting<T>
+where
+    T: std::str::FromStr,
+    T::Err: std::fmt::Display,
+{
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<PoolSetting<T>, String> {
+        let idx = s
+            .find('=')
+            .filter(|idx| *idx > 0)
+            .ok_or_else(|| format!("invalid pool setting \"{}\" (expected POOL=VALUE)", s))?;
+        let value = s[idx + 1..]
+            .parse::<T>()
+            .map_err(|err| format!("invalid pool setting \"{}\": {}", s, err))?;
+        Ok(PoolSetting {
+            pool: s[..idx].to_string(),
+            value,
+        })
+    }
+}
+
+/// One condition a request must meet for a route to apply
+#[derive(Clone, Debug)]
+enum Matcher {
+    /// The Host header names this host (the port is ignored). A leading "*." matches any
+    /// subdomain.
+    Host(String),
+    PathPrefix(String),
+    PathRegex(Regex),
+    Method(Vec<http::Method>),
+    HeaderEquals(http::header::HeaderName, String),
+    HeaderRegex(http::header::HeaderName, Regex),
+}
+
+impl std::str::FromStr for Matcher {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Matcher, String> {
+        let invalid = || format!("invalid route matcher \"{}\"", s);
+        let regex = |pattern: &str| {
+            Regex::new(pattern).map_err(|err| format!("invalid pattern in \"{}\": {}", s, err))
+        };
+        let header_name = |name: &str| {
+            http::header::HeaderName::from_bytes(name.as_bytes()).map_err(|_| invalid())
+        };
+        if let Some(host) = s.strip_prefix("host=") {
+            Ok(Matcher::Host(host.to_ascii_lowercase()))
+        } else if let Some(prefix) = s.strip_prefix("path=") {
+            Ok(Matcher::PathPrefix(prefix.to_string()))
+        } else if let Some(pattern) = s.strip_prefix("path~") {
+            Ok(Matcher::PathRegex(regex(pattern)?))
+        } else if let Some(methods) = s.strip_prefix("method=") {
+            methods
+                .split('|')
+                .map(|method| {
+                    http::Method::from_bytes(method.to_ascii_uppercase().as_bytes())
+                        .map_err(|_| invalid())
+                })
+                .collect::<Result<Vec<_>, _>>()
+                .map(Matcher::Method)
+        } else if let Some(header) = s.strip_prefix("header:") {
+            match header.find(['=', '~']) {
+                Some(idx) if &header[idx..=idx] == "=" => Ok(Matcher::HeaderEquals(
+                    header_name(&header[..idx])?,
+                    header[idx + 1..].to_string(),
+                )),
+                Some(idx) => Ok(Matcher::HeaderRegex(
+                    header_name(&header[..idx])?,
+                    regex(&header[idx + 1..])?,
+                )),
+                None => Err(invalid()),
+            }
+        } else {
+            Err(invalid())
+        }
+    }
+}
+
+impl Matcher {
+    fn matches(&self, request: &http::Request<Vec<u8>>) -> bool {
+        match self {
+            Matcher::Host(host) => match request_host(request) {
+                Some(request_host) => match host.strip_prefix("*.") {
+                    Some(domain) => request_host
+                        .strip_suffix(domain)
+                        .is_some_and(|subdomain| subdomain.ends_with('.')),
+                    None => request_host == *host,
+                },
+                None => false,
+            },
+            Matcher::PathPrefix(prefix) => request.uri().path().starts_with(prefix.as_str()),
+            Matcher::PathRegex(pattern) => pattern.is_match(request.uri().path()),
+            Matcher::Method(methods) => methods.contains(request.method()),
+            Matcher::HeaderEquals(name, value) => request
+                .headers()
+                .get_all(name)
+                .iter()
+                .any(|header| header.as_bytes() == value.as_bytes()),
+            Matcher::HeaderRegex(name, pattern) => request
+                .headers()
+                .get_all(name)
+                .iter()
+                .filter_map(|header| header.to_str().ok())
+                .any(|header| pattern.is_match(header)),
+        }
+    }
+}
+
+/// Returns the host a request is for, lowercased and without the port.
+fn request_host(request: &http::Request<Vec<u8>>) -> Option<String> {
+    let host = match request.headers().get(http::header::HOST) {
+        Some(host) => host.to_str().ok()?,
+        None => request.uri().host()?,
+    };
+    // Leave IPv6 addresses in brackets alone
+    let host = match host.rfind(':') {
+        Some(idx) if !host[idx..].contains(']') => &host[..idx],
+        _ => host,
+    };
+    Some(host.to_ascii_lowercase())
+}
+
+/// Sends the requests matching all of its matchers to a pool of upstreams
+#[derive(Clone, Debug)]
+pub struct Route {
+    matchers: Vec<Matcher>,
+    pub pool: String,
+}
+
+impl std::str::FromStr for Route {
+    type Err = String;
+
+    /// Parses a route of the form "MATCHER... => POOL". A route without any matchers matches every
+    /// request.
+    fn from_str(s: &str) -> Result<Route, String> {
+        let idx = s
+            .rfind("=>")
+            .ok_or_else(|| format!("invalid route \"{}\" (expected MATCHER... => POOL)", s))?;
+        let pool = s[idx + 2..].trim();
+        if pool.is_empty() {
+            return Err(format!("route \"{}\" is missing a pool", s));
+        }
+        Ok(Route {
+            matchers: s[..idx]
+                .split_whitespace()
+                .map(|matcher| matcher.parse())
+                .collect::<Result<_, _>>()?,
+            pool: pool.to_string(),
+        })
+    }
+}
+
+impl Route {
+    fn matches(&self, request: &http::Request<Vec<u8>>) -> bool {
+        self.matchers.iter().all(|matcher| matcher.matches(request))
+    }
+}
+
+/// Returns the pool a request should go to: that of the first route it matches, or the default
+/// pool if it matches none.
+pub fn route<'a>(routes: &'a [Route], request: &http::Request<Vec<u8>>) -> &'a str {
+    routes
+        .iter()
+        .find(|route| route.matches(request))
+        .map_or(DEFAULT_POOL, |route| route.pool.as_str())
+}
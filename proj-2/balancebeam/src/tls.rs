@@ -0,0 +1,407 @@
+use std::collections::HashMap;
+use std::io::BufReader;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite};
+use tokio::net::TcpStream;
+use tokio_rustls::rustls::internal::pemfile;
+use tokio_rustls::rustls::sign::{self, CertifiedKey};
+use tokio_rustls::rustls::{
+    Certificate, ClientConfig, ClientHello, NoClientAuth, PrivateKey, ResolvesServerCert,
+    ServerConfig, Session,
+};
+use tokio_rustls::webpki::DNSNameRef;
+use tokio_rustls::{client, server, TlsAcceptor, TlsConnector};
+
+/// Where CA certificates are usually found, for verifying upstreams when no --upstream-tls-ca is
+/// given
+const SYSTEM_CA_BUNDLE: &str = "/etc/ssl/certs/ca-certificates.crt";
+
+/// Application protocols we offer clients through ALPN, most preferred first
+const SERVER_ALPN_PROTOCOLS: [&[u8]; 2] = [b"h2", b"http/1.1"];
+
+/// Application protocols we offer upstreams through ALPN. Requests always go to upstreams as
+/// HTTP/1.1, whatever the client spoke.
+const UPSTREAM_ALPN_PROTOCOLS: [&[u8]; 1] = [b"http/1.1"];
+
+/// A certificate to serve, as given to --tls-certificate
+#[derive(Clone, Debug)]
+pub struct CertificateSpec {
+    /// The hostname clients ask for (through SNI) to get this certificate, or None for the
+    /// certificate served when no other one matches
+    pub hostname: Option<String>,
+    pub cert_path: String,
+    pub key_path: String,
+}
+
+impl std::str::FromStr for CertificateSpec {
+    type Err = String;
+
+    /// Parses a certificate of the form [HOSTNAME=]CERT_FILE:KEY_FILE.
+    fn from_str(s: &str) -> Result<CertificateSpec, String> {
+        let (hostname, files) = match s.find('=') {
+            Some(idx) => (Some(s[..idx].to_ascii_lowercase()), &s[idx + 1..]),
+            None => (None, s),
+        };
+        let idx = files.rfind(':').ok_or_else(|| {
+            format!(
+                "invalid certificate \"{}\" (expected [HOSTNAME=]CERT_FILE:KEY_FILE)",
+                s
+            )
+        })?;
+        Ok(CertificateSpec {
+            hostname,
+            cert_path: files[..idx].to_string(),
+            key_path: files[idx + 1..].to_string(),
+        })
+    }
+}
+
+/// Picks the certificate to serve from the hostname the client sent with SNI, falling back to the
+/// default certificate (if there is one) for other hostnames and for clients that don't send any
+struct CertificateResolver {
+    by_hostname: HashMap<String, CertifiedKey>,
+    default: Option<CertifiedKey>,
+}
+
+impl ResolvesServerCert for CertificateResolver {
+    fn resolve(&self, client_hello: ClientHello) -> Option<CertifiedKey> {
+        client_hello
+            .server_name()
+            .and_then(|name| {
+                let name: &str = name.into();
+                self.by_hostname.get(&name.to_ascii_lowercase())
+            })
+            .or(self.default.as_ref())
+            .cloned()
+    }
+}
+
+/// Loads the given certificates and their private keys, returning an acceptor that terminates TLS
+/// with them.
+pub fn new_acceptor(certificates: &[CertificateSpec]) -> Result<TlsAcceptor, String> {
+    let mut resolver = CertificateResolver {
+        by_hostname: HashMap::new(),
+        default: None,
+    };
+    for spec in certificates {
+        let key = load_certified_key(spec)?;
+        let previous = match &spec.hostname {
+            Some(hostname) => resolver.by_hostname.insert(hostname.clone(), key),
+            None => resolver.default.replace(key),
+        };
+        if previous.is_some() {
+            return Err(format!(
+                "More than one certificate given for {}",
+                spec.hostname.as_deref().unwrap_or("the default hostname")
+            ));
+        }
+    }
+    let mut config = ServerConfig::new(NoClientAuth::new());
+    config.cert_resolver = Arc::new(resolver);
+    config.set_protocols(
+        &SERVER_ALPN_PROTOCOLS
+            .iter()
+            .map(|protocol| protocol.to_vec())
+            .collect::<Vec<_>>(),
+    );
+    Ok(TlsAcceptor::from(Arc::new(config)))
+}
+
+fn load_certified_key(spec: &CertificateSpec) -> Result<CertifiedKey, String> {
+    let (cert, key) = load_cert_and_key(spec)?;
+    let key = sign::any_supported_type(&key)
+        .map_err(|_| format!("Unsupported private key in {}", spec.key_path))?;
+    Ok(CertifiedKey::new(cert, Arc::new(key)))
+}
+
+fn load_cert_and_key(spec: &CertificateSpec) -> Result<(Vec<Certificate>, PrivateKey), String> {
+    let open = |path: &str| {
+        std::fs::File::open(path)
+            .map(BufReader::new)
+            .map_err(|err| format!("Could not read {}: {}", path, err))
+    };
+    let cert = pemfile::certs(&mut open(&spec.cert_path)?)
+        .ok()
+        .filter(|cert| !cert.is_empty())
+        .ok_or_else(|| format!("No certificates found in {}", spec.cert_path))?;
+    let mut keys = pemfile::pkcs8_private_keys(&mut open(&spec.key_path)?).unwrap_or_default();
+    if keys.is_empty() {
+        keys = pemfile::rsa_private_keys(&mut open(&spec.key_path)?).unwrap_or_default();
+    }
+    let key = keys
+        .into_iter()
+        .next()
+        .ok_or_else(|| format!("No private key found in {}", spec.key_path))?;
+    Ok((cert, key))
+}
+
+/// Splits an upstream address into whether it is reached over TLS (i.e. it was written
+/// https://HOST:PORT) and the HOST:PORT to connect to.
+pub fn parse_upstream_address(address: &str) -> Result<(bool, &str), String> {
+    if let Some(host_port) = address.strip_prefix("https://") {
+        let host = host_port
+            .rfind(':')
+            .map(|idx| &host_port[..idx])
+            .ok_or_else(|| format!("upstream \"{}\" is missing a port", address))?;
+        // Certificates are verified against the hostname, so there has to be one
+        DNSNameRef::try_from_ascii_str(host).map_err(|_| {
+            format!(
+                "upstream \"{}\" must be given by hostname to be verified over TLS",
+                address
+            )
+        })?;
+        Ok((true, host_port))
+    } else if address.contains("://") {
+        Err(format!(
+            "unsupported scheme in upstream \"{}\" (only https:// is supported)",
+            address
+        ))
+    } else {
+        Ok((false, address))
+    }
+}
+
+/// Opens connections to upstreams, over TLS for those written https://HOST:PORT
+pub struct UpstreamConnector {
+    tls: TlsConnector,
+}
+
+impl UpstreamConnector {
+    /// Sets up TLS to upstreams. Their certificates are verified against the CAs in `ca_file`
+    /// (or the system's CAs, if not given), and we present `client_certificate` to upstreams that
+    /// ask for one.
+    pub fn new(
+        ca_file: Option<&str>,
+        client_certificate: Option<&CertificateSpec>,
+    ) -> Result<UpstreamConnector, String> {
+        let mut config = ClientConfig::new();
+        let ca_file = match ca_file {
+            Some(ca_file) => Some(ca_file),
+            None if std::path::Path::new(SYSTEM_CA_BUNDLE).exists() => Some(SYSTEM_CA_BUNDLE),
+            None => None,
+        };
+        if let Some(ca_file) = ca_file {
+            let mut reader = std::fs::File::open(ca_file)
+                .map(BufReader::new)
+                .map_err(|err| format!("Could not read {}: {}", ca_file, err))?;
+            match config.root_store.add_pem_file(&mut reader) {
+                Ok((valid, _)) if valid > 0 => {}
+                _ => return Err(format!("No CA certificates found in {}", ca_file)),
+            }
+        }
+        if let Some(spec) = client_certificate {
+            if spec.hostname.is_some() {
+                return Err("The upstream client certificate can't name a hostname".to_string());
+            }
+            let (cert, key) = load_cert_and_key(spec)?;
+            config
+                .set_single_client_cert(cert, key)
+                .map_err(|err| format!("Invalid client certificate {}: {}", spec.cert_path, err))?;
+        }
+        config.set_protocols(
+            &UPSTREAM_ALPN_PROTOCOLS
+                .iter()
+                .map(|protocol| protocol.to_vec())
+                .collect::<Vec<_>>(),
+        );
+        Ok(UpstreamConnector {
+            tls: TlsConnector::from(Arc::new(config)),
+        })
+    }
+
+    /// Connects to the upstream at `address`, and if it is an https:// upstream, performs the TLS
+    /// handshake, verifying that the upstream's certificate is valid for its hostname.
+    pub async fn connect(&self, address: &str) -> std::io::Result<UpstreamStream> {
+        let (use_tls, host_port) = parse_upstream_address(address)
+            .map_err(|err| std::io::Error::new(std::io::ErrorKind::InvalidInput, err))?;
+        let stream = TcpStream::connect(host_port).await?;
+        if !use_tls {
+            return Ok(Transport::Plain(stream));
+        }
+        let host = &host_port[..host_port.rfind(':').unwrap()];
+        let hostname = DNSNameRef::try_from_ascii_str(host).unwrap();
+        let stream = self.tls.connect(hostname, stream).await?;
+        Ok(Transport::Tls(Box::new(stream)))
+    }
+}
+
+/// A connection to an upstream
+pub type UpstreamStream = Transport<client::TlsStream<TcpStream>>;
+
+impl UpstreamStream {
+    /// Waits until the upstream closes a connection with no request outstanding, or (unexpectedly)
+    /// sends something on it. With TLS, this has to read through the TLS layer, since records
+    /// that carry no data for us (e.g. TLS 1.3 session tickets) can arrive at any time; anything
+    /// that is read is lost, so the connection shouldn't be used afterwards.
+    pub async fn closed(&mut self) -> std::io::Result<()> {
+        let mut buf = [0_u8; 1];
+        match self {
+            Transport::Plain(stream) => stream.peek(&mut buf).await.map(|_| ()),
+            Transport::Tls(stream) => stream.read(&mut buf).await.map(|_| ()),
+        }
+    }
+}
+
+/// A TCP connection, either used as it is or with TLS on top
+pub enum Transport<T> {
+    Plain(TcpStream),
+    Tls(Box<T>),
+}
+
+impl<T: AsyncRead + Unpin> AsyncRead for Transport<T> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<std::io::Result<usize>> {
+        match self.get_mut() {
+            Transport::Plain(stream) => Pin::new(stream).poll_read(cx, buf),
+            Transport::Tls(stream) => Pin::new(stream.as_mut()).poll_read(cx, buf),
+        }
+    }
+}
+
+impl<T: AsyncWrite + Unpin> AsyncWrite for Transport<T> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        match self.get_mut() {
+            Transport::Plain(stream) => Pin::new(stream).poll_write(cx, buf),
+            Transport::Tls(stream) => Pin::new(stream.as_mut()).poll_write(cx, buf),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        match self.get_mut() {
+            Transport::Plain(stream) => Pin::new(stream).poll_flush(cx),
+            Transport::Tls(stream) => Pin::new(stream.as_mut()).poll_flush(cx),
+        }
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        match self.get_mut() {
+            Transport::Plain(stream) => Pin::new(stream).poll_shutdown(cx),
+            Transport::Tls(stream) => Pin::new(stream.as_mut()).poll_shutdown(cx),
+        }
+    }
+}
+
+/// A connection from a client, either in plain text or over TLS. Requests and responses are read
+/// and written through it the same way in both cases.
+pub struct ClientStream {
+    transport: Transport<server::TlsStream<TcpStream>>,
+    peer_addr: SocketAddr,
+    /// Bytes read by wait_for_data or starts_with that haven't been handed out yet
+    buffered: Vec<u8>,
+}
+
+impl ClientStream {
+    pub fn plain(stream: TcpStream, peer_addr: SocketAddr) -> ClientStream {
+        ClientStream {
+            transport: Transport::Plain(stream),
+            peer_addr,
+            buffered: Vec::new(),
+        }
+    }
+
+    /// Performs the TLS handshake with a client that has just connected.
+    pub async fn accept(
+        stream: TcpStream,
+        peer_addr: SocketAddr,
+        acceptor: &TlsAcceptor,
+    ) -> std::io::Result<ClientStream> {
+        let stream = acceptor.accept(stream).await?;
+        Ok(ClientStream {
+            transport: Transport::Tls(Box::new(stream)),
+            peer_addr,
+            buffered: Vec::new(),
+        })
+    }
+
+    pub fn peer_addr(&self) -> SocketAddr {
+        self.peer_addr
+    }
+
+    pub fn is_tls(&self) -> bool {
+        matches!(self.transport, Transport::Tls(_))
+    }
+
+    /// Returns the application protocol agreed on with ALPN during the TLS handshake, if any.
+    pub fn alpn_protocol(&self) -> Option<&[u8]> {
+        match &self.transport {
+            Transport::Plain(_) => None,
+            Transport::Tls(stream) => stream.get_ref().1.get_alpn_protocol(),
+        }
+    }
+
+    /// Reads just enough of what the client sent to tell whether it starts with `prefix`. What was
+    /// read is still handed out by the next reads, and dropping the future before it completes
+    /// loses nothing.
+    pub async fn starts_with(&mut self, prefix: &[u8]) -> bool {
+        while self.buffered.len() < prefix.len() {
+            if !prefix.starts_with(&self.buffered) {
+                return false;
+            }
+            let mut chunk = vec![0_u8; prefix.len() - self.buffered.len()];
+            match self.transport.read(&mut chunk).await {
+                Ok(0) | Err(_) => return false,
+                Ok(bytes_read) => self.buffered.extend_from_slice(&chunk[..bytes_read]),
+            }
+        }
+        self.buffered.starts_with(prefix)
+    }
+
+    /// Waits until the client sends something (or hangs up), without losing what it sent: the
+    /// next read starts with it. Dropping the future before it completes loses nothing either.
+    pub async fn wait_for_data(&mut self) {
+        if !self.buffered.is_empty() {
+            return;
+        }
+        // With TLS we can't just peek at the socket, since the next record may already have been
+        // read and decrypted
+        let mut byte = [0_u8; 1];
+        if let Ok(1) = self.transport.read(&mut byte).await {
+            self.buffered.push(byte[0]);
+        }
+    }
+}
+
+impl AsyncRead for ClientStream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<std::io::Result<usize>> {
+        let this = self.get_mut();
+        if this.buffered.is_empty() {
+            return Pin::new(&mut this.transport).poll_read(cx, buf);
+        }
+        let len = this.buffered.len().min(buf.len());
+        buf[..len].copy_from_slice(&this.buffered[..len]);
+        this.buffered.drain(..len);
+        Poll::Ready(Ok(len))
+    }
+}
+
+impl AsyncWrite for ClientStream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        Pin::new(&mut self.get_mut().transport).poll_write(cx, buf)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.get_mut().transport).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.get_mut().transport).poll_shutdown(cx)
+    }
+}
@@ -0,0 +1,82 @@
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::time;
+
+const BUFFER_SIZE: usize = 16 * 1024;
+
+/// How a tunnel came to an end
+#[derive(Debug)]
+pub enum Ending {
+    ClientClosed,
+    UpstreamClosed,
+    /// Nothing was sent either way for the idle timeout
+    Idle,
+    Error(std::io::Error),
+}
+
+/// Number of bytes relayed in each direction so far
+#[derive(Debug, Default)]
+pub struct Transferred {
+    pub from_client: u64,
+    pub from_upstream: u64,
+}
+
+enum Read {
+    Client(std::io::Result<usize>),
+    Upstream(std::io::Result<usize>),
+}
+
+/// Relays bytes both ways between a client and an upstream that have switched to another protocol
+/// (e.g. WebSocket) after a 101 response, until either side closes its connection or nothing has
+/// been sent for `idle_timeout`. When one side closes, the other is shut down too. `transferred`
+/// is kept up to date as bytes go through, so it is still accurate if the relay is cancelled.
+pub async fn relay<C, U>(
+    client: &mut C,
+    upstream: &mut U,
+    idle_timeout: Duration,
+    transferred: &mut Transferred,
+) -> Ending
+where
+    C: AsyncRead + AsyncWrite + Unpin,
+    U: AsyncRead + AsyncWrite + Unpin,
+{
+    let mut client_buffer = vec![0_u8; BUFFER_SIZE];
+    let mut upstream_buffer = vec![0_u8; BUFFER_SIZE];
+    loop {
+        // Relay whatever arrives first. The write is part of the step, so that a peer that stops
+        // reading also counts as idle.
+        let step = async {
+            let read = tokio::select! {
+                read = client.read(&mut client_buffer) => Read::Client(read),
+                read = upstream.read(&mut upstream_buffer) => Read::Upstream(read),
+            };
+            match read {
+                Read::Client(Ok(0)) => {
+                    let _ = upstream.shutdown().await;
+                    Err(Ending::ClientClosed)
+                }
+                Read::Upstream(Ok(0)) => {
+                    let _ = client.shutdown().await;
+                    Err(Ending::UpstreamClosed)
+                }
+                Read::Client(Ok(len)) => match upstream.write_all(&client_buffer[..len]).await {
+                    Ok(()) => Ok((len as u64, 0)),
+                    Err(err) => Err(Ending::Error(err)),
+                },
+                Read::Upstream(Ok(len)) => match client.write_all(&upstream_buffer[..len]).await {
+                    Ok(()) => Ok((0, len as u64)),
+                    Err(err) => Err(Ending::Error(err)),
+                },
+                Read::Client(Err(err)) | Read::Upstream(Err(err)) => Err(Ending::Error(err)),
+            }
+        };
+        match time::timeout(idle_timeout, step).await {
+            Ok(Ok((from_client, from_upstream))) => {
+                transferred.from_client += from_client;
+                transferred.from_upstream += from_upstream;
+            }
+            Ok(Err(ending)) => return ending,
+            Err(_elapsed) => return Ending::Idle,
+        }
+    }
+}
@@ -2,6 +2,12 @@ mod common;
 
 use common::{init_logging, BalanceBeam, EchoServer, Server};
 use std::sync::Arc;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio_rustls::rustls::internal::pemfile;
+use tokio_rustls::rustls::{ClientConfig, NoClientAuth, ServerConfig, Session};
+use tokio_rustls::webpki::DNSNameRef;
+use tokio_rustls::{TlsAcceptor, TlsConnector};
 
 async fn setup() -> (BalanceBeam, EchoServer) {
     init_logging();
@@ -99,3 +105,1140 @@ async fn test_multiple_requests_per_connection() {
 
     log::info!("All done :)");
 }
+
+/// Reads one HTTP response (with a Content-Length body) off the stream, returning its status code
+/// and body
+async fn read_response<S: AsyncRead + Unpin>(stream: &mut S) -> (u16, String) {
+    let mut buffer = Vec::new();
+    loop {
+        let mut chunk = [0_u8; 512];
+        let bytes_read = stream
+            .read(&mut chunk)
+            .await
+            .expect("Error reading response from balancebeam");
+        assert!(
+            bytes_read > 0,
+            "balancebeam hung up before sending a response"
+        );
+        buffer.extend_from_slice(&chunk[..bytes_read]);
+
+        let mut headers = [httparse::EMPTY_HEADER; 32];
+        let mut response = httparse::Response::new(&mut headers);
+        if let httparse::Status::Complete(headers_len) = response
+            .parse(&buffer)
+            .expect("balancebeam sent a malformed response")
+        {
+            let content_length: usize = response
+                .headers
+                .iter()
+                .find(|header| header.name.eq_ignore_ascii_case("content-length"))
+                .map(|header| std::str::from_utf8(header.value).unwrap().parse().unwrap())
+                .expect("Response is missing Content-Length");
+            if buffer.len() >= headers_len + content_length {
+                let body = &buffer[headers_len..headers_len + content_length];
+                return (
+                    response.code.unwrap(),
+                    String::from_utf8_lossy(body).to_string(),
+                );
+            }
+        }
+    }
+}
+
+/// Send a request with a chunked body, followed by a second request on the same connection, and
+/// make sure the upstream receives the whole body and the connection stays usable. (We can't send
+/// trailers here, since the hyper version our test upstream uses drops connections that send
+/// request trailers.)
+#[tokio::test]
+async fn test_chunked_request() {
+    let (balancebeam, upstream) = setup().await;
+
+    let mut stream = TcpStream::connect(&balancebeam.address)
+        .await
+        .expect("Failed to connect to balancebeam");
+    log::info!("Sending a POST request with a chunked body");
+    stream
+        .write_all(
+            b"POST /post-chunked HTTP/1.1\r\n\
+            Host: localhost\r\n\
+            Transfer-Encoding: chunked\r\n\
+            \r\n\
+            5\r\nHello\r\n\
+            7;some-extension=1\r\n world!\r\n\
+            0\r\n\
+            \r\n",
+        )
+        .await
+        .expect("Failed to send request to balancebeam");
+    let (status, body) = read_response(&mut stream).await;
+    assert_eq!(status, 200);
+    assert!(body.contains("POST /post-chunked HTTP/1.1"));
+    assert!(body.contains("\n\nHello world!"));
+
+    log::info!("Sending a second request on the same connection");
+    stream
+        .write_all(b"GET /after-chunked HTTP/1.1\r\nHost: localhost\r\n\r\n")
+        .await
+        .expect("Failed to send request to balancebeam");
+    let (status, body) = read_response(&mut stream).await;
+    assert_eq!(status, 200);
+    assert!(body.contains("GET /after-chunked HTTP/1.1"));
+
+    log::info!("Checking that the origin server received 2 requests");
+    drop(stream);
+    let num_requests_received = Box::new(upstream).stop().await;
+    assert_eq!(
+        num_requests_received, 2,
+        "Upstream server did not receive the expected number of requests"
+    );
+
+    log::info!("All done :)");
+}
+
+/// Trailers after a chunked body are passed along, both on requests and on responses. The
+/// upstream is a bare TCP listener, since hyper drops connections that send request trailers.
+#[tokio::test]
+async fn test_chunked_trailers() {
+    init_logging();
+    let mut listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let upstream_address = listener.local_addr().unwrap().to_string();
+    let (sender, received) = tokio::sync::oneshot::channel();
+    tokio::spawn(async move {
+        let (mut conn, _) = listener.accept().await.unwrap();
+        let mut request = Vec::new();
+        while !String::from_utf8_lossy(&request).contains("x-checksum: abc\r\n\r\n") {
+            let mut chunk = [0_u8; 512];
+            let bytes_read = conn.read(&mut chunk).await.unwrap();
+            assert!(
+                bytes_read > 0,
+                "balancebeam hung up before sending the trailers"
+            );
+            request.extend_from_slice(&chunk[..bytes_read]);
+        }
+        conn.write_all(
+            b"HTTP/1.1 200 OK\r\n\
+            Transfer-Encoding: chunked\r\n\
+            Trailer: X-Upstream-Checksum\r\n\
+            \r\n\
+            2\r\nok\r\n\
+            0\r\n\
+            X-Upstream-Checksum: def\r\n\
+            \r\n",
+        )
+        .await
+        .unwrap();
+        let _ = sender.send(String::from_utf8_lossy(&request).to_string());
+    });
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&upstream_address],
+        &["--active-health-check-interval", "60"],
+    )
+    .await;
+
+    let mut stream = TcpStream::connect(&balancebeam.address).await.unwrap();
+    stream
+        .write_all(
+            b"POST /trailers HTTP/1.1\r\n\
+            Host: localhost\r\n\
+            Transfer-Encoding: chunked\r\n\
+            Trailer: X-Checksum\r\n\
+            \r\n\
+            5\r\nHello\r\n\
+            0\r\n\
+            X-Checksum: abc\r\n\
+            \r\n",
+        )
+        .await
+        .unwrap();
+    let mut response = Vec::new();
+    let read_response = async {
+        while !String::from_utf8_lossy(&response)
+            .to_lowercase()
+            .ends_with("x-upstream-checksum: def\r\n\r\n")
+        {
+            let mut chunk = [0_u8; 512];
+            let bytes_read = stream.read(&mut chunk).await.unwrap();
+            assert!(
+                bytes_read > 0,
+                "balancebeam hung up before sending the trailers"
+            );
+            response.extend_from_slice(&chunk[..bytes_read]);
+        }
+    };
+    tokio::time::timeout(std::time::Duration::from_secs(5), read_response)
+        .await
+        .expect("The response trailers didn't reach the client");
+    let response = String::from_utf8_lossy(&response).to_lowercase();
+    assert!(response.starts_with("http/1.1 200"));
+    assert!(
+        response.contains("\r\n\r\n2\r\nok\r\n0\r\nx-upstream-checksum: def\r\n\r\n"),
+        "The response trailers didn't reach the client: {:?}",
+        response
+    );
+    let request = received.await.unwrap();
+    assert!(
+        request.contains("\r\n\r\n5\r\nHello\r\n0\r\nx-checksum: abc\r\n\r\n"),
+        "The request trailers didn't reach the upstream: {:?}",
+        request
+    );
+    log::info!("All done :)");
+}
+
+/// Requests whose body could be framed differently by an upstream than by us are turned away,
+/// since the upstream could take part of the body for a request of its own
+#[tokio::test]
+async fn test_ambiguous_framing_is_rejected() {
+    let (balancebeam, upstream) = setup().await;
+    let requests: [&[u8]; 3] = [
+        b"POST / HTTP/1.1\r\nHost: localhost\r\nContent-Length: 3\r\nContent-Length: 5\r\n\r\nabc",
+        b"POST / HTTP/1.1\r\nHost: localhost\r\nContent-Length: +3\r\n\r\nabc",
+        b"POST / HTTP/1.1\r\nHost: localhost\r\nTransfer-Encoding: chunked\r\n\r\n\
+        +3\r\nabc\r\n0\r\n\r\n",
+    ];
+    for request in requests.iter() {
+        let mut stream = TcpStream::connect(&balancebeam.address).await.unwrap();
+        stream.write_all(request).await.unwrap();
+        assert_eq!(
+            read_response(&mut stream).await.0,
+            400,
+            "{:?} should have been rejected",
+            String::from_utf8_lossy(request)
+        );
+    }
+
+    log::info!("Repeating the same Content-Length is fine");
+    let mut stream = TcpStream::connect(&balancebeam.address).await.unwrap();
+    stream
+        .write_all(
+            b"POST / HTTP/1.1\r\nHost: localhost\r\nContent-Length: 3\r\n\
+            Content-Length: 3\r\n\r\nabc",
+        )
+        .await
+        .unwrap();
+    let (status, body) = read_response(&mut stream).await;
+    assert_eq!(status, 200);
+    assert!(body.contains("content-length: 3\n"));
+    assert!(body.ends_with("\n\nabc"));
+
+    // The chunked request was sent on to the upstream before its body turned out to be malformed
+    assert_eq!(Box::new(upstream).stop().await, 2);
+    log::info!("All done :)");
+}
+
+/// An upstream can close an idle keep-alive connection just as we send a request on it. The
+/// request (even a POST, which isn't retried on another upstream) should then be sent again on a
+/// new connection instead of failing.
+#[tokio::test]
+async fn test_closed_pooled_connection() {
+    init_logging();
+    let mut listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let upstream_address = listener.local_addr().unwrap().to_string();
+    let requests_received = Arc::new(std::sync::atomic::AtomicUsize::new(0));
+    let counter = requests_received.clone();
+    tokio::spawn(async move {
+        loop {
+            let (mut conn, _) = listener.accept().await.unwrap();
+            let counter = counter.clone();
+            // Answer the first request on each connection, then hang up on the next one, as if
+            // the connection had timed out while idle
+            tokio::spawn(async move {
+                for answer in [true, false].iter() {
+                    let mut request = Vec::new();
+                    while !request.ends_with(b"\r\n\r\nhello") {
+                        let mut chunk = [0_u8; 512];
+                        let bytes_read = conn.read(&mut chunk).await.unwrap();
+                        if bytes_read == 0 {
+                            return;
+                        }
+                        request.extend_from_slice(&chunk[..bytes_read]);
+                    }
+                    counter.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+                    if *answer {
+                        conn.write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 2\r\n\r\nok")
+                            .await
+                            .unwrap();
+                    }
+                }
+            });
+        }
+    });
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&upstream_address],
+        &["--active-health-check-interval", "60"],
+    )
+    .await;
+
+    let mut stream = TcpStream::connect(&balancebeam.address).await.unwrap();
+    for _ in 0..2 {
+        stream
+            .write_all(b"POST / HTTP/1.1\r\nHost: localhost\r\nContent-Length: 5\r\n\r\nhello")
+            .await
+            .unwrap();
+        assert_eq!(read_response(&mut stream).await, (200, "ok".to_string()));
+    }
+    // The second request was sent on the pooled connection first, then on a new one
+    assert_eq!(
+        requests_received.load(std::sync::atomic::Ordering::SeqCst),
+        3
+    );
+    log::info!("All done :)");
+}
+
+/// Make sure chunked responses from the upstream are relayed intact, and that keep-alive
+/// connections keep working afterwards
+#[tokio::test]
+async fn test_chunked_response() {
+    let (balancebeam, upstream) = setup().await;
+
+    let client = reqwest::Client::new();
+    for i in 0..3 {
+        log::info!("Requesting chunked response #{}", i);
+        let path = format!("/chunked-response-{}", i);
+        let response = client
+            .get(&format!("http://{}{}", balancebeam.address, path))
+            .header("x-sent-by", "balancebeam-tests")
+            .send()
+            .await
+            .expect("Error sending request to balancebeam");
+        assert_eq!(
+            response
+                .headers()
+                .get("transfer-encoding")
+                .map(|value| value.to_str().unwrap()),
+            Some("chunked")
+        );
+        let response_text = response
+            .text()
+            .await
+            .expect("Balancebeam replied with a malformed response");
+        assert!(response_text.contains(&format!("GET {} HTTP/1.1", path)));
+        assert!(response_text.contains("x-sent-by: balancebeam-tests"));
+    }
+
+    let num_requests_received = Box::new(upstream).stop().await;
+    assert_eq!(
+        num_requests_received, 3,
+        "Upstream server did not receive the expected number of requests"
+    );
+
+    log::info!("All done :)");
+}
+
+/// Bodies are streamed through rather than buffered, so there is no limit on how big they can be.
+/// Send a body bigger than the 10MB we used to buffer, and make sure it gets there intact.
+#[tokio::test]
+async fn test_large_body() {
+    let (balancebeam, upstream) = setup().await;
+
+    let body_size = 12 * 1024 * 1024;
+    let body: String = (0..body_size)
+        .map(|i| (b'a' + (i % 26) as u8) as char)
+        .collect();
+    log::info!("Sending a POST request with a {} byte body", body_size);
+    let response_text = balancebeam
+        .post("/large-body", &body)
+        .await
+        .expect("Error sending request to balancebeam");
+    assert!(response_text.contains("POST /large-body HTTP/1.1"));
+    assert!(
+        response_text.ends_with(&format!("\n\n{}", body)),
+        "The upstream did not receive the whole body"
+    );
+
+    let num_requests_received = Box::new(upstream).stop().await;
+    assert_eq!(
+        num_requests_received, 1,
+        "Upstream server did not receive the expected number of requests"
+    );
+
+    log::info!("All done :)");
+}
+
+/// On SIGTERM, a request that is already being sent is still answered (with Connection: close),
+/// idle keep-alive connections are closed, and balancebeam then exits cleanly
+#[tokio::test]
+async fn test_graceful_shutdown() {
+    let (mut balancebeam, upstream) = setup().await;
+
+    let mut idle_conn = TcpStream::connect(&balancebeam.address)
+        .await
+        .expect("Could not connect to balancebeam");
+    idle_conn
+        .write_all(b"GET /first HTTP/1.1\r\nHost: balancebeam\r\n\r\n")
+        .await
+        .unwrap();
+    assert_eq!(read_response(&mut idle_conn).await.0, 200);
+
+    let mut busy_conn = TcpStream::connect(&balancebeam.address)
+        .await
+        .expect("Could not connect to balancebeam");
+    busy_conn
+        .write_all(b"GET /second HTTP/1.1\r\n")
+        .await
+        .unwrap();
+    tokio::time::delay_for(std::time::Duration::from_millis(200)).await;
+
+    log::info!("Sending SIGTERM");
+    balancebeam.send_signal(nix::sys::signal::Signal::SIGTERM);
+    tokio::time::delay_for(std::time::Duration::from_millis(200)).await;
+    let mut buf = [0_u8; 1];
+    assert_eq!(
+        idle_conn.read(&mut buf).await.unwrap(),
+        0,
+        "Idle connection should have been closed"
+    );
+
+    busy_conn
+        .write_all(b"Host: balancebeam\r\n\r\n")
+        .await
+        .unwrap();
+    let (status, body) = read_response(&mut busy_conn).await;
+    assert_eq!(status, 200);
+    assert!(body.contains("GET /second HTTP/1.1"));
+    assert_eq!(
+        busy_conn.read(&mut buf).await.unwrap(),
+        0,
+        "Connection should be closed after the in-flight request"
+    );
+
+    assert_eq!(
+        balancebeam
+            .wait_for_exit(std::time::Duration::from_secs(5))
+            .await,
+        Some(0),
+        "balancebeam should exit cleanly once its connections are closed"
+    );
+    assert_eq!(Box::new(upstream).stop().await, 2);
+    log::info!("All done :)");
+}
+
+/// Connects to balancebeam over TLS, asking for `hostname` through SNI and trusting only the
+/// certificate in `trusted_cert`
+async fn connect_tls(
+    address: &str,
+    hostname: &str,
+    trusted_cert: &str,
+) -> std::io::Result<tokio_rustls::client::TlsStream<TcpStream>> {
+    let mut config = ClientConfig::new();
+    let pem = std::fs::read(trusted_cert).unwrap();
+    config
+        .root_store
+        .add_pem_file(&mut std::io::BufReader::new(&pem[..]))
+        .unwrap();
+    config.set_protocols(&[b"http/1.1".to_vec()]);
+    let stream = TcpStream::connect(address).await?;
+    TlsConnector::from(Arc::new(config))
+        .connect(DNSNameRef::try_from_ascii_str(hostname).unwrap(), stream)
+        .await
+}
+
+/// With --tls-certificate, balancebeam serves HTTPS, picking the certificate by SNI hostname and
+/// telling the upstream that the client used HTTPS
+#[tokio::test]
+async fn test_tls_termination() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let certs = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/certs");
+    let default_cert = format!("{}/localhost.pem:{}/localhost-key.pem", certs, certs);
+    let other_cert = format!(
+        "other.test={}/other.test.pem:{}/other.test-key.pem",
+        certs, certs
+    );
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&upstream.address],
+        &[
+            "--tls-certificate",
+            &default_cert,
+            "--tls-certificate",
+            &other_cert,
+        ],
+    )
+    .await;
+
+    log::info!("Sending a request over TLS");
+    let localhost_cert = format!("{}/localhost.pem", certs);
+    let mut conn = connect_tls(&balancebeam.address, "localhost", &localhost_cert)
+        .await
+        .expect("TLS handshake failed");
+    assert_eq!(conn.get_ref().1.get_alpn_protocol(), Some(&b"http/1.1"[..]));
+    for path in &["/first", "/second"] {
+        conn.write_all(
+            format!(
+                "GET {} HTTP/1.1\r\nHost: localhost\r\nX-Forwarded-Proto: http\r\n\r\n",
+                path
+            )
+            .as_bytes(),
+        )
+        .await
+        .unwrap();
+        let (status, body) = read_response(&mut conn).await;
+        assert_eq!(status, 200);
+        assert!(body.contains(&format!("GET {} HTTP/1.1", path)));
+        assert!(body.contains("x-forwarded-proto: https"));
+        assert!(!body.contains("x-forwarded-proto: http\n"));
+    }
+
+    log::info!("Checking that the certificate is picked by SNI hostname");
+    let other_cert = format!("{}/other.test.pem", certs);
+    connect_tls(&balancebeam.address, "other.test", &other_cert)
+        .await
+        .expect("other.test should get its own certificate");
+    assert!(
+        connect_tls(&balancebeam.address, "other.test", &localhost_cert)
+            .await
+            .is_err(),
+        "other.test should not get the default certificate"
+    );
+    connect_tls(&balancebeam.address, "localhost", &localhost_cert)
+        .await
+        .expect("Hostnames without a certificate of their own should get the default one");
+
+    log::info!("Checking that plain HTTP is refused");
+    assert!(balancebeam.get("/plain").await.is_err());
+
+    assert_eq!(Box::new(upstream).stop().await, 2);
+    log::info!("All done :)");
+}
+
+/// https:// upstreams are reached over TLS, both by requests and by health checks, and their
+/// certificates are verified against --upstream-tls-ca
+#[tokio::test]
+async fn test_tls_to_upstream() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    // A second balancebeam terminating TLS in front of the echo server stands in for an upstream
+    // that speaks HTTPS
+    let certs = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/certs");
+    let cert = format!("{}/localhost.pem:{}/localhost-key.pem", certs, certs);
+    let tls_upstream =
+        BalanceBeam::new_with_args(&[&upstream.address], &["--tls-certificate", &cert]).await;
+    let port = tls_upstream.address.rsplit(':').next().unwrap();
+    let tls_address = format!("https://localhost:{}", port);
+
+    let ca = format!("{}/localhost.pem", certs);
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&tls_address],
+        &[
+            "--upstream-tls-ca",
+            &ca,
+            "--upstream-require-tls",
+            "--active-health-check-interval",
+            "1",
+        ],
+    )
+    .await;
+    // Give the health checks a chance to run; if they didn't speak TLS, the upstream would be
+    // marked dead
+    tokio::time::delay_for(std::time::Duration::from_secs(2)).await;
+    let response_text = balancebeam
+        .get("/secure")
+        .await
+        .expect("Error sending request to balancebeam");
+    assert!(response_text.contains("GET /secure HTTP/1.1"));
+    assert!(response_text.contains("x-forwarded-proto: https"));
+
+    log::info!("Checking that an upstream with an untrusted certificate is refused");
+    let wrong_ca = format!("{}/other.test.pem", certs);
+    let untrusting =
+        BalanceBeam::new_with_args(&[&tls_address], &["--upstream-tls-ca", &wrong_ca]).await;
+    let response = reqwest::get(&format!("http://{}/insecure", untrusting.address))
+        .await
+        .expect("Error sending request to balancebeam");
+    assert_eq!(response.status().as_u16(), 502);
+
+    log::info!("Checking that plaintext upstreams are refused with --upstream-require-tls");
+    let mut plaintext =
+        BalanceBeam::new_with_args(&[&upstream.address], &["--upstream-require-tls"]).await;
+    assert_eq!(
+        plaintext
+            .wait_for_exit(std::time::Duration::from_secs(5))
+            .await,
+        Some(1)
+    );
+
+    // The health checks went through to the echo server as well, so we can't count requests
+    Box::new(upstream).stop().await;
+    log::info!("All done :)");
+}
+
+/// Idle connections to https:// upstreams are reused, which means that checking whether the
+/// upstream has closed one has to see through TLS
+#[tokio::test]
+async fn test_tls_upstream_connections_are_reused() {
+    init_logging();
+    let certs = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/certs");
+    let read_pem = |name: &str| std::fs::read(format!("{}/{}", certs, name)).unwrap();
+    let cert_chain = pemfile::certs(&mut &read_pem("localhost.pem")[..]).unwrap();
+    let key = pemfile::pkcs8_private_keys(&mut &read_pem("localhost-key.pem")[..])
+        .unwrap()
+        .remove(0);
+    let mut config = ServerConfig::new(NoClientAuth::new());
+    config.set_single_cert(cert_chain, key).unwrap();
+    let acceptor = TlsAcceptor::from(Arc::new(config));
+
+    let mut listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let port = listener.local_addr().unwrap().port();
+    let connections = Arc::new(std::sync::atomic::AtomicUsize::new(0));
+    let counter = connections.clone();
+    tokio::spawn(async move {
+        loop {
+            let (conn, _) = listener.accept().await.unwrap();
+            counter.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+            let acceptor = acceptor.clone();
+            tokio::spawn(async move {
+                let mut conn = match acceptor.accept(conn).await {
+                    Ok(conn) => conn,
+                    Err(_) => return,
+                };
+                let mut request = Vec::new();
+                loop {
+                    let mut chunk = [0_u8; 512];
+                    match conn.read(&mut chunk).await {
+                        Ok(0) | Err(_) => return,
+                        Ok(bytes_read) => request.extend_from_slice(&chunk[..bytes_read]),
+                    }
+                    if request.ends_with(b"\r\n\r\n") {
+                        request.clear();
+                        conn.write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 2\r\n\r\nok")
+                            .await
+                            .unwrap();
+                    }
+                }
+            });
+        }
+    });
+    let ca = format!("{}/localhost.pem", certs);
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&format!("https://localhost:{}", port)],
+        &[
+            "--upstream-tls-ca",
+            &ca,
+            "--active-health-check-interval",
+            "60",
+        ],
+    )
+    .await;
+
+    for i in 0..3 {
+        let response_text = balancebeam
+            .get(&format!("/request-{}", i))
+            .await
+            .expect("Error sending request to balancebeam");
+        assert_eq!(response_text, "ok");
+    }
+    assert_eq!(connections.load(std::sync::atomic::Ordering::SeqCst), 1);
+    log::info!("All done :)");
+}
+
+/// Clients can speak HTTP/2, either in plain text with prior knowledge or over TLS (agreed on with
+/// ALPN). Every stream is proxied and rate limited as a request of its own.
+#[tokio::test]
+async fn test_http2() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let certs = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/certs");
+    let cert = format!("{}/localhost.pem:{}/localhost-key.pem", certs, certs);
+    let plain = BalanceBeam::new_with_args(&[&upstream.address], &[]).await;
+    let tls = BalanceBeam::new_with_args(
+        &[&upstream.address],
+        &["--tls-certificate", &cert, "--max-requests-per-minute", "2"],
+    )
+    .await;
+
+    log::info!("Sending requests over h2c");
+    let client = hyper::Client::builder()
+        .http2_only(true)
+        .build_http::<hyper::Body>();
+    // Send the requests at the same time, so that they go out as concurrent streams
+    let requests = (0..3)
+        .map(|i| {
+            let uri = format!("http://{}/h2c/{}", plain.address, i);
+            tokio::spawn(client.get(uri.parse().unwrap()))
+        })
+        .collect::<Vec<_>>();
+    for (i, request) in requests.into_iter().enumerate() {
+        let response = request
+            .await
+            .unwrap()
+            .expect("Error sending HTTP/2 request to balancebeam");
+        assert_eq!(response.version(), hyper::Version::HTTP_2);
+        assert_eq!(response.status().as_u16(), 200);
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        let body = String::from_utf8_lossy(&body);
+        assert!(body.contains(&format!("GET /h2c/{} HTTP/1.1", i)));
+        assert!(body.contains("x-forwarded-for: 127.0.0.1"));
+        assert!(body.contains("x-forwarded-proto: http\n"));
+    }
+
+    log::info!("Sending requests over TLS with ALPN");
+    let mut config = ClientConfig::new();
+    let pem = std::fs::read(format!("{}/localhost.pem", certs)).unwrap();
+    config
+        .root_store
+        .add_pem_file(&mut std::io::BufReader::new(&pem[..]))
+        .unwrap();
+    config.set_protocols(&[b"h2".to_vec(), b"http/1.1".to_vec()]);
+    let stream = TcpStream::connect(&tls.address).await.unwrap();
+    let stream = TlsConnector::from(Arc::new(config))
+        .connect(DNSNameRef::try_from_ascii_str("localhost").unwrap(), stream)
+        .await
+        .expect("TLS handshake failed");
+    assert_eq!(stream.get_ref().1.get_alpn_protocol(), Some(&b"h2"[..]));
+    let (mut sender, connection) = hyper::client::conn::Builder::new()
+        .http2_only(true)
+        .handshake::<_, hyper::Body>(stream)
+        .await
+        .expect("HTTP/2 handshake failed");
+    tokio::spawn(connection);
+    let mut statuses = Vec::new();
+    for i in 0..3 {
+        let request = hyper::Request::post(format!("https://localhost/tls/{}", i))
+            .body(hyper::Body::from("Hello world!"))
+            .unwrap();
+        let response = sender.send_request(request).await.unwrap();
+        statuses.push(response.status().as_u16());
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        let body = String::from_utf8_lossy(&body);
+        if i < 2 {
+            assert!(body.contains(&format!("POST /tls/{} HTTP/1.1", i)));
+            assert!(body.contains("host: localhost"));
+            assert!(body.contains("x-forwarded-proto: https"));
+            assert!(body.contains("\n\nHello world!"));
+        }
+    }
+    assert_eq!(
+        statuses,
+        vec![200, 200, 429],
+        "Each stream should count against the rate limit"
+    );
+
+    log::info!("Checking that a limited client is turned away before it sends its body");
+    let (_body_sender, body) = hyper::Body::channel();
+    let request = hyper::Request::post("https://localhost/tls/unfinished")
+        .body(body)
+        .unwrap();
+    let response = tokio::time::timeout(
+        std::time::Duration::from_secs(5),
+        sender.send_request(request),
+    )
+    .await
+    .expect("balancebeam waited for the body of a request it was going to turn away")
+    .unwrap();
+    assert_eq!(response.status().as_u16(), 429);
+
+    assert_eq!(Box::new(upstream).stop().await, 5);
+    log::info!("All done :)");
+}
+
+/// Once the upstream agrees to switch protocols with a 101 response, bytes are relayed both ways
+/// until the connection has been idle for --upgrade-idle-timeout
+#[tokio::test]
+async fn test_upgrade() {
+    init_logging();
+    // An upstream that switches to a protocol where it echoes back whatever it is sent
+    let mut listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let upstream_address = listener.local_addr().unwrap().to_string();
+    tokio::spawn(async move {
+        while let Ok((mut stream, _)) = listener.accept().await {
+            tokio::spawn(async move {
+                let mut head = Vec::new();
+                while !head.ends_with(b"\r\n\r\n") {
+                    let mut byte = [0_u8; 1];
+                    if stream.read(&mut byte).await.unwrap_or(0) == 0 {
+                        return;
+                    }
+                    head.push(byte[0]);
+                }
+                if !String::from_utf8_lossy(&head).contains("upgrade: echo") {
+                    // Health checks and the like
+                    let _ = stream
+                        .write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 0\r\n\r\n")
+                        .await;
+                    return;
+                }
+                stream
+                    .write_all(
+                        b"HTTP/1.1 101 Switching Protocols\r\nConnection: Upgrade\r\n\
+                        Upgrade: echo\r\n\r\nwelcome",
+                    )
+                    .await
+                    .unwrap();
+                let (mut reader, mut writer) = stream.split();
+                let _ = tokio::io::copy(&mut reader, &mut writer).await;
+            });
+        }
+    });
+    let balancebeam =
+        BalanceBeam::new_with_args(&[&upstream_address], &["--upgrade-idle-timeout", "2"]).await;
+
+    let mut conn = TcpStream::connect(&balancebeam.address)
+        .await
+        .expect("Could not connect to balancebeam");
+    conn.write_all(
+        b"GET /socket HTTP/1.1\r\nHost: balancebeam\r\nConnection: Upgrade\r\nUpgrade: echo\r\n\r\n",
+    )
+    .await
+    .unwrap();
+    let expected_head = b"HTTP/1.1 101 Switching Protocols\r\n";
+    let mut received = Vec::new();
+    while !received.ends_with(b"welcome") {
+        let mut chunk = [0_u8; 512];
+        let bytes_read = conn.read(&mut chunk).await.unwrap();
+        assert!(bytes_read > 0, "balancebeam hung up during the upgrade");
+        received.extend_from_slice(&chunk[..bytes_read]);
+    }
+    assert!(received.starts_with(expected_head));
+
+    log::info!("Relaying bytes over the upgraded connection");
+    for message in &["first message", "second message"] {
+        conn.write_all(message.as_bytes()).await.unwrap();
+        let mut echoed = vec![0_u8; message.len()];
+        conn.read_exact(&mut echoed).await.unwrap();
+        assert_eq!(echoed, message.as_bytes());
+    }
+
+    log::info!("Checking that the idle connection is closed");
+    let mut buf = [0_u8; 1];
+    let read = tokio::time::timeout(std::time::Duration::from_secs(5), conn.read(&mut buf))
+        .await
+        .expect("Idle upgraded connection should have been closed");
+    assert_eq!(read.unwrap(), 0);
+    log::info!("All done :)");
+}
+
+/// Header rules add, set and remove headers, and forwarding headers from clients are only passed
+/// on if the client is a trusted proxy (every one of them, if a header is repeated)
+#[tokio::test]
+async fn test_header_rules_and_forwarded() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let rules = [
+        "--request-header",
+        "set:X-Real-IP=$client_ip",
+        "--request-header",
+        "set:X-Forwarded-Host=$host",
+        "--request-header",
+        "add:Via=$version balancebeam",
+        "--request-header",
+        "remove:X-Internal",
+        "--response-header",
+        "set:X-Served-By=balancebeam",
+    ];
+    let untrusting = BalanceBeam::new_with_args(&[&upstream.address], &rules).await;
+    let mut trusting_args = rules.to_vec();
+    trusting_args.extend_from_slice(&["--trusted-proxy", "127.0.0.0/8"]);
+    let trusting = BalanceBeam::new_with_args(&[&upstream.address], &trusting_args).await;
+
+    let client = reqwest::Client::new();
+    for (balancebeam, trusted) in [(&untrusting, false), (&trusting, true)].iter() {
+        let response = client
+            .get(&format!("http://{}/", balancebeam.address))
+            .header("X-Forwarded-For", "203.0.113.7")
+            .header("X-Forwarded-For", "198.51.100.1")
+            .header("Forwarded", "for=203.0.113.7")
+            .header("Forwarded", "for=198.51.100.1")
+            .header("X-Internal", "secret")
+            .send()
+            .await
+            .expect("Error sending request to balancebeam");
+        assert_eq!(response.headers()["x-served-by"], "balancebeam");
+        let body = response.text().await.expect("Error reading response body");
+        log::debug!("Upstream saw: {}", body);
+        assert!(body.contains("x-real-ip: 127.0.0.1\n"));
+        assert!(body.contains(&format!("x-forwarded-host: {}\n", balancebeam.address)));
+        assert!(body.contains("via: 1.1 balancebeam\n"));
+        assert!(!body.contains("x-internal"));
+        let our_hop = format!("for=127.0.0.1;host=\"{}\";proto=http", balancebeam.address);
+        if *trusted {
+            assert!(body.contains("x-forwarded-for: 203.0.113.7, 198.51.100.1, 127.0.0.1\n"));
+            assert!(body.contains(&format!(
+                "forwarded: for=203.0.113.7, for=198.51.100.1, {}\n",
+                our_hop
+            )));
+        } else {
+            assert!(body.contains("x-forwarded-for: 127.0.0.1\n"));
+            assert!(body.contains(&format!("forwarded: {}\n", our_hop)));
+        }
+    }
+    log::info!("All done :)");
+}
+
+/// Clients that stall get a 408, idle clients are disconnected, and an upstream that stalls gets
+/// a 504 instead of tying up balancebeam forever
+#[tokio::test]
+async fn test_timeouts() {
+    init_logging();
+    // An upstream that accepts connections but never responds
+    let mut listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let upstream_address = listener.local_addr().unwrap().to_string();
+    tokio::spawn(async move {
+        let mut connections = Vec::new();
+        loop {
+            let (connection, _) = listener.accept().await.unwrap();
+            connections.push(connection);
+        }
+    });
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&upstream_address],
+        &[
+            "--client-header-timeout",
+            "1",
+            "--client-body-timeout",
+            "1",
+            "--client-idle-timeout",
+            "1",
+            "--per-try-timeout",
+            "1",
+            "--active-health-check-interval",
+            "60",
+        ],
+    )
+    .await;
+
+    log::info!("Trickling in request headers");
+    let mut stream = TcpStream::connect(&balancebeam.address).await.unwrap();
+    stream
+        .write_all(b"GET / HTTP/1.1\r\nHost: localhost\r\n")
+        .await
+        .unwrap();
+    assert_eq!(read_response(&mut stream).await.0, 408);
+
+    log::info!("Stalling partway through a request body");
+    let mut stream = TcpStream::connect(&balancebeam.address).await.unwrap();
+    stream
+        .write_all(b"POST / HTTP/1.1\r\nHost: localhost\r\nContent-Length: 10\r\n\r\nabc")
+        .await
+        .unwrap();
+    assert_eq!(read_response(&mut stream).await.0, 408);
+
+    log::info!("Leaving a connection idle");
+    let mut stream = TcpStream::connect(&balancebeam.address).await.unwrap();
+    let mut buffer = [0_u8; 512];
+    let read = tokio::time::timeout(std::time::Duration::from_secs(3), stream.read(&mut buffer))
+        .await
+        .expect("balancebeam should close idle connections");
+    assert_eq!(read.unwrap(), 0);
+
+    log::info!("Sending a request the upstream never answers");
+    let response = reqwest::get(&format!("http://{}/", balancebeam.address))
+        .await
+        .expect("Error sending request to balancebeam");
+    assert_eq!(response.status().as_u16(), 504);
+
+    log::info!("Stalling a TLS handshake");
+    let certs = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/certs");
+    let cert = format!("{}/localhost.pem:{}/localhost-key.pem", certs, certs);
+    let tls_balancebeam = BalanceBeam::new_with_args(
+        &[&upstream_address],
+        &[
+            "--tls-certificate",
+            &cert,
+            "--client-header-timeout",
+            "1",
+            "--active-health-check-interval",
+            "60",
+        ],
+    )
+    .await;
+    let mut stream = TcpStream::connect(&tls_balancebeam.address).await.unwrap();
+    let read = tokio::time::timeout(std::time::Duration::from_secs(3), stream.read(&mut buffer))
+        .await
+        .expect("balancebeam should give up on clients that don't finish the TLS handshake");
+    assert_eq!(read.unwrap(), 0);
+    log::info!("All done :)");
+}
+
+/// Connections beyond the per-client limit, and requests an upstream at its connection limit has
+/// no room to queue, are turned away with a 503 and Retry-After. Once too many clients are being
+/// turned away at once, any more are simply disconnected.
+#[tokio::test]
+async fn test_connection_limits() {
+    init_logging();
+    // An upstream that accepts connections but never responds
+    let mut listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let upstream_address = listener.local_addr().unwrap().to_string();
+    tokio::spawn(async move {
+        let mut connections = Vec::new();
+        loop {
+            let (connection, _) = listener.accept().await.unwrap();
+            connections.push(connection);
+        }
+    });
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&upstream_address],
+        &[
+            "--max-connections-per-client",
+            "2",
+            "--upstream-max-connections",
+            "1",
+            "--upstream-queue-size",
+            "0",
+            "--per-try-timeout",
+            "2",
+            "--active-health-check-interval",
+            "60",
+        ],
+    )
+    .await;
+
+    log::info!("Opening one connection more than the client may have");
+    let idle = [
+        TcpStream::connect(&balancebeam.address).await.unwrap(),
+        TcpStream::connect(&balancebeam.address).await.unwrap(),
+    ];
+    let mut stream = TcpStream::connect(&balancebeam.address).await.unwrap();
+    stream
+        .write_all(b"GET / HTTP/1.1\r\nHost: localhost\r\n\r\n")
+        .await
+        .unwrap();
+    assert_eq!(read_response(&mut stream).await.0, 503);
+
+    log::info!("Flooding balancebeam with connections that never send a request");
+    let mut flood = Vec::new();
+    for _ in 0..100 {
+        flood.push(TcpStream::connect(&balancebeam.address).await.unwrap());
+    }
+    let mut stream = TcpStream::connect(&balancebeam.address).await.unwrap();
+    let mut response = Vec::new();
+    tokio::time::timeout(
+        std::time::Duration::from_secs(1),
+        stream.read_to_end(&mut response),
+    )
+    .await
+    .expect("balancebeam should have closed the connection right away")
+    .unwrap();
+    assert!(response.is_empty());
+    drop(flood);
+    drop(idle);
+    tokio::time::delay_for(std::time::Duration::from_millis(200)).await;
+
+    log::info!("Sending a second request while the upstream is busy with the first");
+    let url = format!("http://{}/", balancebeam.address);
+    let first = {
+        let url = url.clone();
+        tokio::spawn(async move { reqwest::get(&url).await })
+    };
+    tokio::time::delay_for(std::time::Duration::from_millis(500)).await;
+    let second = reqwest::get(&url)
+        .await
+        .expect("Error sending request to balancebeam");
+    assert_eq!(second.status().as_u16(), 503);
+    assert_eq!(second.headers()["retry-after"], "1");
+    let first = first
+        .await
+        .unwrap()
+        .expect("Error sending request to balancebeam");
+    assert_eq!(first.status().as_u16(), 504);
+    log::info!("All done :)");
+}
+
+/// Every request gets a line in the access log, which is reopened on SIGUSR1 so that it can be
+/// rotated
+#[tokio::test]
+async fn test_access_log() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let path = std::env::temp_dir().join(format!("balancebeam-test-{}.log", rand::random::<u32>()));
+    let rotated = path.with_extension("log.1");
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&upstream.address],
+        &[
+            "--access-log",
+            path.to_str().unwrap(),
+            "--access-log-format",
+            "json",
+        ],
+    )
+    .await;
+    let read_lines = |path: &std::path::Path| {
+        std::fs::read_to_string(path)
+            .unwrap()
+            .lines()
+            .map(|line| serde_json::from_str::<serde_json::Value>(line).unwrap())
+            .collect::<Vec<_>>()
+    };
+
+    log::info!("Sending a request and checking its log line");
+    let response_text = balancebeam
+        .get("/logged?a=1")
+        .await
+        .expect("Error sending request to balancebeam");
+    // The line is written once the response has been sent
+    tokio::time::delay_for(std::time::Duration::from_millis(200)).await;
+    let lines = read_lines(&path);
+    assert_eq!(lines.len(), 1);
+    let line = &lines[0];
+    assert_eq!(line["client"], "127.0.0.1");
+    assert_eq!(line["upstream"], upstream.address.as_str());
+    assert_eq!(line["method"], "GET");
+    assert_eq!(line["path"], "/logged?a=1");
+    assert_eq!(line["protocol"], "HTTP/1.1");
+    assert_eq!(line["status"], 200);
+    assert_eq!(line["bytes"], response_text.len());
+    assert!(line["upstream_time"].as_f64().unwrap() <= line["request_time"].as_f64().unwrap());
+    assert_eq!(line["rate_limit"], serde_json::Value::Null);
+
+    log::info!("Rotating the log");
+    std::fs::rename(&path, &rotated).unwrap();
+    balancebeam.send_signal(nix::sys::signal::Signal::SIGUSR1);
+    tokio::time::delay_for(std::time::Duration::from_millis(200)).await;
+    balancebeam
+        .get("/rotated")
+        .await
+        .expect("Error sending request to balancebeam");
+    tokio::time::delay_for(std::time::Duration::from_millis(200)).await;
+    let lines = read_lines(&path);
+    assert_eq!(lines.len(), 1);
+    assert_eq!(lines[0]["path"], "/rotated");
+    assert_eq!(read_lines(&rotated).len(), 1);
+
+    std::fs::remove_file(&path).unwrap();
+    std::fs::remove_file(&rotated).unwrap();
+    log::info!("All done :)");
+}
+
+/// Fresh responses are served from the cache, stale ones are revalidated with their ETag, and
+/// unsafe requests invalidate what was cached for their path
+#[tokio::test]
+async fn test_response_cache() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let balancebeam =
+        BalanceBeam::new_with_args(&[&upstream.address], &["--cache-size", "1"]).await;
+    let url = |path: &str| format!("http://{}{}", balancebeam.address, path);
+    let get = |path: String| async move {
+        reqwest::get(&path)
+            .await
+            .expect("Error sending request to balancebeam")
+    };
+
+    log::info!("Fetching a fresh response twice");
+    let first = get(url("/cached/fresh?max-age=60")).await;
+    assert!(first.headers().get("age").is_none());
+    let first = first.text().await.unwrap();
+    let second = get(url("/cached/fresh?max-age=60")).await;
+    assert!(second.headers().get("age").is_some());
+    assert_eq!(second.text().await.unwrap(), first);
+
+    log::info!("Fetching a response that has to be revalidated every time");
+    let first = get(url("/cached/stale")).await.text().await.unwrap();
+    let second = get(url("/cached/stale")).await;
+    assert_eq!(second.status().as_u16(), 200);
+    assert_eq!(second.text().await.unwrap(), first);
+
+    log::info!("Changing the fresh response");
+    let response = reqwest::Client::new()
+        .post(&url("/cached/fresh?max-age=60"))
+        .send()
+        .await
+        .expect("Error sending request to balancebeam");
+    assert_eq!(response.status().as_u16(), 200);
+    let third = get(url("/cached/fresh?max-age=60")).await;
+    assert!(third.headers().get("age").is_none());
+
+    // A fetch and a revalidation of /cached/stale, the POST and the two uncached fetches of
+    // /cached/fresh
+    let num_requests_received = Box::new(upstream).stop().await;
+    assert_eq!(num_requests_received, 5);
+    log::info!("All done :)");
+}
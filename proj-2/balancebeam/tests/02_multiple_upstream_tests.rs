@@ -3,6 +3,7 @@ mod common;
 use common::{init_logging, BalanceBeam, EchoServer, ErrorServer, Server};
 
 use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
 use tokio::time::delay_for;
 
 async fn setup_with_params(
@@ -36,6 +37,54 @@ async fn setup(n_upstreams: usize) -> (BalanceBeam, Vec<Box<dyn Server>>) {
     setup_with_params(n_upstreams, None, None).await
 }
 
+/// Starts one upstream per entry in `weights`, and a balancebeam instance using the given load
+/// balancing strategy, with each upstream given the corresponding weight
+async fn setup_with_strategy(
+    strategy: &str,
+    weights: &[usize],
+) -> (BalanceBeam, Vec<Box<dyn Server>>) {
+    init_logging();
+    let mut upstreams: Vec<Box<dyn Server>> = Vec::new();
+    for _ in weights {
+        upstreams.push(Box::new(EchoServer::new().await));
+    }
+    let upstream_specs: Vec<String> = upstreams
+        .iter()
+        .zip(weights)
+        .map(|(upstream, weight)| format!("{}={}", upstream.address(), weight))
+        .collect();
+    let upstream_specs: Vec<&str> = upstream_specs.iter().map(|spec| spec.as_str()).collect();
+    let balancebeam = BalanceBeam::new_with_args(&upstream_specs, &["--strategy", strategy]).await;
+    (balancebeam, upstreams)
+}
+
+/// Sends `n_requests` requests (each on its own connection), then stops the upstreams and returns
+/// the number of requests each one received
+async fn count_requests_per_upstream(
+    balancebeam: &BalanceBeam,
+    mut upstreams: Vec<Box<dyn Server>>,
+    n_requests: usize,
+) -> Vec<usize> {
+    for i in 0..n_requests {
+        let path = format!("/request-{}", i);
+        let response_text = balancebeam
+            .get(&path)
+            .await
+            .expect("Error sending request to balancebeam");
+        assert!(response_text.contains(&format!("GET {} HTTP/1.1", path)));
+    }
+
+    let mut request_counters = Vec::new();
+    while let Some(upstream) = upstreams.pop() {
+        request_counters.insert(0, upstream.stop().await);
+    }
+    log::info!(
+        "Number of requests received by each upstream: {:?}",
+        request_counters
+    );
+    request_counters
+}
+
 /// Send a bunch of requests to the load balancer, and ensure they are evenly distributed across the
 /// upstream servers
 #[tokio::test]
@@ -80,6 +129,235 @@ async fn test_load_distribution() {
     log::info!("All done :)");
 }
 
+/// Round-robin should spread requests across the upstreams exactly evenly
+#[tokio::test]
+async fn test_round_robin_distribution() {
+    let (balancebeam, upstreams) = setup_with_strategy("round-robin", &[1, 1, 1]).await;
+    let request_counters = count_requests_per_upstream(&balancebeam, upstreams, 30).await;
+    assert_eq!(request_counters, vec![10, 10, 10]);
+    log::info!("All done :)");
+}
+
+/// Weighted round-robin should send each upstream a share of the requests proportional to its
+/// weight
+#[tokio::test]
+async fn test_weighted_round_robin_distribution() {
+    let (balancebeam, upstreams) = setup_with_strategy("weighted-round-robin", &[1, 3]).await;
+    let request_counters = count_requests_per_upstream(&balancebeam, upstreams, 40).await;
+    assert_eq!(request_counters, vec![10, 30]);
+    log::info!("All done :)");
+}
+
+/// Weighted random should send each upstream a share of the requests roughly proportional to its
+/// weight. The split is random, so only check that it is clearly lopsided the right way.
+#[tokio::test]
+async fn test_weighted_random_distribution() {
+    let (balancebeam, upstreams) = setup_with_strategy("weighted-random", &[1, 4]).await;
+    let request_counters = count_requests_per_upstream(&balancebeam, upstreams, 100).await;
+    assert!(
+        request_counters[0] > 0,
+        "An upstream with a weight of 1 never received any requests"
+    );
+    assert!(
+        request_counters[1] > request_counters[0] * 2,
+        "An upstream with four times the weight should get far more of the requests"
+    );
+    log::info!("All done :)");
+}
+
+/// With sequential connections, no upstream is ever busier than the others, so least-connections
+/// should fall back to taking turns
+#[tokio::test]
+async fn test_least_connections_distribution() {
+    let (balancebeam, upstreams) = setup_with_strategy("least-connections", &[1, 1]).await;
+    let request_counters = count_requests_per_upstream(&balancebeam, upstreams, 20).await;
+    for count in request_counters {
+        assert!(count > 0, "An idle upstream never received any requests");
+    }
+    log::info!("All done :)");
+}
+
+/// Requests are balanced one by one, so a client that sends all of its requests over a single
+/// keep-alive connection should still have them spread across the upstreams
+#[tokio::test]
+async fn test_requests_on_one_connection_are_balanced() {
+    let (balancebeam, mut upstreams) = setup_with_strategy("round-robin", &[1, 1]).await;
+
+    let client = reqwest::Client::new();
+    for i in 0..10 {
+        let path = format!("/request-{}", i);
+        let response_text = client
+            .get(&format!("http://{}{}", balancebeam.address, path))
+            .send()
+            .await
+            .expect("Failed to connect to balancebeam")
+            .text()
+            .await
+            .expect("Balancebeam replied with a malformed response");
+        assert!(response_text.contains(&format!("GET {} HTTP/1.1", path)));
+    }
+
+    let mut request_counters = Vec::new();
+    while let Some(upstream) = upstreams.pop() {
+        request_counters.insert(0, upstream.stop().await);
+    }
+    assert_eq!(request_counters, vec![5, 5]);
+    log::info!("All done :)");
+}
+
+/// Consistent hashing on a header should send every request carrying the same header value to the
+/// same upstream
+#[tokio::test]
+async fn test_consistent_hash_sticks_to_one_upstream() {
+    init_logging();
+    let mut upstreams: Vec<Box<dyn Server>> = Vec::new();
+    for _ in 0..3 {
+        upstreams.push(Box::new(EchoServer::new().await));
+    }
+    let upstream_addresses: Vec<String> = upstreams
+        .iter()
+        .map(|upstream| upstream.address())
+        .collect();
+    let upstream_addresses: Vec<&str> = upstream_addresses
+        .iter()
+        .map(|addr| addr.as_str())
+        .collect();
+    let balancebeam = BalanceBeam::new_with_args(
+        &upstream_addresses,
+        &[
+            "--strategy",
+            "consistent-hash",
+            "--hash-key",
+            "header:x-user",
+        ],
+    )
+    .await;
+
+    let n_requests = 12;
+    for i in 0..n_requests {
+        let client = reqwest::Client::new();
+        let path = format!("/sticky-{}", i);
+        let response_text = client
+            .get(&format!("http://{}{}", balancebeam.address, path))
+            .header("x-user", "alice")
+            .send()
+            .await
+            .expect("Error sending request to balancebeam")
+            .text()
+            .await
+            .expect("Balancebeam replied with a malformed response");
+        assert!(response_text.contains(&format!("GET {} HTTP/1.1", path)));
+    }
+
+    let mut request_counters = Vec::new();
+    while let Some(upstream) = upstreams.pop() {
+        request_counters.insert(0, upstream.stop().await);
+    }
+    log::info!(
+        "Number of requests received by each upstream: {:?}",
+        request_counters
+    );
+    assert!(
+        request_counters.contains(&n_requests),
+        "Requests with the same hash key were spread across multiple upstreams"
+    );
+
+    log::info!("All done :)");
+}
+
+/// Sends a request with the given x-user header, and returns the index of the upstream that
+/// received it
+async fn upstream_for_user(
+    balancebeam: &BalanceBeam,
+    upstreams: &[EchoServer],
+    user: &str,
+) -> usize {
+    let before: Vec<usize> = upstreams.iter().map(|u| u.requests_received()).collect();
+    reqwest::Client::new()
+        .get(&format!("http://{}/user/{}", balancebeam.address, user))
+        .header("x-user", user)
+        .send()
+        .await
+        .expect("Error sending request to balancebeam");
+    upstreams
+        .iter()
+        .zip(before)
+        .position(|(upstream, before)| upstream.requests_received() > before)
+        .expect("None of the upstreams received the request")
+}
+
+/// Removing an upstream from a consistent-hash pool should only move the keys that mapped to it;
+/// everyone else keeps going to the same upstream as before
+#[tokio::test]
+async fn test_consistent_hash_remaps_only_removed_keys() {
+    init_logging();
+    let mut upstreams = Vec::new();
+    for _ in 0..3 {
+        upstreams.push(EchoServer::new().await);
+    }
+    let admin_address = format!("127.0.0.1:{}", rand::random::<u16>() % 60000 + 1024);
+    let upstream_addresses: Vec<&str> = upstreams
+        .iter()
+        .map(|upstream| upstream.address.as_str())
+        .collect();
+    let balancebeam = BalanceBeam::new_with_args(
+        &upstream_addresses,
+        &[
+            "--strategy",
+            "consistent-hash",
+            "--hash-key",
+            "header:x-user",
+            "--admin-bind",
+            &admin_address,
+        ],
+    )
+    .await;
+
+    let users: Vec<String> = (0..30).map(|i| format!("user-{}", i)).collect();
+    let mut before = Vec::new();
+    for user in &users {
+        before.push(upstream_for_user(&balancebeam, &upstreams, user).await);
+    }
+    log::info!("Upstream for each user: {:?}", before);
+    let removed = 2;
+    assert!(
+        before.contains(&removed),
+        "No keys mapped to the upstream that is about to be removed"
+    );
+
+    log::info!(
+        "Removing {} through the admin API",
+        upstreams[removed].address
+    );
+    let response = reqwest::Client::new()
+        .delete(&format!(
+            "http://{}/upstreams/{}",
+            admin_address, upstreams[removed].address
+        ))
+        .send()
+        .await
+        .expect("Error sending request to the admin API");
+    assert_eq!(response.status().as_u16(), 200);
+
+    for (user, &upstream) in users.iter().zip(&before) {
+        let after = upstream_for_user(&balancebeam, &upstreams, user).await;
+        if upstream == removed {
+            assert_ne!(after, removed, "{} was sent to a removed upstream", user);
+        } else {
+            assert_eq!(
+                after, upstream,
+                "{} moved to another upstream, though its own upstream was not removed",
+                user
+            );
+        }
+    }
+
+    for upstream in upstreams {
+        Box::new(upstream).stop().await;
+    }
+    log::info!("All done :)");
+}
+
 async fn try_failover(balancebeam: &BalanceBeam, upstreams: &mut Vec<Box<dyn Server>>) {
     // Send some initial requests. Everything should work
     log::info!("Sending some initial requests. These should definitely work.");
@@ -131,6 +409,198 @@ async fn test_passive_health_checks() {
     log::info!("All done :)");
 }
 
+/// Make sure idempotent requests are retried on another upstream when the one they were sent to
+/// accepts the connection but hangs up without responding
+#[tokio::test]
+async fn test_idempotent_requests_are_retried() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+
+    // Accept connections and immediately drop them
+    let mut hang_up_listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let hang_up_address = hang_up_listener.local_addr().unwrap().to_string();
+    tokio::spawn(async move {
+        while let Ok((stream, _)) = hang_up_listener.accept().await {
+            drop(stream);
+        }
+    });
+
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&hang_up_address, &upstream.address],
+        &["--strategy", "round-robin"],
+    )
+    .await;
+    for i in 0..6 {
+        let path = format!("/retry-{}", i);
+        let response_text = balancebeam
+            .get(&path)
+            .await
+            .expect("Error sending request to balancebeam");
+        assert!(
+            response_text.contains(&format!("GET {} HTTP/1.1", path)),
+            "balancebeam returned unexpected response. Retries may not be working."
+        );
+    }
+    assert_eq!(Box::new(upstream).stop().await, 6);
+    log::info!("All done :)");
+}
+
+/// Moving on to another upstream after failing to connect counts as a retry, so it stops once
+/// --max-retries is used up
+#[tokio::test]
+async fn test_connect_failover_respects_max_retries() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let dead_address = std::net::TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap()
+        .to_string();
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&dead_address, &upstream.address],
+        &[
+            "--strategy",
+            "round-robin",
+            "--max-retries",
+            "0",
+            "--active-health-check-interval",
+            "60",
+        ],
+    )
+    .await;
+
+    // Round-robin sends one of the two requests to the dead upstream, which can't be retried
+    let mut statuses = vec![
+        get_status(&balancebeam, 0).await,
+        get_status(&balancebeam, 1).await,
+    ];
+    statuses.sort_unstable();
+    assert_eq!(statuses, vec![200, 502]);
+    assert_eq!(Box::new(upstream).stop().await, 1);
+    log::info!("All done :)");
+}
+
+/// Make sure an upstream that keeps returning 500s is taken out of rotation by its circuit breaker
+/// (without any help from active health checks)
+#[tokio::test]
+async fn test_circuit_breaker_opens_on_server_errors() {
+    init_logging();
+    let upstreams: Vec<Box<dyn Server>> = vec![
+        Box::new(EchoServer::new().await),
+        Box::new(ErrorServer::new().await),
+    ];
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&upstreams[0].address(), &upstreams[1].address()],
+        &[
+            "--strategy",
+            "round-robin",
+            "--breaker-failure-threshold",
+            "2",
+            "--breaker-cool-down",
+            "60",
+        ],
+    )
+    .await;
+
+    for i in 0..10 {
+        let path = format!("/request-{}", i);
+        balancebeam
+            .get(&path)
+            .await
+            .expect("Error sending request to balancebeam");
+    }
+
+    let mut request_counters = Vec::new();
+    for upstream in upstreams {
+        request_counters.push(upstream.stop().await);
+    }
+    assert_eq!(
+        request_counters,
+        vec![8, 2],
+        "The failing upstream should have stopped getting requests after two errors"
+    );
+    log::info!("All done :)");
+}
+
+/// A response body that the upstream cuts short counts against its circuit breaker, whether the
+/// client speaks HTTP/1.1 or HTTP/2
+#[tokio::test]
+async fn test_circuit_breaker_counts_truncated_bodies() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    // An upstream that promises a longer body than it sends
+    let mut listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let truncating_address = listener.local_addr().unwrap().to_string();
+    let requests_received = std::sync::Arc::new(std::sync::atomic::AtomicUsize::new(0));
+    let counter = requests_received.clone();
+    tokio::spawn(async move {
+        loop {
+            let (mut conn, _) = listener.accept().await.unwrap();
+            let counter = counter.clone();
+            tokio::spawn(async move {
+                let mut request = Vec::new();
+                while !request.ends_with(b"\r\n\r\n") {
+                    let mut chunk = [0_u8; 512];
+                    match conn.read(&mut chunk).await {
+                        Ok(0) | Err(_) => return,
+                        Ok(bytes_read) => request.extend_from_slice(&chunk[..bytes_read]),
+                    }
+                }
+                counter.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+                let _ = conn
+                    .write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 10\r\n\r\nok")
+                    .await;
+            });
+        }
+    });
+
+    for http2 in [false, true].iter() {
+        requests_received.store(0, std::sync::atomic::Ordering::SeqCst);
+        let balancebeam = BalanceBeam::new_with_args(
+            &[&upstream.address, &truncating_address],
+            &[
+                "--strategy",
+                "round-robin",
+                "--breaker-failure-threshold",
+                "2",
+                "--breaker-cool-down",
+                "60",
+                "--active-health-check-interval",
+                "60",
+            ],
+        )
+        .await;
+        for i in 0..10 {
+            let uri = format!("http://{}/request-{}", balancebeam.address, i);
+            // Reading the body through makes sure balancebeam is done with the request
+            if *http2 {
+                let client = hyper::Client::builder()
+                    .http2_only(true)
+                    .build_http::<hyper::Body>();
+                let response = client
+                    .get(uri.parse().unwrap())
+                    .await
+                    .expect("Error sending HTTP/2 request to balancebeam");
+                let _ = hyper::body::to_bytes(response.into_body()).await;
+            } else {
+                let response = reqwest::get(&uri)
+                    .await
+                    .expect("Error sending request to balancebeam");
+                let _ = response.text().await;
+            }
+        }
+        assert_eq!(
+            requests_received.load(std::sync::atomic::Ordering::SeqCst),
+            2,
+            "The truncating upstream should have stopped getting requests after two failures \
+            (HTTP/2: {})",
+            http2
+        );
+    }
+    assert_eq!(Box::new(upstream).stop().await, 16);
+    log::info!("All done :)");
+}
+
 /// Verify that the active health checks are monitoring HTTP status, rather than simply depending
 /// on whether connections can be established to determine whether an upstream is up:
 ///
@@ -190,6 +660,57 @@ async fn test_active_health_checks_check_http_status() {
     }
 }
 
+/// Make sure health checks can look at the response body, and can be pointed at a different path
+/// for each upstream. Both upstreams echo the health check request back, but only the one checked
+/// at /healthy produces a body that matches the pattern, so the other should be taken out of
+/// rotation.
+#[tokio::test]
+async fn test_active_health_checks_match_body() {
+    init_logging();
+    let upstreams: Vec<Box<dyn Server>> = vec![
+        Box::new(EchoServer::new().await),
+        Box::new(EchoServer::new().await),
+    ];
+    let checked_upstream = format!("{}@/healthy", upstreams[1].address());
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&upstreams[0].address(), &checked_upstream],
+        &[
+            "--active-health-check-interval",
+            "1",
+            "--active-health-check-body",
+            "^GET /healthy ",
+        ],
+    )
+    .await;
+
+    log::info!("Waiting for health checks to run...");
+    delay_for(Duration::from_secs(3)).await;
+
+    for i in 0..10 {
+        let path = format!("/request-{}", i);
+        let response_text = balancebeam
+            .get(&path)
+            .await
+            .expect("Error sending request to balancebeam");
+        assert!(response_text.contains(&format!("GET {} HTTP/1.1", path)));
+    }
+
+    // Stop balancebeam first, so that both upstreams have received the same number of health
+    // checks by the time we count
+    drop(balancebeam);
+    delay_for(Duration::from_millis(100)).await;
+    let mut request_counters = Vec::new();
+    for upstream in upstreams {
+        request_counters.push(upstream.stop().await);
+    }
+    assert_eq!(
+        request_counters[1] - request_counters[0],
+        10,
+        "Requests should only go to the upstream that passed its health checks"
+    );
+    log::info!("All done :)");
+}
+
 /// Make sure active health checks restore upstreams that were previously failed but are now
 /// working again:
 ///
@@ -294,3 +815,675 @@ async fn test_rate_limiting() {
 
     log::info!("All done :)");
 }
+
+/// Sends a request on a fresh connection, returning the response status
+async fn get_status(balancebeam: &BalanceBeam, i: usize) -> u16 {
+    reqwest::Client::new()
+        .get(&format!("http://{}/request-{}", balancebeam.address, i))
+        .send()
+        .await
+        .expect("Error sending request to balancebeam")
+        .status()
+        .as_u16()
+}
+
+/// The token bucket lets a client burst up to the size of its bucket, then refills it steadily, so
+/// that a client that has been turned away is let back in without waiting for a window to end
+#[tokio::test]
+async fn test_token_bucket_rate_limiting() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    // One token per second, in a bucket that holds three
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&upstream.address],
+        &[
+            "--max-requests-per-minute",
+            "60",
+            "--rate-limit-algorithm",
+            "token-bucket",
+            "--rate-limit-burst",
+            "3",
+        ],
+    )
+    .await;
+
+    for i in 0..3 {
+        assert_eq!(get_status(&balancebeam, i).await, 200);
+    }
+    assert_eq!(
+        get_status(&balancebeam, 3).await,
+        429,
+        "A request beyond the burst size should be rate limited"
+    );
+
+    log::info!("Waiting for the bucket to refill a little...");
+    delay_for(Duration::from_millis(1500)).await;
+    assert_eq!(get_status(&balancebeam, 4).await, 200);
+
+    assert_eq!(Box::new(upstream).stop().await, 4);
+    log::info!("All done :)");
+}
+
+/// The sliding window algorithms shouldn't let a client send twice its limit in a burst that
+/// straddles the boundary between two fixed windows, the way the fixed window algorithm does
+#[tokio::test]
+async fn test_sliding_window_rate_limiting_across_window_boundary() {
+    init_logging();
+    let limit = 4;
+    for algorithm in &["sliding-window-log", "sliding-window-counter"] {
+        log::info!("Testing {}", algorithm);
+        let upstream = EchoServer::new().await;
+        let balancebeam = BalanceBeam::new_with_args(
+            &[&upstream.address],
+            &[
+                "--max-requests-per-minute",
+                &limit.to_string(),
+                "--rate-limit-window",
+                "2",
+                "--rate-limit-algorithm",
+                algorithm,
+            ],
+        )
+        .await;
+
+        // The first request starts the window; the rest of the quota is used up just before it
+        // ends, and the client then tries again for a whole quota just after it
+        let started = std::time::Instant::now();
+        assert_eq!(get_status(&balancebeam, 0).await, 200);
+        delay_for(Duration::from_millis(1700)).await;
+        let mut allowed_in_burst = 0;
+        for i in 1..limit {
+            if get_status(&balancebeam, i).await == 200 {
+                allowed_in_burst += 1;
+            }
+        }
+        assert_eq!(allowed_in_burst, limit - 1);
+        // (On a busy machine, sending the requests may already have taken us past that point)
+        let after_boundary = Duration::from_millis(2300);
+        delay_for(
+            after_boundary
+                .checked_sub(started.elapsed())
+                .unwrap_or_default(),
+        )
+        .await;
+        for i in limit..limit * 2 {
+            if get_status(&balancebeam, i).await == 200 {
+                allowed_in_burst += 1;
+            }
+        }
+        assert!(
+            allowed_in_burst <= limit,
+            "{} let {} requests through in a burst across a window boundary, with a limit of {}",
+            algorithm,
+            allowed_in_burst,
+            limit
+        );
+
+        Box::new(upstream).stop().await;
+    }
+    log::info!("All done :)");
+}
+
+/// Every request counts against the rate limit, even on a keep-alive connection, and clients are
+/// told about their quota in the response headers
+#[tokio::test]
+async fn test_rate_limiting_every_request_with_headers() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&upstream.address],
+        &["--max-requests-per-minute", "3", "--rate-limit-headers"],
+    )
+    .await;
+
+    let client = reqwest::Client::new();
+    for i in 0..5 {
+        let response = client
+            .get(&format!("http://{}/request-{}", balancebeam.address, i))
+            .send()
+            .await
+            .expect("Error sending request to balancebeam");
+        let header = |name: &str| {
+            response
+                .headers()
+                .get(name)
+                .unwrap_or_else(|| panic!("Response is missing the {} header", name))
+                .to_str()
+                .unwrap()
+                .to_string()
+        };
+        assert_eq!(header("ratelimit-limit"), "3");
+        if i < 3 {
+            assert_eq!(response.status().as_u16(), 200);
+            assert_eq!(header("ratelimit-remaining"), (2 - i).to_string());
+        } else {
+            assert_eq!(response.status().as_u16(), 429);
+            assert_eq!(header("ratelimit-remaining"), "0");
+            let retry_after: u64 = header("retry-after").parse().unwrap();
+            assert!(retry_after > 0 && retry_after <= 60);
+        }
+    }
+
+    assert_eq!(Box::new(upstream).stop().await, 3);
+    log::info!("All done :)");
+}
+
+/// Rate limits can be counted per API key instead of per client IP, and a class of keys can be
+/// given a limit of its own
+#[tokio::test]
+async fn test_rate_limiting_by_api_key() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&upstream.address],
+        &[
+            "--max-requests-per-minute",
+            "2",
+            "--rate-limit-key",
+            "header:x-api-key",
+            "--rate-limit-class",
+            "premium-=4",
+        ],
+    )
+    .await;
+
+    let client = reqwest::Client::new();
+    let send = |key: &'static str| {
+        client
+            .get(&format!("http://{}/", balancebeam.address))
+            .header("x-api-key", key)
+            .send()
+    };
+    for (key, allowed) in &[("basic-a", 2), ("basic-b", 2), ("premium-c", 4)] {
+        for i in 0..allowed + 1 {
+            let status = send(key)
+                .await
+                .expect("Error sending request to balancebeam")
+                .status()
+                .as_u16();
+            let expected = if i < *allowed { 200 } else { 429 };
+            assert_eq!(
+                status, expected,
+                "Request {} with key {} got the wrong status",
+                i, key
+            );
+        }
+    }
+
+    assert_eq!(Box::new(upstream).stop().await, 8);
+    log::info!("All done :)");
+}
+
+/// Counting requests per X-Forwarded-For address only believes the header from a trusted proxy.
+/// Anyone else could get a fresh quota with every request by making up a new address each time.
+#[tokio::test]
+async fn test_rate_limiting_by_forwarded_for() {
+    init_logging();
+    for trust_local_proxy in &[false, true] {
+        let upstream = EchoServer::new().await;
+        let mut args = vec![
+            "--max-requests-per-minute",
+            "2",
+            "--rate-limit-key",
+            "forwarded-for",
+        ];
+        if *trust_local_proxy {
+            args.extend_from_slice(&["--trusted-proxy", "127.0.0.0/8"]);
+        }
+        let balancebeam = BalanceBeam::new_with_args(&[&upstream.address], &args).await;
+
+        let client = reqwest::Client::new();
+        let mut statuses = Vec::new();
+        for i in 0..4 {
+            let status = client
+                .get(&format!("http://{}/", balancebeam.address))
+                .header("x-forwarded-for", format!("203.0.113.{}", i))
+                .send()
+                .await
+                .expect("Error sending request to balancebeam")
+                .status()
+                .as_u16();
+            statuses.push(status);
+        }
+        if *trust_local_proxy {
+            assert_eq!(
+                statuses,
+                vec![200, 200, 200, 200],
+                "Each client behind a trusted proxy should have a quota of its own"
+            );
+        } else {
+            assert_eq!(
+                statuses,
+                vec![200, 200, 429, 429],
+                "A client should not get a fresh quota by sending a new X-Forwarded-For address"
+            );
+        }
+        Box::new(upstream).stop().await;
+    }
+    log::info!("All done :)");
+}
+
+/// Upstreams can be added and drained through the admin API while balancebeam is running
+#[tokio::test]
+async fn test_admin_api_manages_upstreams() {
+    init_logging();
+    let first = EchoServer::new().await;
+    let second = EchoServer::new().await;
+    let admin_address = format!("127.0.0.1:{}", rand::random::<u16>() % 60000 + 1024);
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&first.address],
+        &["--admin-bind", &admin_address, "--strategy", "round-robin"],
+    )
+    .await;
+
+    let client = reqwest::Client::new();
+    let admin_url = |path: &str| format!("http://{}{}", admin_address, path);
+    let response = client
+        .post(&admin_url("/upstreams"))
+        .body(second.address.clone())
+        .send()
+        .await
+        .expect("Error sending request to the admin API");
+    assert_eq!(response.status().as_u16(), 201);
+    let response = client
+        .post(&admin_url(&format!("/upstreams/{}/drain", first.address)))
+        .send()
+        .await
+        .expect("Error sending request to the admin API");
+    assert_eq!(response.status().as_u16(), 200);
+
+    let upstreams = client
+        .get(&admin_url("/upstreams"))
+        .send()
+        .await
+        .expect("Error sending request to the admin API")
+        .text()
+        .await
+        .expect("Error reading response from the admin API");
+    let upstreams: serde_json::Value =
+        serde_json::from_str(&upstreams).expect("Admin API should respond with JSON");
+    let states: Vec<(&str, &str)> = upstreams
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|upstream| {
+            (
+                upstream["address"].as_str().unwrap(),
+                upstream["state"].as_str().unwrap(),
+            )
+        })
+        .collect();
+    assert_eq!(
+        states,
+        vec![
+            (first.address.as_str(), "draining"),
+            (second.address.as_str(), "active")
+        ]
+    );
+
+    for i in 0..5 {
+        let path = format!("/request-{}", i);
+        balancebeam
+            .get(&path)
+            .await
+            .expect("Error sending request to balancebeam");
+    }
+
+    // https:// upstreams have slashes in their address, so it is percent-encoded in the path
+    let tls_address = "https://localhost:4443";
+    let tls_path = "/upstreams/https%3A%2F%2Flocalhost%3A4443";
+    let response = client
+        .post(&admin_url("/upstreams"))
+        .body(tls_address)
+        .send()
+        .await
+        .expect("Error sending request to the admin API");
+    assert_eq!(response.status().as_u16(), 201);
+    let response = client
+        .post(&admin_url(&format!("{}/drain", tls_path)))
+        .send()
+        .await
+        .expect("Error sending request to the admin API");
+    assert_eq!(response.status().as_u16(), 200);
+    let upstream = client
+        .get(&admin_url(tls_path))
+        .send()
+        .await
+        .expect("Error sending request to the admin API")
+        .text()
+        .await
+        .expect("Error reading response from the admin API");
+    let upstream: serde_json::Value =
+        serde_json::from_str(&upstream).expect("Admin API should respond with JSON");
+    assert_eq!(upstream["address"], tls_address);
+    assert_eq!(upstream["state"], "draining");
+    let response = client
+        .delete(&admin_url(tls_path))
+        .send()
+        .await
+        .expect("Error sending request to the admin API");
+    assert_eq!(response.status().as_u16(), 200);
+    let response = client
+        .get(&admin_url(tls_path))
+        .send()
+        .await
+        .expect("Error sending request to the admin API");
+    assert_eq!(response.status().as_u16(), 404);
+
+    assert_eq!(Box::new(first).stop().await, 0);
+    assert_eq!(Box::new(second).stop().await, 5);
+    log::info!("All done :)");
+}
+
+/// Enabling an upstream through the admin API only undoes draining or disabling it. An upstream
+/// the health checks found dead stays dead, and gets no requests.
+#[tokio::test]
+async fn test_admin_api_enable_keeps_dead_upstream_dead() {
+    init_logging();
+    let live = EchoServer::new().await;
+    let dead_address = format!("127.0.0.1:{}", rand::random::<u16>() % 60000 + 1024);
+    let admin_address = format!("127.0.0.1:{}", rand::random::<u16>() % 60000 + 1024);
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&live.address, &dead_address],
+        &[
+            "--admin-bind",
+            &admin_address,
+            "--active-health-check-interval",
+            "1",
+        ],
+    )
+    .await;
+
+    let client = reqwest::Client::new();
+    let admin_url = |path: &str| {
+        format!(
+            "http://{}/upstreams/{}{}",
+            admin_address, dead_address, path
+        )
+    };
+    let state = || async {
+        let upstream = client
+            .get(&admin_url(""))
+            .send()
+            .await
+            .expect("Error sending request to the admin API")
+            .text()
+            .await
+            .expect("Error reading response from the admin API");
+        let upstream: serde_json::Value =
+            serde_json::from_str(&upstream).expect("Admin API should respond with JSON");
+        upstream["state"].as_str().unwrap().to_string()
+    };
+    log::info!("Waiting for health checks to run...");
+    delay_for(Duration::from_secs(2)).await;
+    assert_eq!(state().await, "dead");
+
+    for action in &["/disable", "/enable"] {
+        let response = client
+            .post(&admin_url(action))
+            .send()
+            .await
+            .expect("Error sending request to the admin API");
+        assert_eq!(response.status().as_u16(), 200);
+    }
+    assert_eq!(
+        state().await,
+        "dead",
+        "Enabling an upstream should not bring it back to life"
+    );
+
+    // (The live upstream also receives health checks, so check the responses, not its count)
+    for i in 0..5 {
+        let path = format!("/request-{}", i);
+        let response_text = balancebeam
+            .get(&path)
+            .await
+            .expect("Error sending request to balancebeam");
+        assert!(
+            response_text.contains(&format!("GET {} HTTP/1.1", path)),
+            "A request was sent to the dead upstream"
+        );
+    }
+    Box::new(live).stop().await;
+    log::info!("All done :)");
+}
+
+/// Deleting an upstream through the admin API closes the idle connections we kept open to it
+#[tokio::test]
+async fn test_admin_api_delete_closes_pooled_connections() {
+    init_logging();
+    let mut listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let upstream_address = listener.local_addr().unwrap().to_string();
+    let (sender, closed) = tokio::sync::oneshot::channel();
+    tokio::spawn(async move {
+        let (mut conn, _) = listener.accept().await.unwrap();
+        let mut request = Vec::new();
+        while !request.ends_with(b"\r\n\r\n") {
+            let mut chunk = [0_u8; 512];
+            let bytes_read = conn.read(&mut chunk).await.unwrap();
+            assert!(
+                bytes_read > 0,
+                "balancebeam hung up before sending a request"
+            );
+            request.extend_from_slice(&chunk[..bytes_read]);
+        }
+        conn.write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 2\r\n\r\nok")
+            .await
+            .unwrap();
+        // Wait for balancebeam to close the connection
+        let mut chunk = [0_u8; 512];
+        while conn.read(&mut chunk).await.unwrap_or(0) > 0 {}
+        let _ = sender.send(());
+    });
+    let admin_address = format!("127.0.0.1:{}", rand::random::<u16>() % 60000 + 1024);
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&upstream_address],
+        &[
+            "--admin-bind",
+            &admin_address,
+            "--active-health-check-interval",
+            "60",
+        ],
+    )
+    .await;
+
+    let response_text = balancebeam
+        .get("/pooled")
+        .await
+        .expect("Error sending request to balancebeam");
+    assert_eq!(response_text, "ok");
+    let response = reqwest::Client::new()
+        .delete(&format!(
+            "http://{}/upstreams/{}",
+            admin_address, upstream_address
+        ))
+        .send()
+        .await
+        .expect("Error sending request to the admin API");
+    assert_eq!(response.status().as_u16(), 200);
+    tokio::time::timeout(Duration::from_secs(5), closed)
+        .await
+        .expect("The pooled connection to the deleted upstream was kept open")
+        .unwrap();
+    log::info!("All done :)");
+}
+
+/// An admin client that never sends its request is cut off after --client-header-timeout
+#[tokio::test]
+async fn test_admin_api_times_out_silent_clients() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let admin_address = format!("127.0.0.1:{}", rand::random::<u16>() % 60000 + 1024);
+    let _balancebeam = BalanceBeam::new_with_args(
+        &[&upstream.address],
+        &[
+            "--admin-bind",
+            &admin_address,
+            "--client-header-timeout",
+            "1",
+        ],
+    )
+    .await;
+
+    let mut stream = tokio::net::TcpStream::connect(&admin_address)
+        .await
+        .unwrap();
+    let mut response = Vec::new();
+    tokio::time::timeout(Duration::from_secs(5), stream.read_to_end(&mut response))
+        .await
+        .expect("The admin API kept waiting for a request that never came")
+        .unwrap();
+    assert!(String::from_utf8_lossy(&response).starts_with("HTTP/1.1 408"));
+    log::info!("All done :)");
+}
+
+/// Proxied requests show up in the Prometheus metrics, counted by upstream and status
+#[tokio::test]
+async fn test_metrics_count_responses() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let admin_address = format!("127.0.0.1:{}", rand::random::<u16>() % 60000 + 1024);
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&upstream.address],
+        &[
+            "--admin-bind",
+            &admin_address,
+            "--max-requests-per-minute",
+            "3",
+        ],
+    )
+    .await;
+
+    for i in 0..4 {
+        get_status(&balancebeam, i).await;
+    }
+    let metrics = reqwest::get(&format!("http://{}/metrics", admin_address))
+        .await
+        .expect("Error sending request to the admin API")
+        .text()
+        .await
+        .expect("Error reading metrics");
+    log::debug!("Metrics:\n{}", metrics);
+    let expected = [
+        format!(
+            "balancebeam_responses_total{{upstream=\"{}\",status=\"200\"}} 3",
+            upstream.address
+        ),
+        "balancebeam_responses_total{upstream=\"none\",status=\"429\"} 1".to_string(),
+        "balancebeam_rate_limited_requests_total 1".to_string(),
+        "balancebeam_request_duration_seconds_count 3".to_string(),
+    ];
+    for line in expected.iter() {
+        assert!(
+            metrics.lines().any(|metric| metric == line),
+            "Metrics should include {}",
+            line
+        );
+    }
+
+    assert_eq!(Box::new(upstream).stop().await, 3);
+    log::info!("All done :)");
+}
+
+/// Settings can come from a config file, which is reloaded on SIGHUP. An invalid config file is
+/// rejected, and the previous settings stay in place.
+#[tokio::test]
+async fn test_config_file_reload() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let config_path =
+        std::env::temp_dir().join(format!("balancebeam-test-{}.toml", rand::random::<u32>()));
+    let write_config = |contents: &str| {
+        std::fs::write(&config_path, contents).expect("Could not write config file");
+    };
+    write_config("max_requests_per_minute = 2\n");
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&upstream.address],
+        &["--config", config_path.to_str().unwrap()],
+    )
+    .await;
+
+    let statuses = |start: usize| {
+        let balancebeam = &balancebeam;
+        async move {
+            let mut statuses = Vec::new();
+            for i in start..start + 3 {
+                statuses.push(get_status(balancebeam, i).await);
+            }
+            statuses
+        }
+    };
+    assert_eq!(statuses(0).await, vec![200, 200, 429]);
+
+    log::info!("Reloading with an invalid config file...");
+    write_config("max_requests_per_minute = \"lots\"\n");
+    balancebeam.send_signal(nix::sys::signal::Signal::SIGHUP);
+    delay_for(Duration::from_millis(500)).await;
+    assert_eq!(
+        statuses(3).await,
+        vec![429, 429, 429],
+        "An invalid config file should leave the old settings in place"
+    );
+
+    log::info!("Reloading with a higher limit...");
+    write_config("max_requests_per_minute = 10\n");
+    balancebeam.send_signal(nix::sys::signal::Signal::SIGHUP);
+    delay_for(Duration::from_millis(500)).await;
+    assert_eq!(statuses(6).await, vec![200, 200, 200]);
+
+    std::fs::remove_file(&config_path).unwrap();
+    assert_eq!(Box::new(upstream).stop().await, 5);
+    log::info!("All done :)");
+}
+
+/// Routes send requests to the pool of upstreams their host, path or headers match, and anything
+/// else goes to the default pool
+#[tokio::test]
+async fn test_routing_to_pools() {
+    init_logging();
+    let default_upstream = EchoServer::new().await;
+    let api_upstream = EchoServer::new().await;
+    let static_upstream = EchoServer::new().await;
+    let api_spec = format!("api={}", api_upstream.address);
+    let static_spec = format!("static={}", static_upstream.address);
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&default_upstream.address],
+        &[
+            "--pool-upstream",
+            &api_spec,
+            "--pool-upstream",
+            &static_spec,
+            "--pool-strategy",
+            "api=round-robin",
+            "--route",
+            "host=api.example.com => api",
+            "--route",
+            "method=GET|HEAD path=/static/ => static",
+            "--route",
+            "header:x-pool=api => api",
+        ],
+    )
+    .await;
+
+    let client = reqwest::Client::new();
+    let url = |path: &str| format!("http://{}{}", balancebeam.address, path);
+    let requests = vec![
+        client.get(&url("/")),
+        client.get(&url("/")).header("Host", "api.example.com:8080"),
+        client.get(&url("/static/app.js")),
+        client.post(&url("/static/app.js")),
+        client.get(&url("/")).header("x-pool", "api"),
+    ];
+    for request in requests {
+        let response = request
+            .send()
+            .await
+            .expect("Error sending request to balancebeam");
+        assert_eq!(response.status().as_u16(), 200);
+    }
+
+    assert_eq!(Box::new(default_upstream).stop().await, 2);
+    assert_eq!(Box::new(api_upstream).stop().await, 2);
+    assert_eq!(Box::new(static_upstream).stop().await, 1);
+    log::info!("All done :)");
+}
@@ -24,29 +24,40 @@ impl BalanceBeam {
         active_health_check_interval: Option<usize>,
         max_requests_per_minute: Option<usize>,
     ) -> BalanceBeam {
+        let mut extra_args = Vec::new();
+        if let Some(active_health_check_interval) = active_health_check_interval {
+            extra_args.push("--active-health-check-interval".to_string());
+            extra_args.push(active_health_check_interval.to_string());
+        }
+        if let Some(max_requests_per_minute) = max_requests_per_minute {
+            extra_args.push("--max-requests-per-minute".to_string());
+            extra_args.push(max_requests_per_minute.to_string());
+        }
+        let extra_args: Vec<&str> = extra_args.iter().map(|arg| arg.as_str()).collect();
+        BalanceBeam::new_with_args(upstreams, &extra_args).await
+    }
+
+    /// Starts balancebeam with the given upstreams, passing any additional command-line arguments
+    /// straight through
+    pub async fn new_with_args(upstreams: &[&str], extra_args: &[&str]) -> BalanceBeam {
         let mut rng = rand::thread_rng();
         let address = format!("127.0.0.1:{}", rng.gen_range(1024, 65535));
         let mut cmd = Command::new(BalanceBeam::target_bin_path());
         cmd.arg("--bind").arg(&address);
+        cmd.args(extra_args);
         cmd.arg("--upstream");
         for upstream in upstreams {
             cmd.arg(upstream);
         }
-        if let Some(active_health_check_interval) = active_health_check_interval {
-            cmd.arg("--active-health-check-interval")
-                .arg(active_health_check_interval.to_string());
-        }
-        if let Some(max_requests_per_minute) = max_requests_per_minute {
-            cmd.arg("--max-requests-per-minute")
-                .arg(max_requests_per_minute.to_string());
-        }
         cmd.kill_on_drop(true);
         cmd.stdout(std::process::Stdio::piped());
         cmd.stderr(std::process::Stdio::piped());
-        let mut child = cmd.spawn().expect(&format!(
-            "Could not execute balancebeam binary {}",
-            BalanceBeam::target_bin_path().to_str().unwrap()
-        ));
+        let mut child = cmd.spawn().unwrap_or_else(|_| {
+            panic!(
+                "Could not execute balancebeam binary {}",
+                BalanceBeam::target_bin_path().to_str().unwrap()
+            )
+        });
 
         // Print output from the child. We want to intercept and log this output (instead of letting
         // the child inherit stderr and print directly to the terminal) so that the output can be
@@ -85,6 +96,28 @@ impl BalanceBeam {
         BalanceBeam { child, address }
     }
 
+    /// Sends a signal to the balancebeam process (e.g. SIGHUP to make it reload its configuration)
+    #[allow(dead_code)]
+    pub fn send_signal(&self, signal: nix::sys::signal::Signal) {
+        let pid = nix::unistd::Pid::from_raw(self.child.id() as i32);
+        nix::sys::signal::kill(pid, signal).expect("Could not send signal to balancebeam");
+    }
+
+    /// Waits up to `timeout` for the balancebeam process to exit, returning its exit code (or None if
+    /// it is still running).
+    #[allow(dead_code)]
+    pub async fn wait_for_exit(&mut self, timeout: Duration) -> Option<i32> {
+        tokio::time::timeout(timeout, &mut self.child)
+            .await
+            .ok()
+            .map(|status| {
+                status
+                    .expect("Error waiting for balancebeam to exit")
+                    .code()
+                    .expect("balancebeam was killed by a signal")
+            })
+    }
+
     #[allow(dead_code)]
     pub async fn get(&self, path: &str) -> Result<String, reqwest::Error> {
         let client = reqwest::Client::new();
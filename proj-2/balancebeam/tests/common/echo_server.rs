@@ -27,9 +27,52 @@ async fn echo(
         );
     }
     req_text += "\n";
+    let send_chunked = req.uri().path().starts_with("/chunked");
+    // Responses under /cached may be cached: they stay fresh for the number of seconds given with
+    // ?max-age= (none by default), and carry an ETag to be revalidated with
+    let cache_headers = if req.uri().path().starts_with("/cached") {
+        let max_age = req
+            .uri()
+            .query()
+            .and_then(|query| query.strip_prefix("max-age="))
+            .unwrap_or("0")
+            .to_string();
+        let etag = format!("\"{}\"", req.uri().path());
+        if req.headers().get("if-none-match").map(|tag| tag.as_bytes()) == Some(etag.as_bytes()) {
+            return Ok(Response::builder()
+                .status(304)
+                .header("cache-control", format!("max-age={}", max_age))
+                .header("etag", etag)
+                .body(Body::empty())
+                .unwrap());
+        }
+        Some((max_age, etag))
+    } else {
+        None
+    };
     let mut req_as_bytes = req_text.into_bytes();
     req_as_bytes.extend(hyper::body::to_bytes(req.into_body()).await?);
-    Ok(Response::new(Body::from(req_as_bytes)))
+    if send_chunked {
+        // A streamed body has no known length, so hyper sends it with Transfer-Encoding: chunked.
+        // Split it in two to make sure there is more than one chunk.
+        let (mut sender, body) = Body::channel();
+        tokio::spawn(async move {
+            let second_half = req_as_bytes.split_off(req_as_bytes.len() / 2);
+            let _ = sender.send_data(req_as_bytes.into()).await;
+            let _ = sender.send_data(second_half.into()).await;
+        });
+        return Ok(Response::new(body));
+    }
+    let mut response = Response::new(Body::from(req_as_bytes));
+    if let Some((max_age, etag)) = cache_headers {
+        let headers = response.headers_mut();
+        headers.insert(
+            "cache-control",
+            format!("max-age={}", max_age).parse().unwrap(),
+        );
+        headers.insert("etag", etag.parse().unwrap());
+    }
+    Ok(response)
 }
 
 pub struct EchoServer {
@@ -83,6 +126,13 @@ impl EchoServer {
             address: bind_addr_string,
         }
     }
+
+    /// The number of requests received so far, for tests that need to see where each request
+    /// went without stopping the server
+    #[allow(dead_code)]
+    pub fn requests_received(&self) -> usize {
+        self.state.requests_received.load(atomic::Ordering::SeqCst)
+    }
 }
 
 #[async_trait]